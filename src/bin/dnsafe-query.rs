@@ -0,0 +1,479 @@
+//! A dig-like client for querying name servers
+//!
+//! Arguments follow dig: `@server` picks the server, the first other argument
+//! is the name, followed by an optional type and class, and `+option`
+//! switches control the query and the output.
+
+extern crate dnsafe;
+extern crate getopts;
+extern crate serde_json;
+
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::process;
+use std::time::{Duration, Instant};
+
+use getopts::{Options, ParsingStyle};
+use serde_json::{json, Value};
+
+use dnsafe::{lookup_with_traced, recursive_lookup, root_server, DnsPacket, DnsRecord,
+             EdnsOption, QueryOptions, QueryType, ResultCode, Trace, Transport};
+
+// The server asked when none is given, normally a local resolver.
+const DEFAULT_SERVER: &str = "127.0.0.1";
+
+// How many referrals `+trace` follows before giving up.
+const MAX_REFERRALS: usize = 32;
+
+struct Query {
+    server: Option<String>,
+    port: u16,
+    name: String,
+    qtype: QueryType,
+    options: QueryOptions,
+    // Follow referrals from the root, showing every response on the way.
+    trace: bool,
+    json: bool,
+}
+
+enum Command {
+    Run(Query),
+    Help(String),
+}
+
+impl Query {
+    fn parse_args(program: &str, args: &[String]) -> Result<Command> {
+        let mut opts = Options::new();
+        opts.parsing_style(ParsingStyle::FloatingFrees);
+        opts.optopt("p", "port", "port to send queries to (default 53)", "PORT");
+        opts.optflag("h", "help", "print this help");
+
+        let matches = opts.parse(args).map_err(|e| invalid(e.to_string()))?;
+        if matches.opt_present("h") {
+            let brief = format!(
+                "Usage: {} [@server] [options] name [type] [class] [+option...]\n\n\
+                 Query options:\n    \
+                 +[no]tcp        use TCP instead of UDP\n    \
+                 +[no]dnssec     set the DO bit, asking for DNSSEC records\n    \
+                 +[no]cd         set the CD bit, disabling validation upstream\n    \
+                 +[no]rec        set the RD bit, asking for recursion (default)\n    \
+                 +[no]edns       send an OPT record (default)\n    \
+                 +subnet=ADDR[/LEN]\n                    \
+                 send an EDNS client subnet option\n    \
+                 +[no]trace      follow referrals from the root\n    \
+                 +[no]json       print the responses as JSON",
+                program
+            );
+            return Ok(Command::Help(opts.usage(&brief)));
+        }
+
+        let mut query = Query {
+            server: None,
+            port: 53,
+            name: String::new(),
+            qtype: QueryType::A,
+            options: QueryOptions::default(),
+            trace: false,
+            json: false,
+        };
+        if let Some(port) = matches.opt_str("p") {
+            query.port = port
+                .parse()
+                .map_err(|_| invalid(format!("Invalid port {}", port)))?;
+        }
+
+        let mut positional = Vec::new();
+        for arg in &matches.free {
+            if let Some(server) = arg.strip_prefix('@') {
+                query.server = Some(server.to_string());
+            } else if let Some(option) = arg.strip_prefix('+') {
+                query.set_option(option)?;
+            } else {
+                positional.push(arg.as_str());
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        query.name = match positional.next() {
+            Some(name) => name.to_string(),
+            None => return Err(invalid("No name to look up".to_string())),
+        };
+        // The type and class can be given in either order, as they can't be
+        // mistaken for one another.
+        for arg in positional {
+            if let Ok(qtype) = arg.parse() {
+                query.qtype = qtype;
+            } else if let Some(qclass) = parse_class(arg) {
+                query.options.qclass = qclass;
+            } else {
+                return Err(invalid(format!("Unknown type or class {}", arg)));
+            }
+        }
+
+        Ok(Command::Run(query))
+    }
+
+    fn set_option(&mut self, option: &str) -> Result<()> {
+        if let Some(subnet) = option.strip_prefix("subnet=") {
+            self.options.options.push(parse_subnet(subnet)?);
+            return Ok(());
+        }
+
+        let (name, value) = match option.strip_prefix("no") {
+            Some(name) => (name, false),
+            None => (option, true),
+        };
+        match name {
+            "tcp" | "vc" => self.options.tcp = value,
+            "dnssec" => self.options.dnssec_ok = value,
+            "cd" | "cdflag" => self.options.checking_disabled = value,
+            "rec" | "recurse" => self.options.recursion_desired = value,
+            "edns" => self.options.edns = value,
+            "trace" => self.trace = value,
+            "json" => self.json = value,
+            _ => return Err(invalid(format!("Unknown option +{}", option))),
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+// Parses a class mnemonic or the generic `CLASSn` syntax.
+fn parse_class(arg: &str) -> Option<u16> {
+    match arg.to_uppercase().as_str() {
+        "IN" => Some(1),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        name => name.strip_prefix("CLASS").and_then(|x| x.parse().ok()),
+    }
+}
+
+// Parses `ADDR/LEN` into a client subnet option. The address is cut to the
+// prefix length, as servers reject options with bits set beyond it.
+fn parse_subnet(subnet: &str) -> Result<EdnsOption> {
+    let (addr, prefix) = match subnet.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (subnet, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| invalid(format!("Invalid subnet {}", subnet)))?;
+
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix.map(str::parse) {
+        None => max_prefix,
+        Some(Ok(x)) if x <= max_prefix => x,
+        Some(_) => return Err(invalid(format!("Invalid subnet {}", subnet))),
+    };
+
+    let addr = match addr {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    };
+
+    Ok(EdnsOption::ECS {
+        source_prefix: prefix,
+        scope_prefix: 0,
+        addr,
+    })
+}
+
+// A response along with where it came from and how long it took.
+struct Exchange {
+    // The server as `addr#port`.
+    server: String,
+    transport: Transport,
+    elapsed: Duration,
+    response: DnsPacket,
+}
+
+fn ask(query: &Query, server: &str, port: u16, options: &QueryOptions) -> Result<Exchange> {
+    let mut trace = Trace::new();
+    let start = Instant::now();
+    let server_port = (server, port);
+    let response = lookup_with_traced(&query.name, query.qtype, server_port, options, &mut trace)?;
+
+    // A truncated response is retried over TCP, so the last query tells how
+    // the response arrived.
+    let transport = match trace.queries().last() {
+        Some(query) => query.transport,
+        None => Transport::Udp,
+    };
+
+    Ok(Exchange {
+        server: format!("{}#{}", server, port),
+        transport,
+        elapsed: start.elapsed(),
+        response,
+    })
+}
+
+// Follows referrals the way a recursive lookup does, but without asking for
+// recursion, passing every exchange to `step`. Starts at the root unless a
+// server was given.
+fn trace<F>(query: &Query, mut step: F) -> Result<()>
+where
+    F: FnMut(&Exchange),
+{
+    let mut options = query.options.clone();
+    options.recursion_desired = false;
+
+    let mut server = match query.server {
+        Some(ref server) => server.clone(),
+        None => root_server().to_string(),
+    };
+    let mut port = query.port;
+
+    for _ in 0..MAX_REFERRALS {
+        let exchange = ask(query, &server, port, &options)?;
+        step(&exchange);
+
+        let response = exchange.response;
+
+        if !response.answers.is_empty() || response.rescode() == ResultCode::NXDOMAIN {
+            return Ok(());
+        }
+
+        server = if let Some(ns) = response.get_resolved_ns(&query.name) {
+            ns
+        } else if let Some(ns_name) = response.get_unresolved_ns(&query.name) {
+            match recursive_lookup(&ns_name, QueryType::A)?.get_random_a() {
+                Some(ns) => ns,
+                None => return Ok(()),
+            }
+        } else {
+            return Ok(());
+        };
+        port = 53;
+    }
+
+    Err(Error::other("Too many referrals"))
+}
+
+fn flags(packet: &DnsPacket) -> Vec<&'static str> {
+    let header = &packet.header;
+    [
+        (header.response, "qr"),
+        (header.authoritative_answer, "aa"),
+        (header.truncated_message, "tc"),
+        (header.recursion_desired, "rd"),
+        (header.recursion_available, "ra"),
+        (header.authed_data, "ad"),
+        (header.checking_disabled, "cd"),
+    ]
+    .iter()
+    .filter(|&&(set, _)| set)
+    .map(|&(_, name)| name)
+    .collect()
+}
+
+fn record_json(record: &DnsRecord) -> Value {
+    // The data is the last of the tab-separated presentation format fields.
+    let text = record.to_string();
+    let data = text.splitn(5, '\t').nth(4).unwrap_or("");
+
+    json!({
+        "name": record.get_domain(),
+        "ttl": record.get_ttl(),
+        "class": "IN",
+        "type": record.get_querytype().to_string(),
+        "data": data,
+    })
+}
+
+fn records_json(records: &[DnsRecord]) -> Vec<Value> {
+    records
+        .iter()
+        .filter(|record| !matches!(**record, DnsRecord::OPT { .. }))
+        .map(record_json)
+        .collect()
+}
+
+fn packet_json(packet: &DnsPacket) -> Value {
+    let edns = match packet.get_opt() {
+        Some(&DnsRecord::OPT {
+            packet_len, flags, ..
+        }) => json!({
+            "version": (flags >> 16) & 0xFF,
+            "do": flags & 0x8000 != 0,
+            "udp": packet_len,
+            "options": packet
+                .edns_options()
+                .iter()
+                .map(|option| option.to_string())
+                .collect::<Vec<_>>(),
+        }),
+        _ => Value::Null,
+    };
+
+    let questions: Vec<Value> = packet
+        .questions
+        .iter()
+        .map(|question| {
+            json!({
+                "name": question.name,
+                "type": question.qtype.to_string(),
+                "class": question.qclass,
+            })
+        })
+        .collect();
+
+    json!({
+        "id": packet.header.id,
+        "opcode": packet.header.opcode.to_string(),
+        "status": packet.rescode().to_string(),
+        "flags": flags(packet),
+        "edns": edns,
+        "question": questions,
+        "answer": records_json(&packet.answers),
+        "authority": records_json(&packet.authorities),
+        "additional": records_json(&packet.resources),
+    })
+}
+
+fn exchange_json(exchange: &Exchange) -> Value {
+    json!({
+        "server": exchange.server,
+        "transport": exchange.transport.to_string(),
+        "time_ms": exchange.elapsed.as_millis() as u64,
+        "response": packet_json(&exchange.response),
+    })
+}
+
+fn run(query: &Query) -> Result<()> {
+    if query.trace {
+        let mut steps = Vec::new();
+        let result = trace(query, |exchange| {
+            if query.json {
+                steps.push(exchange_json(exchange));
+                return;
+            }
+
+            let response = &exchange.response;
+            for record in response.answers.iter().chain(response.authorities.iter()) {
+                println!("{}", record);
+            }
+            println!(
+                ";; Received from {}({}) in {} ms\n",
+                exchange.server,
+                exchange.transport,
+                exchange.elapsed.as_millis()
+            );
+        });
+        if query.json {
+            println!("{}", Value::Array(steps));
+        }
+        return result;
+    }
+
+    let server = query.server.as_deref().unwrap_or(DEFAULT_SERVER);
+    let exchange = ask(query, server, query.port, &query.options)?;
+
+    if query.json {
+        println!("{}", exchange_json(&exchange));
+    } else {
+        println!("; <<>> dnsafe-query <<>> {} {}", query.name, query.qtype);
+        println!(";; Got answer:");
+        println!("{}", exchange.response);
+        println!(";; Query time: {} msec", exchange.elapsed.as_millis());
+        println!(";; SERVER: {}({})", exchange.server, exchange.transport);
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let query = match Query::parse_args(&args[0], &args[1..]) {
+        Ok(Command::Run(query)) => query,
+        Ok(Command::Help(usage)) => {
+            print!("{}", usage);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(&query) {
+        eprintln!(";; {}", e);
+        process::exit(9);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Query> {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        match Query::parse_args("dnsafe-query", &args)? {
+            Command::Run(query) => Ok(query),
+            Command::Help(_) => panic!("Expected a query"),
+        }
+    }
+
+    #[test]
+    fn it_parses_dig_style_arguments() {
+        let query = parse(&["@192.0.2.53", "example.com", "ch", "mx", "-p", "5353"]).unwrap();
+        assert_eq!(Some("192.0.2.53".to_string()), query.server);
+        assert_eq!(5353, query.port);
+        assert_eq!("example.com", query.name);
+        assert_eq!(QueryType::MX, query.qtype);
+        assert_eq!(3, query.options.qclass);
+
+        let query = parse(&["example.com", "+tcp", "+dnssec", "+cd", "+norec", "+json"]).unwrap();
+        assert_eq!(None, query.server);
+        assert_eq!(QueryType::A, query.qtype);
+        assert!(query.options.tcp);
+        assert!(query.options.dnssec_ok);
+        assert!(query.options.checking_disabled);
+        assert!(!query.options.recursion_desired);
+        assert!(query.options.edns);
+        assert!(query.json);
+        assert!(!query.trace);
+    }
+
+    #[test]
+    fn it_parses_client_subnets() {
+        let query = parse(&["example.com", "+subnet=192.0.2.77/20"]).unwrap();
+        assert_eq!(
+            vec![EdnsOption::ECS {
+                source_prefix: 20,
+                scope_prefix: 0,
+                addr: "192.0.0.0".parse().unwrap(),
+            }],
+            query.options.options
+        );
+
+        let query = parse(&["example.com", "+subnet=2001:db8::1"]).unwrap();
+        match query.options.options[0] {
+            EdnsOption::ECS { source_prefix, .. } => assert_eq!(128, source_prefix),
+            ref x => panic!("Expected a client subnet, got {:?}", x),
+        }
+
+        assert!(parse(&["example.com", "+subnet=192.0.2.0/33"]).is_err());
+        assert!(parse(&["example.com", "+subnet=example.com"]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["+tcp"]).is_err());
+        assert!(parse(&["example.com", "TXT"]).is_err());
+        assert!(parse(&["example.com", "+bogus"]).is_err());
+        assert!(parse(&["example.com", "-p", "x"]).is_err());
+    }
+}
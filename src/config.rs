@@ -0,0 +1,1195 @@
+//! Settings for the server binary
+//!
+//! Settings are read from an optional TOML file, after which any command line
+//! flags override the values from the file:
+//!
+//! ```toml
+//! listen = ["0.0.0.0", "::"]
+//! port = 53
+//! mode = "forwarding"
+//! forwarders = ["10.0.0.53", "10.0.1.53:5353"]
+//! forward-selection = "lowest-latency"
+//! cache-size = 10000
+//! timeout = 5
+//! log-level = "info"
+//! query-log = true
+//! metrics-listen = "127.0.0.1:9153"
+//! allow = ["127.0.0.0/8", "::1", "10.0.0.0/8"]
+//! allow-recursion = ["127.0.0.0/8", "::1"]
+//! allow-transfer = ["10.0.0.2"]
+//! hosts-file = "/etc/dnsafe/hosts"
+//! policy-reload-interval = 300
+//!
+//! # Queries for these zones go to internal servers instead.
+//! [[zone]]
+//! name = "corp.internal"
+//! forwarders = ["10.0.0.53"]
+//!
+//! [[zone]]
+//! name = "10.in-addr.arpa"
+//! nameservers = ["10.0.0.1"]
+//!
+//! # Listeners with access lists of their own. Lists that aren't given are
+//! # the ones above.
+//! [[listener]]
+//! address = "10.0.0.1"
+//! allow-recursion = ["10.0.0.0/8"]
+//!
+//! # Zones served authoritatively, from RFC 1035 zone files. Records of types
+//! # other than A, AAAA, NS, CNAME, PTR, MX and SOA have to be written in the
+//! # generic syntax of RFC 3597, as in `TYPE16 \# 6 0568656c6c6f`, and any
+//! # others are skipped with a warning.
+//! [[zone-file]]
+//! origin = "example.com"
+//! path = "/etc/dnsafe/example.com.zone"
+//!
+//! # Sends part of each client's address upstream (RFC 7871). Off unless
+//! # enabled.
+//! [client-subnet]
+//! enabled = true
+//! ipv4-prefix = 24
+//! ipv6-prefix = 56
+//!
+//! # Blocklists and response policy zones, checked in this order. Files are
+//! # reloaded when they change.
+//! [[policy]]
+//! path = "/etc/dnsafe/blocklist.hosts"
+//! format = "hosts"
+//!
+//! [[policy]]
+//! name = "rpz.local"
+//! path = "/etc/dnsafe/rpz.local.zone"
+//! format = "rpz"
+//!
+//! # Limits the responses sent over UDP to each client network, so that we
+//! # can't be used to reflect traffic at somebody else. Slipped responses are
+//! # truncated, and clients retry them over TCP.
+//! [rate-limit]
+//! enabled = true
+//! responses-per-second = 5
+//! slip = 2
+//! log-only = false
+//!
+//! # Queries and responses logged in dnstap format, to a collector's socket
+//! # or to a file.
+//! [dnstap]
+//! socket = "/run/dnstap.sock"
+//! identity = "ns1"
+//! message-types = ["client-query", "client-response"]
+//! ```
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use getopts::Options;
+use serde::Deserialize;
+
+use dnsafe::{AccessControl, AccessList, ClientSubnetConfig, Dnstap, DnstapMessageType, DnstapOutput,
+             Forwarder, HostsFile, Policy, PolicyFormat, PolicySource, RateLimitConfig,
+             UpstreamSelection, Zone, ZoneRoute, ZoneRoutes, ZoneStore};
+
+/// How the server answers queries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Resolve queries by walking down from the root servers.
+    Recursive,
+    /// Pass queries on to upstream resolvers.
+    Forwarding,
+    /// Only answer from locally configured zones.
+    Authoritative,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Mode> {
+        match s {
+            "recursive" => Ok(Mode::Recursive),
+            "forwarding" => Ok(Mode::Forwarding),
+            "authoritative" => Ok(Mode::Authoritative),
+            _ => Err(invalid(format!("Unknown mode: {}", s))),
+        }
+    }
+}
+
+/// How forwarding mode picks the upstream to ask first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selection {
+    RoundRobin,
+    LowestLatency,
+}
+
+impl Selection {
+    pub fn upstream_selection(self) -> UpstreamSelection {
+        match self {
+            Selection::RoundRobin => UpstreamSelection::RoundRobin,
+            Selection::LowestLatency => UpstreamSelection::LowestLatency,
+        }
+    }
+}
+
+impl FromStr for Selection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Selection> {
+        match s {
+            "round-robin" => Ok(Selection::RoundRobin),
+            "lowest-latency" => Ok(Selection::LowestLatency),
+            _ => Err(invalid(format!("Unknown upstream selection: {}", s))),
+        }
+    }
+}
+
+/// How much the server logs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LogLevel> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(invalid(format!("Unknown log level: {}", s))),
+        }
+    }
+}
+
+/// A `[[zone]]` section, routing the queries for a zone to particular servers.
+/// Either `forwarders` or `nameservers` has to be given.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ZoneSection {
+    pub name: String,
+    /// Resolvers to forward the queries to.
+    #[serde(default)]
+    pub forwarders: Vec<String>,
+    /// Name servers of the zone to start resolving the queries at.
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+}
+
+impl ZoneSection {
+    // Parses a zone given on the command line as `ZONE=ADDR[,ADDR...]`.
+    fn from_arg(arg: &str, stub: bool) -> Result<ZoneSection> {
+        let i = match arg.find('=') {
+            Some(i) => i,
+            None => return Err(invalid(format!("Expected ZONE=ADDR: {}", arg))),
+        };
+        let servers = arg[i + 1..].split(',').map(|x| x.trim().to_string()).collect();
+        let (forwarders, nameservers) = if stub {
+            (Vec::new(), servers)
+        } else {
+            (servers, Vec::new())
+        };
+
+        Ok(ZoneSection {
+            name: arg[..i].to_string(),
+            forwarders,
+            nameservers,
+        })
+    }
+}
+
+/// A `[[zone-file]]` section, naming a zone to serve authoritatively. Records
+/// of types that can't be read are skipped with a warning.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ZoneFileSection {
+    pub origin: String,
+    pub path: String,
+}
+
+/// A `[[policy]]` section, loading a blocklist or a response policy zone.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PolicySection {
+    /// The name of the zone, which has to be given for an RPZ as its origin.
+    /// Blocklists are named after their file otherwise.
+    pub name: Option<String>,
+    pub path: String,
+    /// `hosts`, `domains` or `rpz`.
+    pub format: String,
+}
+
+/// A `[[listener]]` section, accepting queries on an address with access
+/// lists of its own.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ListenerSection {
+    pub address: String,
+    pub allow: Option<Vec<String>>,
+    pub allow_recursion: Option<Vec<String>>,
+    pub allow_transfer: Option<Vec<String>>,
+}
+
+/// The `[client-subnet]` section. Client networks are only sent upstream when
+/// it's enabled, as RFC 7871 asks.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClientSubnetSection {
+    pub enabled: bool,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for ClientSubnetSection {
+    fn default() -> Self {
+        let defaults = ClientSubnetConfig::default();
+        ClientSubnetSection {
+            enabled: false,
+            ipv4_prefix: defaults.ipv4_prefix,
+            ipv6_prefix: defaults.ipv6_prefix,
+        }
+    }
+}
+
+/// The `[rate-limit]` section.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RateLimitSection {
+    pub enabled: bool,
+    pub responses_per_second: u32,
+    pub slip: u32,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub log_only: bool,
+}
+
+impl Default for RateLimitSection {
+    fn default() -> Self {
+        let defaults = RateLimitConfig::default();
+        RateLimitSection {
+            enabled: false,
+            responses_per_second: defaults.responses_per_second,
+            slip: defaults.slip,
+            ipv4_prefix: defaults.ipv4_prefix,
+            ipv6_prefix: defaults.ipv6_prefix,
+            log_only: defaults.log_only,
+        }
+    }
+}
+
+/// The `[dnstap]` section. Logging is enabled by giving either a socket or a
+/// file to write to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DnstapSection {
+    /// The Unix socket of a collector.
+    pub socket: Option<String>,
+    pub file: Option<String>,
+    /// The name of this server included in every message.
+    pub identity: Option<String>,
+    /// The types of messages to log, such as `client-query`.
+    pub message_types: Vec<String>,
+}
+
+impl Default for DnstapSection {
+    fn default() -> Self {
+        let message_types = [
+            DnstapMessageType::AuthQuery,
+            DnstapMessageType::AuthResponse,
+            DnstapMessageType::ClientQuery,
+            DnstapMessageType::ClientResponse,
+            DnstapMessageType::ResolverQuery,
+            DnstapMessageType::ResolverResponse,
+            DnstapMessageType::ForwarderQuery,
+            DnstapMessageType::ForwarderResponse,
+        ];
+
+        DnstapSection {
+            socket: None,
+            file: None,
+            identity: None,
+            message_types: message_types.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// The addresses to accept queries on, over both UDP and TCP.
+    pub listen: Vec<String>,
+    /// Further addresses to accept queries on, with access lists of their own.
+    /// Setting `listen` to an empty list leaves only these.
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerSection>,
+    /// The port to accept queries on, for addresses that don't specify one.
+    pub port: u16,
+    pub mode: Mode,
+    /// The server recursive lookups start from.
+    pub root_server: Option<IpAddr>,
+    /// The resolvers to pass queries on to in forwarding mode.
+    pub forwarders: Vec<String>,
+    pub forward_selection: Selection,
+    /// Zones whose queries go to particular servers, in any mode.
+    #[serde(rename = "zone")]
+    pub zones: Vec<ZoneSection>,
+    /// Zones to answer authoritatively, in any mode.
+    #[serde(rename = "zone-file")]
+    pub zone_files: Vec<ZoneFileSection>,
+    /// A hosts file whose names are answered authoritatively, ahead of our
+    /// zones and recursion. It's reloaded whenever it changes.
+    pub hosts_file: Option<String>,
+    /// The number of queries resolved at the same time.
+    pub workers: usize,
+    /// The number of received queries that may wait for a free worker. Once
+    /// the queue is full, further queries are dropped until the workers
+    /// catch up.
+    pub queue_size: usize,
+    /// The number of packets kept in the cache.
+    pub cache_size: usize,
+    /// Seconds to wait for each upstream server to respond.
+    pub timeout: u64,
+    pub log_level: LogLevel,
+    /// Logs a line for every query answered, under the `dnsafe::query`
+    /// target at the info level.
+    pub query_log: bool,
+    /// The address to serve Prometheus metrics on, at `/metrics`.
+    pub metrics_listen: Option<String>,
+    /// The networks clients may query from, in CIDR notation.
+    pub allow: Vec<String>,
+    /// The networks clients may have names resolved recursively from, on top
+    /// of being allowed to query. Only the local host and private networks
+    /// unless given, so that recursion has to be opened up explicitly.
+    pub allow_recursion: Option<Vec<String>>,
+    /// The networks clients may transfer zones from.
+    pub allow_transfer: Vec<String>,
+    pub client_subnet: ClientSubnetSection,
+    pub rate_limit: RateLimitSection,
+    pub dnstap: DnstapSection,
+    /// Blocklists and response policy zones, in order of precedence.
+    #[serde(rename = "policy")]
+    pub policies: Vec<PolicySection>,
+    /// Seconds between checks for changed policy files. Zero disables
+    /// reloading.
+    pub policy_reload_interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["0.0.0.0".to_string(), "::".to_string()],
+            listeners: Vec::new(),
+            port: 2053,
+            mode: Mode::Recursive,
+            root_server: None,
+            forwarders: Vec::new(),
+            forward_selection: Selection::RoundRobin,
+            zones: Vec::new(),
+            zone_files: Vec::new(),
+            hosts_file: None,
+            workers: 32,
+            queue_size: 1024,
+            cache_size: 10_000,
+            timeout: 5,
+            log_level: LogLevel::Info,
+            query_log: false,
+            metrics_listen: None,
+            allow: vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
+            allow_recursion: None,
+            allow_transfer: Vec::new(),
+            client_subnet: ClientSubnetSection::default(),
+            rate_limit: RateLimitSection::default(),
+            dnstap: DnstapSection::default(),
+            policies: Vec::new(),
+            policy_reload_interval: 300,
+        }
+    }
+}
+
+/// What the command line asks the server to do.
+pub enum Command {
+    Run(Box<Config>),
+    Help(String),
+}
+
+impl Config {
+    /// Parses a configuration file. Settings missing from it keep their
+    /// default values.
+    pub fn from_toml(data: &str) -> Result<Config> {
+        toml::from_str(data).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Reads the configuration from the command line, and from the file it
+    /// points to with `--config`. `args` excludes the program name.
+    pub fn from_args<S: AsRef<str>>(program: &str, args: &[S]) -> Result<Command> {
+        let mut opts = Options::new();
+        opts.optopt("c", "config", "read settings from a TOML file", "FILE");
+        opts.optmulti("l", "listen", "address to accept queries on", "ADDR");
+        opts.optopt("p", "port", "port to accept queries on", "PORT");
+        opts.optopt("m", "mode", "recursive, forwarding or authoritative", "MODE");
+        opts.optopt("", "root-server", "server to start recursive lookups from", "ADDR");
+        opts.optmulti("", "forwarder", "resolver to forward queries to", "ADDR");
+        opts.optopt("", "forward-selection", "round-robin or lowest-latency", "HOW");
+        opts.optmulti("", "forward-zone", "forward a zone to resolvers", "ZONE=ADDR,..");
+        opts.optmulti("", "stub-zone", "resolve a zone from its name servers", "ZONE=ADDR,..");
+        opts.optmulti("", "zone-file", "serve a zone from a zone file", "ORIGIN=PATH");
+        opts.optopt("", "hosts-file", "answer the names in a hosts file", "PATH");
+        opts.optopt("", "workers", "number of queries resolved at once", "COUNT");
+        opts.optopt("", "queue-size", "number of queries waiting for a worker", "COUNT");
+        opts.optopt("", "cache-size", "number of packets to cache", "COUNT");
+        opts.optopt("", "timeout", "seconds to wait for upstream servers", "SECS");
+        opts.optopt("", "log-level", "error, warn, info, debug or trace", "LEVEL");
+        opts.optflag("", "query-log", "log every query answered");
+        opts.optopt("", "rate-limit", "responses per second to each network", "COUNT");
+        opts.optflag("", "rate-limit-log-only", "only log responses that would be limited");
+        opts.optopt("", "metrics-listen", "address to serve metrics on", "ADDR");
+        opts.optopt("", "dnstap-socket", "log dnstap messages to a collector", "PATH");
+        opts.optopt("", "dnstap-file", "log dnstap messages to a file", "PATH");
+        opts.optmulti("", "blocklist", "block the names in a hosts file", "PATH");
+        opts.optmulti("", "domain-list", "block the domains listed in a file", "PATH");
+        opts.optmulti("", "rpz", "apply a response policy zone", "ORIGIN=PATH");
+        opts.optmulti("", "allow", "network allowed to query, in CIDR notation", "NET");
+        opts.optmulti("", "allow-recursion", "network allowed to recurse", "NET");
+        opts.optmulti("", "allow-transfer", "network allowed to transfer zones", "NET");
+        opts.optflag("h", "help", "print this help");
+
+        let args: Vec<&str> = args.iter().map(|x| x.as_ref()).collect();
+        let matches = opts.parse(&args).map_err(|e| invalid(e.to_string()))?;
+        if matches.opt_present("h") {
+            let brief = format!("Usage: {} [options]", program);
+            return Ok(Command::Help(opts.usage(&brief)));
+        }
+        if !matches.free.is_empty() {
+            return Err(invalid(format!("Unexpected argument: {}", matches.free[0])));
+        }
+
+        let mut config = match matches.opt_str("c") {
+            Some(path) => {
+                let data = fs::read_to_string(&path)
+                    .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))?;
+                Config::from_toml(&data).map_err(|e| invalid(format!("{}: {}", path, e)))?
+            }
+            None => Config::default(),
+        };
+
+        let listen = matches.opt_strs("l");
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        let forwarders = matches.opt_strs("forwarder");
+        if !forwarders.is_empty() {
+            config.forwarders = forwarders;
+        }
+        if let Some(x) = matches.opt_str("forward-selection") {
+            config.forward_selection = x.parse()?;
+        }
+        for x in matches.opt_strs("forward-zone") {
+            config.zones.push(ZoneSection::from_arg(&x, false)?);
+        }
+        for x in matches.opt_strs("stub-zone") {
+            config.zones.push(ZoneSection::from_arg(&x, true)?);
+        }
+        for x in matches.opt_strs("zone-file") {
+            let (origin, path) = match x.find('=') {
+                Some(i) => (&x[..i], &x[i + 1..]),
+                None => return Err(invalid(format!("Expected ORIGIN=PATH: {}", x))),
+            };
+            config.zone_files.push(ZoneFileSection {
+                origin: origin.to_string(),
+                path: path.to_string(),
+            });
+        }
+        for x in matches.opt_strs("blocklist") {
+            config.policies.push(PolicySection {
+                name: None,
+                path: x,
+                format: PolicyFormat::Hosts.to_string(),
+            });
+        }
+        for x in matches.opt_strs("domain-list") {
+            config.policies.push(PolicySection {
+                name: None,
+                path: x,
+                format: PolicyFormat::Domains.to_string(),
+            });
+        }
+        for x in matches.opt_strs("rpz") {
+            let (origin, path) = match x.find('=') {
+                Some(i) => (&x[..i], &x[i + 1..]),
+                None => return Err(invalid(format!("Expected ORIGIN=PATH: {}", x))),
+            };
+            config.policies.push(PolicySection {
+                name: Some(origin.to_string()),
+                path: path.to_string(),
+                format: PolicyFormat::Rpz.to_string(),
+            });
+        }
+        if let Some(x) = matches.opt_str("hosts-file") {
+            config.hosts_file = Some(x);
+        }
+        let allow = matches.opt_strs("allow");
+        if !allow.is_empty() {
+            config.allow = allow;
+        }
+        let allow_recursion = matches.opt_strs("allow-recursion");
+        if !allow_recursion.is_empty() {
+            config.allow_recursion = Some(allow_recursion);
+        }
+        let allow_transfer = matches.opt_strs("allow-transfer");
+        if !allow_transfer.is_empty() {
+            config.allow_transfer = allow_transfer;
+        }
+        if let Some(x) = matches.opt_str("p") {
+            config.port = parse_value("port", &x)?;
+        }
+        if let Some(x) = matches.opt_str("m") {
+            config.mode = x.parse()?;
+        }
+        if let Some(x) = matches.opt_str("root-server") {
+            config.root_server = Some(parse_value("root server", &x)?);
+        }
+        if let Some(x) = matches.opt_str("workers") {
+            config.workers = parse_value("worker count", &x)?;
+        }
+        if let Some(x) = matches.opt_str("queue-size") {
+            config.queue_size = parse_value("queue size", &x)?;
+        }
+        if let Some(x) = matches.opt_str("cache-size") {
+            config.cache_size = parse_value("cache size", &x)?;
+        }
+        if let Some(x) = matches.opt_str("timeout") {
+            config.timeout = parse_value("timeout", &x)?;
+        }
+        if let Some(x) = matches.opt_str("log-level") {
+            config.log_level = x.parse()?;
+        }
+        if matches.opt_present("query-log") {
+            config.query_log = true;
+        }
+        if let Some(x) = matches.opt_str("rate-limit") {
+            config.rate_limit.enabled = true;
+            config.rate_limit.responses_per_second = parse_value("rate limit", &x)?;
+        }
+        if matches.opt_present("rate-limit-log-only") {
+            config.rate_limit.log_only = true;
+        }
+        if let Some(x) = matches.opt_str("metrics-listen") {
+            config.metrics_listen = Some(x);
+        }
+        if let Some(x) = matches.opt_str("dnstap-socket") {
+            config.dnstap.socket = Some(x);
+            config.dnstap.file = None;
+        }
+        if let Some(x) = matches.opt_str("dnstap-file") {
+            config.dnstap.file = Some(x);
+            config.dnstap.socket = None;
+        }
+
+        config.validate()?;
+
+        Ok(Command::Run(Box::new(config)))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() && self.listeners.is_empty() {
+            return Err(invalid("At least one listen address is required".to_string()));
+        }
+        if self.workers == 0 {
+            return Err(invalid("At least one worker is required".to_string()));
+        }
+        if self.timeout == 0 {
+            return Err(invalid("The timeout must be at least one second".to_string()));
+        }
+        if self.rate_limit.enabled && self.rate_limit.responses_per_second == 0 {
+            return Err(invalid("The rate limit must be at least one response".to_string()));
+        }
+        if self.mode == Mode::Forwarding && self.forwarders.is_empty() {
+            return Err(invalid("Forwarding mode requires forwarders".to_string()));
+        }
+        let local_data = !self.zone_files.is_empty() || self.hosts_file.is_some();
+        if self.mode == Mode::Authoritative && !local_data {
+            return Err(invalid(
+                "Authoritative mode requires zone files or a hosts file".to_string(),
+            ));
+        }
+        self.listeners()?;
+        self.forwarder_addrs()?;
+        self.zone_routes()?;
+        self.metrics_addr()?;
+        self.dnstap_output()?;
+        self.dnstap_types()?;
+        self.policy_sources()?;
+
+        Ok(())
+    }
+
+    /// The socket addresses to listen on. Listen addresses may carry a port
+    /// of their own, as in `127.0.0.1:5353` or `[::1]:5353`.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.listen
+            .iter()
+            .map(|addr| parse_addr(addr, self.port, "listen address"))
+            .collect()
+    }
+
+    /// The addresses of the forwarders, on port 53 unless they specify one.
+    pub fn forwarder_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.forwarders
+            .iter()
+            .map(|addr| parse_addr(addr, 53, "forwarder"))
+            .collect()
+    }
+
+    /// The routing table built from the `[[zone]]` sections.
+    pub fn zone_routes(&self) -> Result<ZoneRoutes> {
+        let mut routes = ZoneRoutes::new();
+        for zone in &self.zones {
+            let parse = |servers: &[String]| {
+                servers
+                    .iter()
+                    .map(|addr| parse_addr(addr, 53, "server"))
+                    .collect::<Result<Vec<_>>>()
+            };
+
+            let route = match (zone.forwarders.is_empty(), zone.nameservers.is_empty()) {
+                (false, true) => ZoneRoute::Forward(Forwarder::new(
+                    parse(&zone.forwarders)?,
+                    self.forward_selection.upstream_selection(),
+                )),
+                (true, false) => ZoneRoute::Stub(parse(&zone.nameservers)?),
+                _ => {
+                    return Err(invalid(format!(
+                        "Zone {} needs either forwarders or nameservers",
+                        zone.name
+                    )))
+                }
+            };
+            routes.add(&zone.name, route);
+        }
+
+        Ok(routes)
+    }
+
+    /// Reads the zones from the `[[zone-file]]` sections.
+    pub fn zone_store(&self) -> Result<ZoneStore> {
+        let mut store = ZoneStore::new();
+        for zone in &self.zone_files {
+            store.add(Zone::from_file(&zone.path, &zone.origin)?);
+        }
+
+        Ok(store)
+    }
+
+    /// Reads the hosts file, if there is one.
+    pub fn hosts(&self) -> Result<Option<HostsFile>> {
+        match self.hosts_file {
+            Some(ref x) => HostsFile::load(x).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The access lists of the addresses in `listen`.
+    pub fn access_control(&self) -> Result<AccessControl> {
+        let recursion = match self.allow_recursion {
+            Some(ref x) => AccessList::parse(x)?,
+            None => AccessList::local(),
+        };
+        Ok(AccessControl {
+            query: AccessList::parse(&self.allow)?,
+            recursion,
+            transfer: AccessList::parse(&self.allow_transfer)?,
+        })
+    }
+
+    /// Every address to listen on, along with its access lists. Those of
+    /// `[[listener]]` sections fall back to the global lists.
+    pub fn listeners(&self) -> Result<Vec<(SocketAddr, AccessControl)>> {
+        let access = self.access_control()?;
+        let mut listeners: Vec<(SocketAddr, AccessControl)> = self
+            .listen_addrs()?
+            .into_iter()
+            .map(|addr| (addr, access.clone()))
+            .collect();
+
+        for listener in &self.listeners {
+            let addr = parse_addr(&listener.address, self.port, "listen address")?;
+            let parse = |list: &Option<Vec<String>>, default: &AccessList| match *list {
+                Some(ref x) => AccessList::parse(x),
+                None => Ok(default.clone()),
+            };
+            listeners.push((
+                addr,
+                AccessControl {
+                    query: parse(&listener.allow, &access.query)?,
+                    recursion: parse(&listener.allow_recursion, &access.recursion)?,
+                    transfer: parse(&listener.allow_transfer, &access.transfer)?,
+                },
+            ));
+        }
+
+        Ok(listeners)
+    }
+
+    /// The address to serve metrics on, on port 9153 unless it specifies one.
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>> {
+        match self.metrics_listen {
+            Some(ref x) => parse_addr(x, 9153, "metrics address").map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the `[dnstap]` section logs to, if anywhere.
+    pub fn dnstap_output(&self) -> Result<Option<DnstapOutput>> {
+        match (&self.dnstap.socket, &self.dnstap.file) {
+            (Some(_), Some(_)) => Err(invalid(
+                "Dnstap can log to either a socket or a file, not both".to_string(),
+            )),
+            (Some(x), None) => Ok(Some(DnstapOutput::Socket(PathBuf::from(x)))),
+            (None, Some(x)) => Ok(Some(DnstapOutput::File(PathBuf::from(x)))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn dnstap_types(&self) -> Result<Vec<DnstapMessageType>> {
+        self.dnstap.message_types.iter().map(|x| x.parse()).collect()
+    }
+
+    /// Starts logging to dnstap, if the `[dnstap]` section asks for it.
+    pub fn dnstap(&self) -> Result<Option<Dnstap>> {
+        let output = match self.dnstap_output()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        Dnstap::new(output, self.dnstap_types()?, self.dnstap.identity.clone()).map(Some)
+    }
+
+    /// The files of the `[[policy]]` sections.
+    pub fn policy_sources(&self) -> Result<Vec<PolicySource>> {
+        let mut sources = Vec::new();
+        for policy in &self.policies {
+            let format: PolicyFormat = policy.format.parse()?;
+            let path = PathBuf::from(&policy.path);
+            let name = match (&policy.name, format) {
+                (Some(x), _) => x.clone(),
+                (None, PolicyFormat::Rpz) => {
+                    return Err(invalid(format!("Policy zone {} needs a name", policy.path)))
+                }
+                (None, _) => match path.file_name() {
+                    Some(x) => x.to_string_lossy().into_owned(),
+                    None => policy.path.clone(),
+                },
+            };
+            sources.push(PolicySource { name, path, format });
+        }
+
+        Ok(sources)
+    }
+
+    /// Loads the policy zones, if there are any.
+    pub fn policy(&self) -> Result<Option<Policy>> {
+        let sources = self.policy_sources()?;
+        if sources.is_empty() {
+            return Ok(None);
+        }
+
+        Policy::load(sources).map(Some)
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimitConfig> {
+        if !self.rate_limit.enabled {
+            return None;
+        }
+
+        Some(RateLimitConfig {
+            responses_per_second: self.rate_limit.responses_per_second,
+            slip: self.rate_limit.slip,
+            ipv4_prefix: self.rate_limit.ipv4_prefix.min(32),
+            ipv6_prefix: self.rate_limit.ipv6_prefix.min(128),
+            log_only: self.rate_limit.log_only,
+        })
+    }
+
+    pub fn client_subnet(&self) -> Option<ClientSubnetConfig> {
+        if !self.client_subnet.enabled {
+            return None;
+        }
+
+        Some(ClientSubnetConfig {
+            ipv4_prefix: self.client_subnet.ipv4_prefix.min(32),
+            ipv6_prefix: self.client_subnet.ipv6_prefix.min(128),
+        })
+    }
+}
+
+// Parses an address that may or may not come with a port.
+fn parse_addr(addr: &str, default_port: u16, name: &str) -> Result<SocketAddr> {
+    if let Ok(x) = addr.parse::<SocketAddr>() {
+        return Ok(x);
+    }
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => Err(invalid(format!("Invalid {}: {}", name, addr))),
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("Invalid {}: {}", name, value)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config> {
+        match Config::from_args("dnsafe", args)? {
+            Command::Run(config) => Ok(*config),
+            Command::Help(_) => panic!("unexpected help"),
+        }
+    }
+
+    #[test]
+    fn it_reads_a_config_file() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["127.0.0.1", "[::1]:5353"]
+            port = 53
+            mode = "recursive"
+            cache-size = 100
+            log-level = "debug"
+            query-log = true
+            allow = ["127.0.0.0/8"]
+
+            [client-subnet]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(53, config.port);
+        assert_eq!(100, config.cache_size);
+        assert_eq!(LogLevel::Debug, config.log_level);
+        assert!(config.query_log);
+        assert_eq!(None, config.client_subnet());
+        assert_eq!(5, config.timeout);
+        assert_eq!(
+            vec![
+                "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+                "[::1]:5353".parse().unwrap(),
+            ],
+            config.listen_addrs().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_leaves_client_subnet_off_by_default() {
+        assert_eq!(None, parse(&[]).unwrap().client_subnet());
+
+        let config = Config::from_toml("[client-subnet]\nipv4-prefix = 20\n").unwrap();
+        assert_eq!(None, config.client_subnet());
+
+        let config = Config::from_toml("[client-subnet]\nenabled = true\n").unwrap();
+        assert_eq!(Some(ClientSubnetConfig::default()), config.client_subnet());
+    }
+
+    #[test]
+    fn it_reads_forwarders() {
+        let config = Config::from_toml(
+            r#"
+            mode = "forwarding"
+            forwarders = ["10.0.0.53", "[2001:db8::53]:5353"]
+            forward-selection = "lowest-latency"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Mode::Forwarding, config.mode);
+        assert_eq!(Selection::LowestLatency, config.forward_selection);
+        assert_eq!(
+            vec![
+                "10.0.0.53:53".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::53]:5353".parse().unwrap(),
+            ],
+            config.forwarder_addrs().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_reads_zone_routes() {
+        let config = Config::from_toml(
+            r#"
+            [[zone]]
+            name = "corp.internal"
+            forwarders = ["10.0.0.53", "10.0.1.53"]
+
+            [[zone]]
+            name = "10.in-addr.arpa"
+            nameservers = ["10.0.0.1"]
+            "#,
+        )
+        .unwrap();
+        let routes = config.zone_routes().unwrap();
+
+        match routes.route_for("www.corp.internal") {
+            Some((_, ZoneRoute::Forward(x))) => assert_eq!(2, x.upstreams().len()),
+            _ => panic!("expected a forwarded zone"),
+        }
+        match routes.route_for("1.0.0.10.in-addr.arpa") {
+            Some((_, ZoneRoute::Stub(x))) => {
+                assert_eq!(vec!["10.0.0.1:53".parse::<SocketAddr>().unwrap()], *x)
+            }
+            _ => panic!("expected a stub zone"),
+        }
+    }
+
+    #[test]
+    fn it_reads_zone_routes_from_the_command_line() {
+        let config = parse(&[
+            "--forward-zone", "corp.internal=10.0.0.53,10.0.1.53:5353",
+            "--stub-zone", "10.in-addr.arpa=10.0.0.1",
+        ])
+        .unwrap();
+
+        assert_eq!(2, config.zones.len());
+        assert_eq!(2, config.zone_routes().unwrap().len());
+        assert!(parse(&["--stub-zone", "corp.internal"]).is_err());
+        assert!(parse(&["--stub-zone", "corp.internal=ns1"]).is_err());
+    }
+
+    #[test]
+    fn it_reads_access_lists_per_listener() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["127.0.0.1"]
+            allow = ["10.0.0.0/8"]
+            allow-transfer = ["10.0.0.2"]
+
+            [[listener]]
+            address = "192.0.2.1"
+            allow = ["0.0.0.0/0"]
+
+            [[listener]]
+            address = "192.0.2.2:5353"
+            allow-recursion = ["192.0.2.0/24"]
+            "#,
+        )
+        .unwrap();
+
+        let listeners = config.listeners().unwrap();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|&(addr, _)| addr).collect();
+        assert_eq!(
+            vec![
+                "127.0.0.1:2053".parse::<SocketAddr>().unwrap(),
+                "192.0.2.1:2053".parse().unwrap(),
+                "192.0.2.2:5353".parse().unwrap(),
+            ],
+            addrs
+        );
+
+        let internal = "10.1.2.3".parse().unwrap();
+        let outside = "198.51.100.1".parse().unwrap();
+        let transfer = "10.0.0.2".parse().unwrap();
+
+        let global = &listeners[0].1;
+        assert!(global.query.allows(internal) && global.recursion.allows(internal));
+        assert!(!global.query.allows(outside) && !global.recursion.allows(outside));
+        assert!(global.transfer.allows(transfer) && !global.transfer.allows(internal));
+
+        // Opening up a listener to queries doesn't open it up to recursion.
+        let public = &listeners[1].1;
+        assert!(public.query.allows(outside) && !public.recursion.allows(outside));
+        assert!(public.recursion.allows(internal));
+        assert!(public.transfer.allows(transfer));
+
+        let restricted = &listeners[2].1;
+        assert!(restricted.query.allows(internal) && !restricted.recursion.allows(internal));
+
+        let config = parse(&["--allow-recursion", "127.0.0.1", "--allow-transfer", "::1"]).unwrap();
+        let access = config.access_control().unwrap();
+        assert!(access.query.allows(outside) && !access.recursion.allows(outside));
+        assert!(access.transfer.allows("::1".parse().unwrap()));
+
+        assert!(Config::from_toml("[[listener]]\naddress = \"a\"").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn it_reads_rate_limits() {
+        assert_eq!(None, Config::default().rate_limit());
+
+        let config = Config::from_toml("[rate-limit]\nenabled = true\nslip = 0").unwrap();
+        let rate_limit = config.rate_limit().unwrap();
+        assert_eq!(5, rate_limit.responses_per_second);
+        assert_eq!(0, rate_limit.slip);
+
+        let config = parse(&["--rate-limit", "20", "--rate-limit-log-only"]).unwrap();
+        let rate_limit = config.rate_limit().unwrap();
+        assert_eq!(20, rate_limit.responses_per_second);
+        assert!(rate_limit.log_only);
+
+        assert!(parse(&["--rate-limit", "0"]).is_err());
+    }
+
+    #[test]
+    fn it_reads_dnstap_settings() {
+        let config = Config::from_toml(
+            r#"
+            [dnstap]
+            socket = "/run/dnstap.sock"
+            message-types = ["client-query", "resolver-response"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(DnstapOutput::Socket(PathBuf::from("/run/dnstap.sock"))),
+            config.dnstap_output().unwrap()
+        );
+        assert_eq!(
+            vec![DnstapMessageType::ClientQuery, DnstapMessageType::ResolverResponse],
+            config.dnstap_types().unwrap()
+        );
+
+        let config = parse(&["--dnstap-file", "/var/log/dnsafe.dnstap"]).unwrap();
+        assert_eq!(
+            Some(DnstapOutput::File(PathBuf::from("/var/log/dnsafe.dnstap"))),
+            config.dnstap_output().unwrap()
+        );
+        assert_eq!(8, config.dnstap_types().unwrap().len());
+        assert_eq!(None, Config::default().dnstap_output().unwrap());
+
+        let config = Config::from_toml("[dnstap]\nmessage-types = [\"client\"]").unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::from_toml("[dnstap]\nsocket = \"a\"\nfile = \"b\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn it_reads_a_hosts_file() {
+        let config = parse(&["-m", "authoritative", "--hosts-file", "/etc/dnsafe/hosts"]).unwrap();
+        assert_eq!(Some("/etc/dnsafe/hosts".to_string()), config.hosts_file);
+        assert!(parse(&["-m", "authoritative"]).is_err());
+
+        let config = Config::from_toml("hosts-file = \"/nonexistent/hosts\"").unwrap();
+        assert!(config.hosts().is_err());
+        assert!(Config::default().hosts().unwrap().is_none());
+    }
+
+    #[test]
+    fn it_reads_policies() {
+        let config = Config::from_toml(
+            r#"
+            policy-reload-interval = 60
+
+            [[policy]]
+            path = "/etc/dnsafe/ads.hosts"
+            format = "hosts"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(60, config.policy_reload_interval);
+
+        let config = parse(&[
+            "--domain-list",
+            "/etc/dnsafe/malware.txt",
+            "--rpz",
+            "rpz.local=/etc/dnsafe/rpz.zone",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                PolicySource {
+                    name: "malware.txt".to_string(),
+                    path: PathBuf::from("/etc/dnsafe/malware.txt"),
+                    format: PolicyFormat::Domains,
+                },
+                PolicySource {
+                    name: "rpz.local".to_string(),
+                    path: PathBuf::from("/etc/dnsafe/rpz.zone"),
+                    format: PolicyFormat::Rpz,
+                },
+            ],
+            config.policy_sources().unwrap()
+        );
+
+        let config = Config::from_toml(
+            "[[policy]]\npath = \"rpz.zone\"\nformat = \"rpz\"",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::from_toml(
+            "[[policy]]\npath = \"list\"\nformat = \"adblock\"",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn it_rejects_unknown_settings() {
+        assert!(Config::from_toml("cache = 100").is_err());
+        assert!(Config::from_toml("mode = \"proxy\"").is_err());
+
+        let config = Config::from_toml("[[zone]]\nname = \"corp.internal\"").unwrap();
+        assert!(config.zone_routes().is_err());
+    }
+
+    #[test]
+    fn it_overrides_settings_from_the_command_line() {
+        let config = parse(&[
+            "-l", "127.0.0.1", "--listen", "::1", "-p", "5353", "--timeout", "2",
+            "--allow", "10.0.0.0/8", "--log-level", "warn", "--query-log",
+            "--metrics-listen", "127.0.0.1",
+        ])
+        .unwrap();
+
+        assert_eq!(Some("127.0.0.1:9153".parse().unwrap()), config.metrics_addr().unwrap());
+        assert_eq!(5353, config.port);
+        assert_eq!(2, config.timeout);
+        assert_eq!(LogLevel::Warn, config.log_level);
+        assert!(config.query_log);
+        assert_eq!(vec!["10.0.0.0/8".to_string()], config.allow);
+        assert_eq!(2, config.listen_addrs().unwrap().len());
+        assert_eq!(Mode::Recursive, config.mode);
+    }
+
+    #[test]
+    fn it_rejects_invalid_arguments() {
+        assert!(parse(&["--port", "dns"]).is_err());
+        assert!(parse(&["--mode", "proxy"]).is_err());
+        assert!(parse(&["--listen", "localhost"]).is_err());
+        assert!(parse(&["--allow", "10.0.0.0/40"]).is_err());
+        assert!(parse(&["--workers", "0"]).is_err());
+        assert!(parse(&["--mode", "forwarding"]).is_err());
+        assert!(parse(&["--mode", "authoritative"]).is_err());
+        assert!(parse(&["--zone-file", "example.com"]).is_err());
+        assert!(parse(&["--forwarder", "resolver"]).is_err());
+        assert!(parse(&["--metrics-listen", "localhost:9153"]).is_err());
+        assert!(parse(&["extra"]).is_err());
+    }
+}
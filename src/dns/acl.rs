@@ -0,0 +1,167 @@
+//! Access control by client address
+//!
+//! An access list is a set of networks in CIDR notation, such as `10.0.0.0/8`
+//! or `2001:db8::/32`. A bare address stands for a network of just that host.
+//! Separate lists decide who may query at all, who may have names resolved
+//! recursively, and who may transfer zones. Unless told otherwise, only the
+//! local host and private networks may have names resolved, so that we don't
+//! end up as an open resolver.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::dns::client_subnet::{subnet_contains, truncate};
+
+/// The networks clients are allowed to query from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl AccessList {
+    /// An access list that allows every client.
+    pub fn allow_all() -> AccessList {
+        AccessList {
+            networks: vec![
+                ("0.0.0.0".parse().unwrap(), 0),
+                ("::".parse().unwrap(), 0),
+            ],
+        }
+    }
+
+    /// An access list of the local host and private networks: the loopback
+    /// addresses, the networks of RFC 1918 and unique local addresses.
+    pub fn local() -> AccessList {
+        AccessList::parse(&[
+            "127.0.0.0/8",
+            "::1",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "fc00::/7",
+        ])
+        .unwrap()
+    }
+
+    /// Builds an access list from networks in CIDR notation.
+    pub fn parse<S: AsRef<str>>(networks: &[S]) -> Result<AccessList> {
+        let mut list = AccessList::default();
+        for network in networks {
+            list.networks.push(parse_network(network.as_ref())?);
+        }
+
+        Ok(list)
+    }
+
+    /// Whether `addr` falls within any of the networks on the list. An empty
+    /// list allows nobody.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        // IPv4 clients reaching a dual-stack socket show up as mapped addresses.
+        let addr = match addr {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+
+        self.networks
+            .iter()
+            .any(|&(net, prefix)| subnet_contains(net, prefix, addr))
+    }
+}
+
+/// The access lists deciding what clients may ask of the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessControl {
+    /// Who may query at all.
+    pub query: AccessList,
+    /// Who may have names outside of our own zones resolved, from the cache
+    /// or otherwise.
+    pub recursion: AccessList,
+    /// Who may transfer our zones.
+    pub transfer: AccessList,
+}
+
+impl Default for AccessControl {
+    /// Anybody may query, only the local host and private networks may
+    /// recurse, and nobody may transfer zones.
+    fn default() -> Self {
+        AccessControl {
+            query: AccessList::allow_all(),
+            recursion: AccessList::local(),
+            transfer: AccessList::default(),
+        }
+    }
+}
+
+fn parse_network(network: &str) -> Result<(IpAddr, u8)> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid network: {}", network),
+        )
+    };
+
+    let (addr, prefix) = match network.find('/') {
+        Some(i) => (&network[..i], Some(&network[i + 1..])),
+        None => (network, None),
+    };
+
+    let addr = IpAddr::from_str(addr.trim()).map_err(|_| invalid())?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(x) => x.trim().parse::<u8>().map_err(|_| invalid())?,
+        None => max_prefix,
+    };
+    if prefix > max_prefix {
+        return Err(invalid());
+    }
+
+    Ok((truncate(addr, prefix), prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_networks_and_hosts() {
+        let list = AccessList::parse(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"]).unwrap();
+
+        assert!(list.allows("10.1.2.3".parse().unwrap()));
+        assert!(list.allows("192.0.2.1".parse().unwrap()));
+        assert!(list.allows("2001:db8:1::1".parse().unwrap()));
+        assert!(!list.allows("192.0.2.2".parse().unwrap()));
+        assert!(!list.allows("11.0.0.1".parse().unwrap()));
+        assert!(!list.allows("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_matches_mapped_ipv4_addresses() {
+        let list = AccessList::parse(&["127.0.0.0/8"]).unwrap();
+
+        assert!(list.allows("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(AccessList::allow_all().allows("::ffff:192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_only_recurses_for_local_networks_by_default() {
+        let access = AccessControl::default();
+
+        for addr in &["127.0.0.1", "::1", "10.1.2.3", "172.31.0.1", "192.168.1.1", "fd00::1"] {
+            let addr = addr.parse().unwrap();
+            assert!(access.query.allows(addr) && access.recursion.allows(addr));
+        }
+        for addr in &["192.0.2.1", "172.32.0.1", "2001:db8::1"] {
+            let addr = addr.parse().unwrap();
+            assert!(access.query.allows(addr) && !access.recursion.allows(addr));
+        }
+        assert!(!access.transfer.allows("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_rejects_invalid_networks() {
+        assert!(AccessList::parse(&["10.0.0.0/33"]).is_err());
+        assert!(AccessList::parse(&["example.com"]).is_err());
+        assert!(AccessList::parse(&["10.0.0.0/"]).is_err());
+    }
+}
@@ -51,7 +51,7 @@ impl PacketBuffer for BytePacketBuffer {
         if start + len >= 512 {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
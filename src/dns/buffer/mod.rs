@@ -1,6 +1,6 @@
 //! buffers for use when writing and reading dns packets
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
 mod byte_packet_buffer;
 mod stream_packet_buffer;
@@ -11,6 +11,8 @@ pub use self::stream_packet_buffer::StreamPacketBuffer;
 pub use self::vector_packet_buffer::VectorPacketBuffer;
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module, clippy::unnecessary_cast,
+        clippy::unnecessary_to_owned)]
 mod tests {
 
     use super::*;
@@ -99,6 +101,20 @@ mod tests {
 
         assert_eq!("ns2.google.com", str2);
     }
+
+    #[test]
+    fn it_rejects_compression_loops() {
+        // A pointer to itself, and two pointers to each other.
+        for data in &[vec![0xC0, 0x00], vec![0xC0, 0x02, 0xC0, 0x00]] {
+            let mut buffer = VectorPacketBuffer::new();
+            buffer.buffer = data.clone();
+            buffer.pos = data.len() - 2;
+
+            let mut name = String::new();
+            let err = buffer.read_qname(&mut name).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+        }
+    }
 }
 
 pub trait PacketBuffer {
@@ -114,43 +130,48 @@ pub trait PacketBuffer {
     fn save_label(&mut self, label: &str, pos: usize);
 
     fn write_u8(&mut self, val: u8) -> Result<()> {
-        try!(self.write(val));
+        self.write(val)?;
 
         Ok(())
     }
 
     fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
-        try!(self.set(pos, (val >> 8) as u8));
-        try!(self.set(pos + 1, (val & 0xFF) as u8));
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
 
         Ok(())
     }
 
     fn write_u16(&mut self, val: u16) -> Result<()> {
-        try!(self.write((val >> 8) as u8));
-        try!(self.write((val & 0xFF) as u8));
+        self.write((val >> 8) as u8)?;
+        self.write((val & 0xFF) as u8)?;
 
         Ok(())
     }
 
     fn write_u32(&mut self, val: u32) -> Result<()> {
-        try!(self.write(((val >> 24) & 0xFF) as u8));
-        try!(self.write(((val >> 16) & 0xFF) as u8));
-        try!(self.write(((val >> 8) & 0xFF) as u8));
-        try!(self.write((val & 0xFF) as u8));
+        self.write(((val >> 24) & 0xFF) as u8)?;
+        self.write(((val >> 16) & 0xFF) as u8)?;
+        self.write(((val >> 8) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
 
         Ok(())
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        let split_str = qname.split('.').collect::<Vec<&str>>();
+        // Empty labels are skipped so that the root name, as well as names with
+        // a trailing dot, are encoded with a single terminating zero.
+        let split_str = qname
+            .split('.')
+            .filter(|label| !label.is_empty())
+            .collect::<Vec<&str>>();
 
         let mut jump_performed = false;
         for (i, label) in split_str.iter().enumerate() {
             let search_lbl = split_str[i..split_str.len()].join(".");
             if let Some(prev_pos) = self.find_label(&search_lbl) {
                 let jump_inst = (prev_pos as u16) | 0xC000;
-                try!(self.write_u16(jump_inst));
+                self.write_u16(jump_inst)?;
                 jump_performed = true;
 
                 break;
@@ -160,29 +181,29 @@ pub trait PacketBuffer {
             self.save_label(&search_lbl, pos);
 
             let len = label.len();
-            try!(self.write_u8(len as u8));
+            self.write_u8(len as u8)?;
             for b in label.as_bytes() {
-                try!(self.write_u8(*b));
+                self.write_u8(*b)?;
             }
         }
 
         if !jump_performed {
-            try!(self.write_u8(0));
+            self.write_u8(0)?;
         }
 
         Ok(())
     }
 
     fn read_u16(&mut self) -> Result<u16> {
-        let res = (u16::from(try!(self.read())) << 8) | (u16::from(try!(self.read())));
+        let res = (u16::from(self.read()?) << 8) | (u16::from(self.read()?));
 
         Ok(res)
     }
 
     fn read_u32(&mut self) -> Result<u32> {
-        let res = (u32::from(try!(self.read())) << 24) | (u32::from(try!(self.read())) << 16)
-            | (u32::from(try!(self.read())) << 8)
-            | (u32::from(try!(self.read())));
+        let res = (u32::from(self.read()?) << 24) | (u32::from(self.read()?) << 16)
+            | (u32::from(self.read()?) << 8)
+            | (u32::from(self.read()?));
 
         Ok(res)
     }
@@ -190,10 +211,13 @@ pub trait PacketBuffer {
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jumped = false;
+        // Where the labels being read start. Every jump has to go back before
+        // this, so that crafted pointers can't send us round in circles.
+        let mut start = pos;
 
         let mut delim = "";
         loop {
-            let len = try!(self.get(pos));
+            let len = self.get(pos)?;
 
             // A two byte sequence, where the two highest bits of the first byte is
             // set, represents a offset relative to the start of the buffer. We
@@ -203,12 +227,19 @@ pub trait PacketBuffer {
                 // When a jump is performed, we only modify the shared buffer
                 // position once, and avoid making the change later on.
                 if !jumped {
-                    try!(self.seek(pos + 2));
+                    self.seek(pos + 2)?;
                 }
 
-                let b2 = u16::from(try!(self.get(pos + 1)));
+                let b2 = u16::from(self.get(pos + 1)?);
                 let offset = ((u16::from(len) ^ 0xC0) << 8) | b2;
+                if offset as usize >= start {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Compression pointer at {} doesn't point backwards", pos),
+                    ));
+                }
                 pos = offset as usize;
+                start = pos;
                 jumped = true;
                 continue;
             }
@@ -222,7 +253,7 @@ pub trait PacketBuffer {
 
             outstr.push_str(delim);
 
-            let str_buffer = try!(self.get_range(pos, len as usize));
+            let str_buffer = self.get_range(pos, len as usize)?;
             outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
 
             delim = ".";
@@ -231,7 +262,7 @@ pub trait PacketBuffer {
         }
 
         if !jumped {
-            try!(self.seek(pos));
+            self.seek(pos)?;
         }
 
         Ok(())
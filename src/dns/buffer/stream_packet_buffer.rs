@@ -16,7 +16,7 @@ where
     T: Read + 'a,
 {
     #[allow(dead_code)]
-    pub fn new(stream: &'a mut T) -> StreamPacketBuffer<T> {
+    pub fn new(stream: &'a mut T) -> StreamPacketBuffer<'a, T> {
         StreamPacketBuffer {
             stream,
             buffer: Vec::new(),
@@ -40,7 +40,7 @@ where
     fn read(&mut self) -> Result<u8> {
         while self.pos >= self.buffer.len() {
             let mut local_buffer = [0; 1];
-            try!(self.stream.read_exact(&mut local_buffer));
+            self.stream.read_exact(&mut local_buffer)?;
             self.buffer.push(local_buffer[0]);
         }
 
@@ -53,7 +53,7 @@ where
     fn get(&mut self, pos: usize) -> Result<u8> {
         while pos >= self.buffer.len() {
             let mut local_buffer = [0; 1];
-            try!(self.stream.read_exact(&mut local_buffer));
+            self.stream.read_exact(&mut local_buffer)?;
             self.buffer.push(local_buffer[0]);
         }
 
@@ -63,11 +63,11 @@ where
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
         while start + len > self.buffer.len() {
             let mut local_buffer = [0; 1];
-            try!(self.stream.read_exact(&mut local_buffer));
+            self.stream.read_exact(&mut local_buffer)?;
             self.buffer.push(local_buffer[0]);
         }
 
-        Ok(&self.buffer[start..start + len as usize])
+        Ok(&self.buffer[start..start + len])
     }
 
     fn write(&mut self, _: u8) -> Result<()> {
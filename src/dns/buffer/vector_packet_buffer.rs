@@ -1,5 +1,6 @@
-use std::io::Result;
 use std::collections::BTreeMap;
+use std::io::Result;
+use std::io::{Error, ErrorKind};
 
 use super::PacketBuffer;
 
@@ -32,18 +33,24 @@ impl PacketBuffer for VectorPacketBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        let res = self.buffer[self.pos];
+        let res = self.get(self.pos)?;
         self.pos += 1;
 
         Ok(res)
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        Ok(self.buffer[pos])
+        match self.buffer.get(pos) {
+            Some(&x) => Ok(x),
+            None => Err(Error::new(ErrorKind::InvalidInput, "End of buffer")),
+        }
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        Ok(&self.buffer[start..start + len as usize])
+        match self.buffer.get(start..start + len) {
+            Some(x) => Ok(x),
+            None => Err(Error::new(ErrorKind::InvalidInput, "End of buffer")),
+        }
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
@@ -54,7 +61,10 @@ impl PacketBuffer for VectorPacketBuffer {
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
-        self.buffer[pos] = val;
+        match self.buffer.get_mut(pos) {
+            Some(x) => *x = val,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "End of buffer")),
+        }
 
         Ok(())
     }
@@ -0,0 +1,358 @@
+//! A cache for resolved packets
+//!
+//! Entries are keyed by name, type and class, with names compared without
+//! regard to case. Answers tailored to a client subnet through EDNS Client
+//! Subnet are additionally keyed by the subnet they were scoped to, so that
+//! they're only handed to clients within it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use super::client_subnet::{response_scope, subnet_contains};
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+
+type CacheKey = (String, QueryType, u16);
+
+struct CacheEntry {
+    // Tells entries expiring at the same time apart in the expiry index.
+    id: u64,
+    subnet: Option<(IpAddr, u8)>,
+    packet: DnsPacket,
+    // The name servers that referrals led to on the way to the packet.
+    name_servers: Vec<String>,
+    stored: Instant,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    lists: HashMap<CacheKey, Vec<CacheEntry>>,
+    // Every entry by the time it expires, so that the next one to go can be
+    // found without scanning the whole cache.
+    expiry: BTreeMap<(Instant, u64), CacheKey>,
+    len: usize,
+    next_id: u64,
+}
+
+impl CacheEntries {
+    fn insert(&mut self, key: CacheKey, mut entry: CacheEntry) {
+        entry.id = self.next_id;
+        self.next_id += 1;
+
+        self.expiry.insert((entry.expires, entry.id), key.clone());
+        self.lists.entry(key).or_default().push(entry);
+        self.len += 1;
+    }
+
+    // Removes the entries of `key` matching `pred`.
+    fn remove_where<F>(&mut self, key: &CacheKey, pred: F)
+    where
+        F: Fn(&CacheEntry) -> bool,
+    {
+        let list = match self.lists.get_mut(key) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let expiry = &mut self.expiry;
+        let before = list.len();
+        list.retain(|entry| {
+            if !pred(entry) {
+                return true;
+            }
+            expiry.remove(&(entry.expires, entry.id));
+            false
+        });
+        self.len -= before - list.len();
+
+        if list.is_empty() {
+            self.lists.remove(key);
+        }
+    }
+
+    // Removes the entry that expires first, which is an expired one if there
+    // are any.
+    fn pop_first(&mut self) -> bool {
+        let ((expires, id), key) = match self.expiry.iter().next() {
+            Some((&x, key)) => (x, key.clone()),
+            None => return false,
+        };
+        self.remove_where(&key, |entry| entry.expires == expires && entry.id == id);
+
+        true
+    }
+}
+
+pub struct Cache {
+    capacity: usize,
+    entries: RwLock<CacheEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Cache {
+    /// Creates a cache holding at most `capacity` packets.
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            entries: RwLock::new(CacheEntries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of packets currently held, including expired ones that haven't
+    /// been evicted yet.
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of lookups that found a packet.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of lookups that didn't find a packet.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets removed to make room for new ones.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Looks up a packet for `client`, along with the name servers it was
+    /// stored with. Among the entries that apply to the client, the one scoped
+    /// to the most specific subnet wins. The TTLs of the returned records are
+    /// reduced by the time spent in the cache.
+    pub fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: u16,
+        client: IpAddr,
+    ) -> Option<(DnsPacket, Vec<String>)> {
+        let entries = self.entries.read().unwrap();
+        let now = Instant::now();
+
+        let entry = entries
+            .lists
+            .get(&key(qname, qtype, qclass))
+            .and_then(|list| {
+                list.iter()
+                    .filter(|entry| entry.expires > now)
+                    .filter(|entry| match entry.subnet {
+                        Some((net, prefix)) => subnet_contains(net, prefix, client),
+                        None => true,
+                    })
+                    .max_by_key(|entry| entry.subnet.map(|(_, prefix)| prefix))
+            });
+        let entry = match entry {
+            Some(x) => x,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut packet = entry.packet.clone();
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            if let Some(ttl) = rec.get_ttl() {
+                rec.set_ttl(ttl.saturating_sub(elapsed));
+            }
+        }
+
+        Some((packet, entry.name_servers.clone()))
+    }
+
+    /// Stores a packet for as long as its shortest TTL, along with the name
+    /// servers that referrals led to while resolving it. The ECS option of the
+    /// packet decides which clients it applies to. Packets without any records
+    /// carry no TTL and aren't cached.
+    pub fn store(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: u16,
+        packet: &DnsPacket,
+        name_servers: &[String],
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let ttl = match packet
+            .answers
+            .iter()
+            .chain(packet.authorities.iter())
+            .filter_map(DnsRecord::get_ttl)
+            .min()
+        {
+            Some(0) | None => return,
+            Some(x) => x,
+        };
+
+        let subnet = response_scope(packet.edns_options());
+        let now = Instant::now();
+        let entry = CacheEntry {
+            id: 0,
+            subnet,
+            packet: packet.clone(),
+            name_servers: name_servers.to_vec(),
+            stored: now,
+            expires: now + Duration::from_secs(u64::from(ttl)),
+        };
+
+        let key = key(qname, qtype, qclass);
+        let mut entries = self.entries.write().unwrap();
+        entries.remove_where(&key, |x| x.subnet == subnet || x.expires <= now);
+        entries.insert(key, entry);
+
+        // Expired entries expire first, so they're given up before any live
+        // ones that are closest to expiry.
+        let mut evicted = 0;
+        while entries.len > self.capacity && entries.pop_first() {
+            evicted += 1;
+        }
+        if evicted > 0 {
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        }
+    }
+}
+
+// Names are stored in lower case and without the trailing dot, so that any
+// spelling of a name finds the same entries.
+fn key(qname: &str, qtype: QueryType, qclass: u16) -> CacheKey {
+    (qname.trim_end_matches('.').to_ascii_lowercase(), qtype, qclass)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EdnsOption;
+
+    fn answer(addr: &str, scope: Option<(&str, u8)>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "cdn.example.com".into(),
+            addr: addr.parse().unwrap(),
+            ttl: 300,
+        });
+        if let Some((net, prefix)) = scope {
+            packet.resources.push(DnsRecord::OPT {
+                packet_len: 512,
+                flags: 0,
+                options: vec![EdnsOption::ECS {
+                    source_prefix: 24,
+                    scope_prefix: prefix,
+                    addr: net.parse().unwrap(),
+                }],
+            });
+        }
+        packet
+    }
+
+    #[test]
+    fn it_keys_tailored_answers_by_scope() {
+        let cache = Cache::new(16);
+        cache.store("cdn.example.com", QueryType::A, 1, &answer("192.0.2.1", None), &[]);
+        cache.store(
+            "cdn.example.com",
+            QueryType::A,
+            1,
+            &answer("192.0.2.2", Some(("203.0.114.0", 24))),
+            &[],
+        );
+        assert_eq!(cache.len(), 2);
+
+        let (inside, _) = cache
+            .lookup("cdn.example.com", QueryType::A, 1, "203.0.114.9".parse().unwrap())
+            .unwrap();
+        assert_eq!(inside.get_random_a(), Some("192.0.2.2".to_string()));
+
+        let (outside, _) = cache
+            .lookup("cdn.example.com", QueryType::A, 1, "198.51.100.9".parse().unwrap())
+            .unwrap();
+        assert_eq!(outside.get_random_a(), Some("192.0.2.1".to_string()));
+
+        assert!(
+            cache
+                .lookup("cdn.example.com", QueryType::AAAA, 1, "198.51.100.9".parse().unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn it_keys_entries_by_name_regardless_of_case_and_by_class() {
+        let cache = Cache::new(16);
+        cache.store("CDN.example.com.", QueryType::A, 1, &answer("192.0.2.1", None), &[]);
+
+        let client = "192.0.2.100".parse().unwrap();
+        assert!(cache.lookup("cdn.Example.COM", QueryType::A, 1, client).is_some());
+        assert!(cache.lookup("cdn.example.com.", QueryType::A, 1, client).is_some());
+        assert!(cache.lookup("cdn.example.com", QueryType::A, 3, client).is_none());
+    }
+
+    #[test]
+    fn it_keeps_the_name_servers_along_with_packets() {
+        let cache = Cache::new(16);
+        let name_servers = vec!["a.gtld-servers.net".to_string(), "ns1.example.com".to_string()];
+        cache.store("cdn.example.com", QueryType::A, 1, &answer("192.0.2.1", None), &name_servers);
+
+        let client = "192.0.2.100".parse().unwrap();
+        let (_, cached) = cache.lookup("cdn.example.com", QueryType::A, 1, client).unwrap();
+        assert_eq!(cached, name_servers);
+    }
+
+    #[test]
+    fn it_respects_the_capacity() {
+        let cache = Cache::new(1);
+        cache.store("a.example.com", QueryType::A, 1, &answer("192.0.2.1", None), &[]);
+        cache.store("b.example.com", QueryType::A, 1, &answer("192.0.2.2", None), &[]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.evictions(), 1);
+
+        let client = "192.0.2.100".parse().unwrap();
+        assert!(cache.lookup("a.example.com", QueryType::A, 1, client).is_none());
+        assert!(cache.lookup("b.example.com", QueryType::A, 1, client).is_some());
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    }
+
+    #[test]
+    fn it_evicts_the_entries_closest_to_expiry() {
+        let cache = Cache::new(2);
+        let mut short = answer("192.0.2.1", None);
+        short.answers[0].set_ttl(60);
+        cache.store("a.example.com", QueryType::A, 1, &answer("192.0.2.1", None), &[]);
+        cache.store("b.example.com", QueryType::A, 1, &short, &[]);
+        cache.store("c.example.com", QueryType::A, 1, &answer("192.0.2.3", None), &[]);
+        cache.store("c.example.com", QueryType::A, 1, &answer("192.0.2.4", None), &[]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evictions(), 1);
+
+        let client = "192.0.2.100".parse().unwrap();
+        assert!(cache.lookup("a.example.com", QueryType::A, 1, client).is_some());
+        assert!(cache.lookup("b.example.com", QueryType::A, 1, client).is_none());
+        let (c, _) = cache.lookup("c.example.com", QueryType::A, 1, client).unwrap();
+        assert_eq!(c.get_random_a(), Some("192.0.2.4".to_string()));
+    }
+}
@@ -0,0 +1,212 @@
+//! EDNS Client Subnet (RFC 7871) helpers
+//!
+//! Authoritative servers behind CDNs tailor their answers to the network a
+//! query came from. Rather than revealing the full client address, only its
+//! first few bits are sent upstream, and the answer states how many of them it
+//! actually depended on (the scope prefix).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::EdnsOption;
+
+/// How client subnets are passed on to upstream servers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClientSubnetConfig {
+    /// Prefix length IPv4 client addresses are truncated to.
+    pub ipv4_prefix: u8,
+    /// Prefix length IPv6 client addresses are truncated to.
+    pub ipv6_prefix: u8,
+}
+
+impl Default for ClientSubnetConfig {
+    // The defaults recommended by RFC 7871 for the sake of client privacy.
+    fn default() -> Self {
+        ClientSubnetConfig {
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+impl ClientSubnetConfig {
+    /// The ECS option to send upstream on behalf of `client`. If the client's
+    /// query carried an ECS option of its own, that subnet is used instead,
+    /// but never with a longer prefix than configured.
+    ///
+    /// Addresses that aren't globally routable tell the authority nothing
+    /// useful, so no option is sent for them.
+    pub fn option_for(&self, client: IpAddr, query_options: &[EdnsOption]) -> Option<EdnsOption> {
+        let (addr, prefix) = query_options
+            .iter()
+            .filter_map(|option| match *option {
+                EdnsOption::ECS {
+                    source_prefix,
+                    addr,
+                    ..
+                } => Some((addr, source_prefix)),
+                _ => None,
+            })
+            .next()
+            .unwrap_or((client, 128));
+
+        if !is_global(addr) {
+            return None;
+        }
+
+        let max_prefix = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        let source_prefix = prefix.min(max_prefix);
+
+        Some(EdnsOption::ECS {
+            source_prefix,
+            scope_prefix: 0,
+            addr: truncate(addr, source_prefix),
+        })
+    }
+}
+
+/// The subnet a response applies to, based on the ECS option it carries. A
+/// scope of zero means that the answer is the same for every client, and so
+/// does the absence of the option.
+pub fn response_scope(options: &[EdnsOption]) -> Option<(IpAddr, u8)> {
+    options
+        .iter()
+        .filter_map(|option| match *option {
+            EdnsOption::ECS {
+                source_prefix,
+                scope_prefix,
+                addr,
+            } if scope_prefix > 0 => {
+                // The scope can't be more specific than what we revealed.
+                let prefix = scope_prefix.min(source_prefix);
+                Some((truncate(addr, prefix), prefix))
+            }
+            _ => None,
+        })
+        .next()
+}
+
+/// Clears every bit of `addr` after the first `prefix` bits.
+pub fn truncate(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u32 << (32 - u32::from(prefix.min(32)))
+            };
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u128 << (128 - u32::from(prefix.min(128)))
+            };
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        }
+    }
+}
+
+/// Whether `addr` falls within the subnet `net`/`prefix`.
+pub fn subnet_contains(net: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    match (net, addr) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+            truncate(addr, prefix) == net
+        }
+        _ => false,
+    }
+}
+
+fn is_global(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => {
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || (first & 0xFE00) == 0xFC00
+                || (first & 0xFFC0) == 0xFE80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_truncates_client_addresses() {
+        let config = ClientSubnetConfig::default();
+
+        let option = config.option_for("203.0.114.77".parse().unwrap(), &[]);
+        assert_eq!(
+            option,
+            Some(EdnsOption::ECS {
+                source_prefix: 24,
+                scope_prefix: 0,
+                addr: "203.0.114.0".parse().unwrap(),
+            })
+        );
+
+        let option = config.option_for("2001:db9:1:2ff:3:4:5:6".parse().unwrap(), &[]);
+        assert_eq!(
+            option,
+            Some(EdnsOption::ECS {
+                source_prefix: 56,
+                scope_prefix: 0,
+                addr: "2001:db9:1:200::".parse().unwrap(),
+            })
+        );
+
+        assert_eq!(config.option_for("10.1.2.3".parse().unwrap(), &[]), None);
+        assert_eq!(config.option_for("::1".parse().unwrap(), &[]), None);
+    }
+
+    #[test]
+    fn it_prefers_the_clients_own_subnet() {
+        let config = ClientSubnetConfig::default();
+        let query = vec![EdnsOption::ECS {
+            source_prefix: 16,
+            scope_prefix: 0,
+            addr: "198.18.0.0".parse().unwrap(),
+        }];
+
+        assert_eq!(
+            config.option_for("127.0.0.1".parse().unwrap(), &query),
+            Some(EdnsOption::ECS {
+                source_prefix: 16,
+                scope_prefix: 0,
+                addr: "198.18.0.0".parse().unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_limits_the_scope_to_the_source_prefix() {
+        let response = vec![EdnsOption::ECS {
+            source_prefix: 24,
+            scope_prefix: 32,
+            addr: "203.0.114.0".parse().unwrap(),
+        }];
+        assert_eq!(
+            response_scope(&response),
+            Some(("203.0.114.0".parse().unwrap(), 24))
+        );
+        assert!(subnet_contains(
+            "203.0.114.0".parse().unwrap(),
+            24,
+            "203.0.114.200".parse().unwrap()
+        ));
+        assert!(!subnet_contains(
+            "203.0.114.0".parse().unwrap(),
+            24,
+            "203.0.115.1".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,341 @@
+//! DNS Cookies (RFC 7873) for both sides of an exchange
+//!
+//! Server cookies use the layout from RFC 9018: a version byte, three reserved
+//! bytes, a timestamp and an eight byte hash. The hash is a HMAC-SHA256 over the
+//! client cookie, the preceding fields and the client address, truncated to
+//! eight bytes, so only servers sharing the secret can produce valid cookies.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rand::random;
+use sha2::Sha256;
+
+use crate::EdnsOption;
+
+const COOKIE_VERSION: u8 = 1;
+
+// Server cookies are accepted for an hour, and a little clock skew between
+// servers sharing a secret is tolerated.
+const COOKIE_LIFETIME: u32 = 3600;
+const COOKIE_CLOCK_SKEW: u32 = 300;
+
+// The number of upstream servers whose cookies are remembered.
+const COOKIE_JAR_CAPACITY: usize = 4096;
+
+/// The state of the cookie a client sent along with its query.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CookieStatus {
+    /// The query didn't contain a COOKIE option.
+    Missing,
+    /// The COOKIE option had an invalid length.
+    Malformed,
+    /// Only a client cookie was present, or a server cookie that we didn't
+    /// issue to this client or that has expired.
+    Unverified,
+    /// The server cookie was issued by us to this client.
+    Valid,
+}
+
+/// The secret used to issue and validate server cookies.
+pub struct ServerCookieSecret {
+    secret: [u8; 32],
+}
+
+impl ServerCookieSecret {
+    pub fn new(secret: [u8; 32]) -> ServerCookieSecret {
+        ServerCookieSecret { secret }
+    }
+
+    pub fn random() -> ServerCookieSecret {
+        ServerCookieSecret::new(random())
+    }
+
+    /// Checks the COOKIE option of a query received from `client_ip`.
+    pub fn check(&self, options: &[EdnsOption], client_ip: IpAddr) -> CookieStatus {
+        for option in options {
+            match *option {
+                EdnsOption::COOKIE {
+                    ref client,
+                    ref server,
+                } => {
+                    if self.verify(client, server, client_ip, now()) {
+                        return CookieStatus::Valid;
+                    }
+                    return CookieStatus::Unverified;
+                }
+                EdnsOption::UNKNOWN { code: 10, .. } => return CookieStatus::Malformed,
+                _ => {}
+            }
+        }
+
+        CookieStatus::Missing
+    }
+
+    /// Builds the COOKIE option answering the client cookie in a query, with a
+    /// freshly issued server cookie.
+    pub fn respond(&self, options: &[EdnsOption], client_ip: IpAddr) -> Option<EdnsOption> {
+        options
+            .iter()
+            .filter_map(|option| match *option {
+                EdnsOption::COOKIE { ref client, .. } => Some(EdnsOption::COOKIE {
+                    client: *client,
+                    server: self.generate(client, client_ip, now()),
+                }),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn generate(&self, client_cookie: &[u8; 8], client_ip: IpAddr, timestamp: u32) -> Vec<u8> {
+        let mut cookie = vec![COOKIE_VERSION, 0, 0, 0];
+        cookie.extend_from_slice(&[
+            (timestamp >> 24) as u8,
+            (timestamp >> 16) as u8,
+            (timestamp >> 8) as u8,
+            timestamp as u8,
+        ]);
+
+        let hash = self.hash(client_cookie, &cookie, client_ip);
+        cookie.extend_from_slice(&hash[..8]);
+
+        cookie
+    }
+
+    fn verify(
+        &self,
+        client_cookie: &[u8; 8],
+        server_cookie: &[u8],
+        client_ip: IpAddr,
+        now: u32,
+    ) -> bool {
+        if server_cookie.len() != 16 || server_cookie[0] != COOKIE_VERSION {
+            return false;
+        }
+
+        let timestamp = (u32::from(server_cookie[4]) << 24)
+            | (u32::from(server_cookie[5]) << 16)
+            | (u32::from(server_cookie[6]) << 8)
+            | u32::from(server_cookie[7]);
+
+        // Timestamps use serial number arithmetic, so compare the differences.
+        if now.wrapping_sub(timestamp) > COOKIE_LIFETIME
+            && timestamp.wrapping_sub(now) > COOKIE_CLOCK_SKEW
+        {
+            return false;
+        }
+
+        let mac = self.mac(client_cookie, &server_cookie[..8], client_ip);
+        mac.verify_truncated_left(&server_cookie[8..]).is_ok()
+    }
+
+    fn hash(&self, client_cookie: &[u8; 8], header: &[u8], client_ip: IpAddr) -> Vec<u8> {
+        let mac = self.mac(client_cookie, header, client_ip);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn mac(&self, client_cookie: &[u8; 8], header: &[u8], client_ip: IpAddr) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(client_cookie);
+        mac.update(header);
+        match client_ip {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+
+        mac
+    }
+}
+
+// The client cookie we picked for a server, the last server cookie it sent,
+// and when we last sent them.
+struct CookiePair {
+    client: [u8; 8],
+    server: Vec<u8>,
+    used: Instant,
+}
+
+/// The cookies we use towards upstream servers, remembered per server.
+pub struct CookieJar {
+    capacity: usize,
+    cookies: Mutex<HashMap<IpAddr, CookiePair>>,
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        CookieJar::with_capacity(COOKIE_JAR_CAPACITY)
+    }
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Creates a jar remembering the cookies of at most `capacity` servers.
+    /// Once it's full, pairs that haven't been used for as long as a server
+    /// cookie stays valid are dropped, and if that isn't enough, the least
+    /// recently used one.
+    pub fn with_capacity(capacity: usize) -> CookieJar {
+        CookieJar {
+            capacity,
+            cookies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The number of servers whose cookies are remembered.
+    pub fn len(&self) -> usize {
+        self.cookies.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The COOKIE option to send to `server`. A random client cookie is picked
+    /// the first time we talk to a server, and the last server cookie it gave
+    /// us is sent along with it.
+    pub fn option_for(&self, server: IpAddr) -> EdnsOption {
+        let mut cookies = self.cookies.lock().unwrap();
+        let now = Instant::now();
+        if !cookies.contains_key(&server) && cookies.len() >= self.capacity {
+            make_room(&mut cookies, self.capacity, now);
+        }
+
+        let pair = cookies.entry(server).or_insert_with(|| CookiePair {
+            client: random(),
+            server: Vec::new(),
+            used: now,
+        });
+        pair.used = now;
+
+        EdnsOption::COOKIE {
+            client: pair.client,
+            server: pair.server.clone(),
+        }
+    }
+
+    /// Remembers the server cookie from a response. Returns `false` if the
+    /// response echoed a client cookie that isn't ours, which means it
+    /// wasn't sent in reply to our query.
+    pub fn update(&self, server: IpAddr, options: &[EdnsOption]) -> bool {
+        let mut cookies = self.cookies.lock().unwrap();
+        for option in options {
+            if let EdnsOption::COOKIE {
+                ref client,
+                server: ref server_cookie,
+            } = *option
+            {
+                return match cookies.get_mut(&server) {
+                    Some(pair) if pair.client == *client => {
+                        pair.server = server_cookie.clone();
+                        true
+                    }
+                    _ => false,
+                };
+            }
+        }
+
+        true
+    }
+}
+
+// Drops the pairs whose server cookies would have expired by now, or the least
+// recently used one if all of them are still fresh.
+fn make_room(cookies: &mut HashMap<IpAddr, CookiePair>, capacity: usize, now: Instant) {
+    let lifetime = Duration::from_secs(u64::from(COOKIE_LIFETIME));
+    cookies.retain(|_, pair| now.duration_since(pair.used) < lifetime);
+
+    while cookies.len() >= capacity {
+        let oldest = match cookies.iter().min_by_key(|&(_, pair)| pair.used) {
+            Some((&server, _)) => server,
+            None => return,
+        };
+        cookies.remove(&oldest);
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn it_validates_issued_cookies() {
+        let secret = ServerCookieSecret::new([7; 32]);
+        let client = [1, 2, 3, 4, 5, 6, 7, 8];
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        let cookie = secret.generate(&client, ip, 1000);
+        assert_eq!(cookie.len(), 16);
+        assert!(secret.verify(&client, &cookie, ip, 1000 + COOKIE_LIFETIME));
+        assert!(secret.verify(&client, &cookie, ip, 1000 - COOKIE_CLOCK_SKEW));
+        assert!(!secret.verify(&client, &cookie, ip, 1001 + COOKIE_LIFETIME));
+        assert!(!secret.verify(&client, &cookie, other_ip, 1000));
+        assert!(!secret.verify(&[0; 8], &cookie, ip, 1000));
+        assert!(!ServerCookieSecret::new([8; 32]).verify(&client, &cookie, ip, 1000));
+    }
+
+    #[test]
+    fn it_rejects_foreign_client_cookies() {
+        let jar = CookieJar::new();
+        let server = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+        let client = match jar.option_for(server) {
+            EdnsOption::COOKIE { client, .. } => client,
+            _ => panic!(),
+        };
+
+        let forged = vec![EdnsOption::COOKIE {
+            client: [0xFF; 8],
+            server: vec![0; 16],
+        }];
+        assert!(!jar.update(server, &forged));
+
+        let genuine = vec![EdnsOption::COOKIE {
+            client,
+            server: vec![1; 16],
+        }];
+        assert!(jar.update(server, &genuine));
+        assert_eq!(
+            jar.option_for(server),
+            EdnsOption::COOKIE {
+                client,
+                server: vec![1; 16],
+            }
+        );
+    }
+    #[test]
+    fn it_forgets_the_least_recently_used_servers() {
+        let jar = CookieJar::with_capacity(2);
+        let a = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2));
+        let c = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 3));
+
+        let first = jar.option_for(a);
+        jar.option_for(b);
+        assert_eq!(jar.option_for(a), first);
+        jar.option_for(c);
+        assert_eq!(jar.len(), 2);
+        assert_eq!(jar.option_for(a), first);
+
+        // Long after their server cookies would have expired, all of them go.
+        let mut cookies = jar.cookies.lock().unwrap();
+        let later = Instant::now() + Duration::from_secs(u64::from(COOKIE_LIFETIME) + 1);
+        make_room(&mut cookies, 2, later);
+        assert!(cookies.is_empty());
+    }
+}
@@ -1,8 +1,8 @@
 use std::io::Result;
 
-use PacketBuffer;
-use BytePacketBuffer;
-use ResultCode;
+use crate::PacketBuffer;
+use crate::Opcode;
+use crate::ResultCode;
 
 #[derive(Clone, Debug)]
 pub struct DnsHeader {
@@ -11,7 +11,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,    // 1 bit
     pub truncated_message: bool,    // 1 bit
     pub authoritative_answer: bool, // 1 bit
-    pub opcode: u8,                 // 4 bits
+    pub opcode: Opcode,             // 4 bits
     pub response: bool,             // 1 bit
 
     pub rescode: ResultCode,       // 4 bits
@@ -34,7 +34,7 @@ impl Default for DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResultCode::NOERROR,
@@ -56,53 +56,79 @@ impl DnsHeader {
         DnsHeader::default()
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        self.id = try!(buffer.read_u16());
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        self.id = buffer.read_u16()?;
 
-        let flags = try!(buffer.read_u16());
+        let flags = buffer.read_u16()?;
         let a = (flags >> 8) as u8;
         let b = (flags & 0xFF) as u8;
         self.recursion_desired = (a & 1) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
-        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.rescode = ResultCode::from_num(u16::from(b & 0x0F));
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
         self.recursion_available = (b & (1 << 7)) > 0;
 
-        self.questions = try!(buffer.read_u16());
-        self.answers = try!(buffer.read_u16());
-        self.authoritative_entries = try!(buffer.read_u16());
-        self.resource_entries = try!(buffer.read_u16());
+        self.questions = buffer.read_u16()?;
+        self.answers = buffer.read_u16()?;
+        self.authoritative_entries = buffer.read_u16()?;
+        self.resource_entries = buffer.read_u16()?;
 
         // Return the constant header size
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        try!(buffer.write_u16(self.id));
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u16(self.id)?;
 
-        try!(buffer.write_u8(
+        buffer.write_u8(
             (self.recursion_desired as u8) | ((self.truncated_message as u8) << 1)
-                | ((self.authoritative_answer as u8) << 2) | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8
-        ));
+                | ((self.authoritative_answer as u8) << 2) | (self.opcode.to_num() << 3)
+                | ((self.response as u8) << 7)
+        )?;
 
-        try!(buffer.write_u8(
-            (self.rescode as u8) | ((self.checking_disabled as u8) << 4)
+        buffer.write_u8(
+            (self.rescode.to_num() & 0x0F) as u8 | ((self.checking_disabled as u8) << 4)
                 | ((self.authed_data as u8) << 5) | ((self.z as u8) << 6)
                 | ((self.recursion_available as u8) << 7)
-        ));
+        )?;
 
-        try!(buffer.write_u16(self.questions));
-        try!(buffer.write_u16(self.answers));
-        try!(buffer.write_u16(self.authoritative_entries));
-        try!(buffer.write_u16(self.resource_entries));
+        buffer.write_u16(self.questions)?;
+        buffer.write_u16(self.answers)?;
+        buffer.write_u16(self.authoritative_entries)?;
+        buffer.write_u16(self.resource_entries)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::BytePacketBuffer;
+
+    use super::*;
+
+    #[test]
+    fn it_round_trips_the_opcode() {
+        for num in 0..16 {
+            let mut header = DnsHeader::new();
+            header.opcode = Opcode::from_num(num);
+
+            let mut buffer = BytePacketBuffer::new();
+            header.write(&mut buffer).unwrap();
+            buffer.seek(0).unwrap();
+
+            let mut parsed = DnsHeader::new();
+            parsed.read(&mut buffer).unwrap();
+            assert_eq!(parsed.opcode, header.opcode);
+            assert_eq!(parsed.opcode.to_num(), num);
+        }
+        assert_eq!(Opcode::from_num(5), Opcode::UPDATE);
+        assert_eq!(Opcode::from_num(3), Opcode::UNKNOWN(3));
+    }
+}
@@ -1,13 +1,17 @@
+use std::fmt;
 use std::io::Result;
 
 use rand::random;
 
 // use PacketBuffer;
-use BytePacketBuffer;
-use QueryType;
-use DnsRecord;
-use DnsHeader;
-use DnsQuestion;
+use crate::PacketBuffer;
+use crate::QueryType;
+use crate::DnsRecord;
+use crate::DnsHeader;
+use crate::DnsQuestion;
+use crate::EdnsOption;
+use crate::ExtendedErrorCode;
+use crate::ResultCode;
 
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
@@ -34,56 +38,145 @@ impl DnsPacket {
         DnsPacket::default()
     }
 
-    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<DnsPacket> {
         let mut result = DnsPacket::new();
-        try!(result.header.read(buffer));
+        result.header.read(buffer)?;
 
         for _ in 0..result.header.questions {
             let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
-            try!(question.read(buffer));
+            question.read(buffer)?;
             result.questions.push(question);
         }
 
         for _ in 0..result.header.answers {
-            let rec = try!(DnsRecord::read(buffer));
+            let rec = DnsRecord::read(buffer)?;
             result.answers.push(rec);
         }
         for _ in 0..result.header.authoritative_entries {
-            let rec = try!(DnsRecord::read(buffer));
+            let rec = DnsRecord::read(buffer)?;
             result.authorities.push(rec);
         }
         for _ in 0..result.header.resource_entries {
-            let rec = try!(DnsRecord::read(buffer));
+            let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
 
         Ok(result)
     }
 
-    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
         self.header.authoritative_entries = self.authorities.len() as u16;
         self.header.resource_entries = self.resources.len() as u16;
 
-        try!(self.header.write(buffer));
+        self.header.write(buffer)?;
 
         for question in &self.questions {
-            try!(question.write(buffer));
+            question.write(buffer)?;
         }
         for rec in &self.answers {
-            try!(rec.write(buffer));
+            rec.write(buffer)?;
         }
         for rec in &self.authorities {
-            try!(rec.write(buffer));
+            rec.write(buffer)?;
         }
         for rec in &self.resources {
-            try!(rec.write(buffer));
+            rec.write(buffer)?;
         }
 
         Ok(())
     }
 
+    /// The full result code of the message. The header only has room for the
+    /// lower four bits, so if an OPT record is present its upper eight bits
+    /// are combined with the header to form the 12-bit extended code.
+    pub fn rescode(&self) -> ResultCode {
+        let upper = match self.get_opt() {
+            Some(&DnsRecord::OPT { flags, .. }) => (flags >> 24) as u16,
+            _ => 0,
+        };
+
+        // Only the low four bits fit into the header, whatever it was set to.
+        ResultCode::from_num((upper << 4) | (self.header.rescode.to_num() & 0xF))
+    }
+
+    /// Sets the result code, splitting it between the header and an OPT
+    /// record. Extended codes need an OPT record, so one is added if missing.
+    /// Only the low 12 bits of the code can be sent.
+    pub fn set_rescode(&mut self, rescode: ResultCode) {
+        let num = rescode.to_num() & 0xFFF;
+        self.header.rescode = ResultCode::from_num(num & 0x0F);
+
+        if !rescode.is_extended() && self.get_opt().is_none() {
+            return;
+        }
+
+        if let DnsRecord::OPT { ref mut flags, .. } = *self.get_or_add_opt() {
+            *flags = (*flags & 0x00FF_FFFF) | (u32::from(num >> 4) << 24);
+        }
+    }
+
+    /// Returns the OPT pseudo-record from the additional section, if any.
+    pub fn get_opt(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|rec| matches!(**rec, DnsRecord::OPT { .. }))
+    }
+
+    /// Returns the OPT pseudo-record, adding an empty one advertising our
+    /// 512 byte buffer if the packet doesn't have one yet.
+    pub fn get_or_add_opt(&mut self) -> &mut DnsRecord {
+        let idx = match self.resources
+            .iter()
+            .position(|rec| matches!(*rec, DnsRecord::OPT { .. }))
+        {
+            Some(x) => x,
+            None => {
+                self.resources.push(DnsRecord::OPT {
+                    packet_len: 512,
+                    flags: 0,
+                    options: Vec::new(),
+                });
+                self.resources.len() - 1
+            }
+        };
+
+        &mut self.resources[idx]
+    }
+
+    /// The EDNS options of the packet, empty if there's no OPT record.
+    pub fn edns_options(&self) -> &[EdnsOption] {
+        match self.get_opt() {
+            Some(DnsRecord::OPT { options, .. }) => options,
+            _ => &[],
+        }
+    }
+
+    /// Attaches an Extended DNS Error (RFC 8914) explaining the result code.
+    pub fn add_extended_error(&mut self, info_code: ExtendedErrorCode, extra_text: &str) {
+        if let DnsRecord::OPT { ref mut options, .. } = *self.get_or_add_opt() {
+            options.push(EdnsOption::EDE {
+                info_code,
+                extra_text: extra_text.to_string(),
+            });
+        }
+    }
+
+    /// Every Extended DNS Error in the packet, along with its extra text.
+    pub fn extended_errors(&self) -> Vec<(ExtendedErrorCode, &str)> {
+        self.edns_options()
+            .iter()
+            .filter_map(|option| match *option {
+                EdnsOption::EDE {
+                    info_code,
+                    ref extra_text,
+                } => Some((info_code, extra_text.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
     // It's useful to be able to pick a random A record from a packet. When we
     // get multiple IP's for a single name, it doesn't matter which one we
     // choose, so in those cases we can now pick one at random.
@@ -181,3 +274,230 @@ impl DnsPacket {
         None
     } // End of get_unresolved_ns
 }
+
+impl fmt::Display for DnsPacket {
+    /// Formats the packet the way dig prints responses: the header first,
+    /// then the EDNS options, and then each non-empty section.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            header.opcode,
+            self.rescode(),
+            header.id
+        )?;
+
+        let flags = [
+            (header.response, "qr"),
+            (header.authoritative_answer, "aa"),
+            (header.truncated_message, "tc"),
+            (header.recursion_desired, "rd"),
+            (header.recursion_available, "ra"),
+            (header.authed_data, "ad"),
+            (header.checking_disabled, "cd"),
+        ];
+        write!(f, ";; flags:")?;
+        for &(set, name) in &flags {
+            if set {
+                write!(f, " {}", name)?;
+            }
+        }
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        )?;
+
+        if let Some(opt) = self.get_opt() {
+            writeln!(f, "\n;; OPT PSEUDOSECTION:")?;
+            writeln!(f, "{}", opt)?;
+            for option in self.edns_options() {
+                writeln!(f, "; {}", option)?;
+            }
+        }
+
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{}", question)?;
+            }
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ];
+        for &(name, records) in &sections {
+            // The OPT record already got a section of its own.
+            let mut records = records
+                .iter()
+                .filter(|x| !matches!(**x, DnsRecord::OPT { .. }))
+                .peekable();
+            if records.peek().is_none() {
+                continue;
+            }
+
+            writeln!(f, "\n;; {} SECTION:", name)?;
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BytePacketBuffer;
+
+    use super::*;
+
+    #[test]
+    fn it_assembles_the_extended_rescode() {
+        let mut packet = DnsPacket::new();
+        packet.set_rescode(ResultCode::BADCOOKIE);
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.rescode(), ResultCode::BADCOOKIE);
+        assert_eq!(parsed.header.rescode, ResultCode::YXRRSET);
+    }
+
+    #[test]
+    fn it_round_trips_badvers() {
+        let mut packet = DnsPacket::new();
+        packet.set_rescode(ResultCode::BADVERS);
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.rescode(), ResultCode::BADVERS);
+        assert_eq!(parsed.header.rescode, ResultCode::NOERROR);
+    }
+
+    #[test]
+    fn it_round_trips_the_largest_unassigned_rescode() {
+        let mut packet = DnsPacket::new();
+        packet.set_rescode(ResultCode::UNKNOWN(4095));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.rescode(), ResultCode::UNKNOWN(4095));
+    }
+
+    #[test]
+    fn it_truncates_rescodes_above_4095() {
+        let mut packet = DnsPacket::new();
+        packet.set_rescode(ResultCode::UNKNOWN(4096 + 3841));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.rescode(), ResultCode::UNKNOWN(3841));
+    }
+
+    #[test]
+    fn it_ignores_header_rescode_bits_beyond_four() {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::UNKNOWN(4097);
+        assert_eq!(packet.rescode(), ResultCode::FORMERR);
+    }
+
+    #[test]
+    fn it_preserves_unassigned_rescodes() {
+        let mut packet = DnsPacket::new();
+        packet.set_rescode(ResultCode::UNKNOWN(3841));
+        assert_eq!(packet.rescode(), ResultCode::UNKNOWN(3841));
+    }
+
+    #[test]
+    fn it_round_trips_extended_errors() {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet.add_extended_error(ExtendedErrorCode::DnssecBogus, "");
+        packet.add_extended_error(ExtendedErrorCode::NoReachableAuthority, "timed out");
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.rescode(), ResultCode::SERVFAIL);
+        assert_eq!(
+            parsed.extended_errors(),
+            vec![
+                (ExtendedErrorCode::DnssecBogus, ""),
+                (ExtendedErrorCode::NoReachableAuthority, "timed out"),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_round_trips_client_subnets() {
+        let option = EdnsOption::ECS {
+            source_prefix: 20,
+            scope_prefix: 16,
+            addr: "198.51.96.0".parse().unwrap(),
+        };
+
+        let mut packet = DnsPacket::new();
+        if let DnsRecord::OPT {
+            ref mut options, ..
+        } = *packet.get_or_add_opt()
+        {
+            options.push(option.clone());
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        // Header, root name, OPT fields and a 3 byte address after the ECS header
+        assert_eq!(buffer.pos(), 12 + 1 + 10 + 4 + 4 + 3);
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.edns_options(), &[option]);
+    }
+
+    #[test]
+    fn it_formats_packets_like_dig() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4242;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".into(), QueryType::AAAA));
+        packet.add_extended_error(ExtendedErrorCode::NoReachableAuthority, "timed out");
+
+        let expected = "\
+;; ->>HEADER<<- opcode: QUERY, status: SERVFAIL, id: 4242
+;; flags: qr rd ra; QUERY: 1, ANSWER: 0, AUTHORITY: 0, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags:; udp: 512
+; EDE: 22 (No Reachable Authority): (timed out)
+
+;; QUESTION SECTION:
+;example.com.\t\tIN\tAAAA
+";
+        assert_eq!(packet.to_string(), expected);
+    }
+}
@@ -1,35 +1,55 @@
+use std::fmt;
 use std::io::Result;
 
-use PacketBuffer;
-use BytePacketBuffer;
-use QueryType;
+use crate::PacketBuffer;
+use crate::QueryType;
+use crate::dns::dns_record::{class_name, presentation_name};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub qclass: u16,
 }
 
 impl DnsQuestion {
+    /// Creates a question for the IN class.
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+        DnsQuestion {
+            name,
+            qtype,
+            qclass: 1,
+        }
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        try!(buffer.read_qname(&mut self.name));
-        self.qtype = QueryType::from_num(try!(buffer.read_u16())); // qtype
-        let _ = try!(buffer.read_u16()); // class
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        buffer.read_qname(&mut self.name)?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
+        self.qclass = buffer.read_u16()?; // class
 
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        try!(buffer.write_qname(&self.name));
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_qname(&self.name)?;
 
         let typenum = self.qtype.to_num();
-        try!(buffer.write_u16(typenum));
-        try!(buffer.write_u16(1));
+        buffer.write_u16(typenum)?;
+        buffer.write_u16(self.qclass)?;
 
         Ok(())
     }
 }
+
+impl fmt::Display for DnsQuestion {
+    // Questions are shown like records without a TTL or data, commented out.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            presentation_name(&self.name),
+            class_name(self.qclass),
+            self.qtype
+        )
+    }
+}
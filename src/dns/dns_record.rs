@@ -1,16 +1,20 @@
+use std::fmt;
+use std::str::FromStr;
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
-use PacketBuffer;
-use BytePacketBuffer;
-use QueryType;
+use crate::PacketBuffer;
+use crate::EdnsOption;
+use crate::QueryType;
+use crate::dns::edns_option::hex;
+use crate::dns::zone::parse_record;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -28,6 +32,22 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
@@ -39,24 +59,106 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        options: Vec<EdnsOption>,
+    }, // 41
 }
 
 impl DnsRecord {
-    pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
+    /// The name the record belongs to. The OPT pseudo-record always belongs
+    /// to the root.
+    pub fn get_domain(&self) -> &str {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    /// Moves the record to another name. The OPT pseudo-record stays at the
+    /// root.
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut domain, .. }
+            | DnsRecord::A { ref mut domain, .. }
+            | DnsRecord::NS { ref mut domain, .. }
+            | DnsRecord::CNAME { ref mut domain, .. }
+            | DnsRecord::SOA { ref mut domain, .. }
+            | DnsRecord::PTR { ref mut domain, .. }
+            | DnsRecord::MX { ref mut domain, .. }
+            | DnsRecord::AAAA { ref mut domain, .. } => *domain = new_domain.to_string(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn get_querytype(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    /// The TTL of the record, or `None` for the OPT pseudo-record, which uses
+    /// the field for flags instead.
+    pub fn get_ttl(&self) -> Option<u32> {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => Some(ttl),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::PTR { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<DnsRecord> {
         let mut domain = String::new();
-        try!(buffer.read_qname(&mut domain));
+        buffer.read_qname(&mut domain)?;
 
-        let qtype_num = try!(buffer.read_u16());
+        let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = try!(buffer.read_u16());
-        let ttl = try!(buffer.read_u32());
-        let data_len = try!(buffer.read_u16());
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+        let data_start = buffer.pos();
 
-        match qtype {
+        let record = match qtype {
             // Handle each record type separately, starting with the A record
             // type which remains the same as before.
             QueryType::A => {
-                let raw_addr = try!(buffer.read_u32());
+                let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
@@ -64,16 +166,16 @@ impl DnsRecord {
                     (raw_addr & 0xFF) as u8,
                 );
 
-                Ok(DnsRecord::A { domain, addr, ttl })
+                DnsRecord::A { domain, addr, ttl }
             }
 
             // The AAAA record type follows the same logic, but with more numbers to keep
             // track off.
             QueryType::AAAA => {
-                let raw_addr1 = try!(buffer.read_u32());
-                let raw_addr2 = try!(buffer.read_u32());
-                let raw_addr3 = try!(buffer.read_u32());
-                let raw_addr4 = try!(buffer.read_u32());
+                let raw_addr1 = buffer.read_u32()?;
+                let raw_addr2 = buffer.read_u32()?;
+                let raw_addr3 = buffer.read_u32()?;
+                let raw_addr4 = buffer.read_u32()?;
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
                     (raw_addr1 & 0xFFFF) as u16,
@@ -85,53 +187,117 @@ impl DnsRecord {
                     (raw_addr4 & 0xFFFF) as u16,
                 );
 
-                Ok(DnsRecord::AAAA { domain, addr, ttl })
+                DnsRecord::AAAA { domain, addr, ttl }
             }
 
             // NS and CNAME both have the same structure.
             QueryType::NS => {
                 let mut host = String::new();
-                try!(buffer.read_qname(&mut host));
+                buffer.read_qname(&mut host)?;
 
-                Ok(DnsRecord::NS { domain, host, ttl })
+                DnsRecord::NS { domain, host, ttl }
             }
 
             QueryType::CNAME => {
                 let mut host = String::new();
-                try!(buffer.read_qname(&mut host));
+                buffer.read_qname(&mut host)?;
+
+                DnsRecord::CNAME { domain, host, ttl }
+            }
+
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                DnsRecord::PTR { domain, host, ttl }
+            }
+
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
 
-                Ok(DnsRecord::CNAME { domain, host, ttl })
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                }
             }
 
             // MX is almost like the previous two, but with one extra field for priority.
             QueryType::MX => {
-                let priority = try!(buffer.read_u16());
+                let priority = buffer.read_u16()?;
                 let mut host = String::new();
-                try!(buffer.read_qname(&mut host));
+                buffer.read_qname(&mut host)?;
 
-                Ok(DnsRecord::MX {
+                DnsRecord::MX {
                     domain,
                     priority,
                     host,
                     ttl,
-                })
+                }
+            }
+
+            // The OPT pseudo-record reuses the class field for the requestor's UDP
+            // payload size and the TTL for the extended result code and flags.
+            QueryType::OPT => {
+                let options = EdnsOption::read_all(buffer, data_len)?;
+
+                DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    options,
+                }
             }
 
-            // And we end with some code for handling unknown record types, as before.
+            // The data of unknown record types is kept as it is, so that the
+            // record can be passed on and formatted as described in RFC 3597.
             QueryType::UNKNOWN(_) => {
-                try!(buffer.step(data_len as usize));
+                let data = buffer.get_range(data_start, data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
 
-                Ok(DnsRecord::UNKNOWN {
+                DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
-                })
+                }
             }
+        };
+
+        // Every decoder above has to consume exactly the `data_len` bytes the
+        // record declared, otherwise the rest of the message would be read from
+        // the wrong offset. Compressed names only count the bytes stored inside
+        // the RDATA itself, since `read_qname` doesn't advance past a jump.
+        let consumed = buffer.pos() - data_start;
+        if consumed != data_len as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "RDATA length mismatch for {:?} record: declared {} bytes, consumed {}",
+                    qtype, data_len, consumed
+                ),
+            ));
         }
+
+        Ok(record)
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
         let start_pos = buffer.pos();
 
         match *self {
@@ -140,53 +306,101 @@ impl DnsRecord {
                 ref addr,
                 ttl,
             } => {
-                try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::A.to_num()));
-                try!(buffer.write_u16(1));
-                try!(buffer.write_u32(ttl));
-                try!(buffer.write_u16(4));
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::A.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4)?;
 
                 let octets = addr.octets();
-                try!(buffer.write_u8(octets[0]));
-                try!(buffer.write_u8(octets[1]));
-                try!(buffer.write_u8(octets[2]));
-                try!(buffer.write_u8(octets[3]));
+                buffer.write_u8(octets[0])?;
+                buffer.write_u8(octets[1])?;
+                buffer.write_u8(octets[2])?;
+                buffer.write_u8(octets[3])?;
             }
             DnsRecord::NS {
                 ref domain,
                 ref host,
                 ttl,
             } => {
-                try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::NS.to_num()));
-                try!(buffer.write_u16(1));
-                try!(buffer.write_u32(ttl));
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
-                try!(buffer.write_u16(0));
+                buffer.write_u16(0)?;
 
-                try!(buffer.write_qname(host));
+                buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                try!(buffer.set_u16(pos, size as u16));
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::CNAME {
                 ref domain,
                 ref host,
                 ttl,
             } => {
-                try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::CNAME.to_num()));
-                try!(buffer.write_u16(1));
-                try!(buffer.write_u32(ttl));
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CNAME.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
-                try!(buffer.write_u16(0));
+                buffer.write_u16(0)?;
 
-                try!(buffer.write_qname(host));
+                buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                try!(buffer.set_u16(pos, size as u16));
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::MX {
                 ref domain,
@@ -194,40 +408,350 @@ impl DnsRecord {
                 ref host,
                 ttl,
             } => {
-                try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::MX.to_num()));
-                try!(buffer.write_u16(1));
-                try!(buffer.write_u32(ttl));
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::MX.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
-                try!(buffer.write_u16(0));
+                buffer.write_u16(0)?;
 
-                try!(buffer.write_u16(priority));
-                try!(buffer.write_qname(host));
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                try!(buffer.set_u16(pos, size as u16));
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
                 ttl,
             } => {
-                try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::AAAA.to_num()));
-                try!(buffer.write_u16(1));
-                try!(buffer.write_u32(ttl));
-                try!(buffer.write_u16(16));
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
 
                 for octet in &addr.segments() {
-                    try!(buffer.write_u16(*octet));
+                    buffer.write_u16(*octet)?;
+                }
+            }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    option.write(buffer)?;
                 }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
 }
+
+/// Formats a name the way zone files write it, as an absolute name with a
+/// trailing dot.
+pub(crate) fn presentation_name(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// The mnemonic for a class, or the generic syntax of RFC 3597.
+pub(crate) fn class_name(class: u16) -> String {
+    match class {
+        1 => "IN".to_string(),
+        3 => "CH".to_string(),
+        4 => "HS".to_string(),
+        _ => format!("CLASS{}", class),
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    /// Formats the record in the presentation format of zone files, with the
+    /// fields separated by tabs like dig does. The OPT pseudo-record has no
+    /// such format, so it's shown as a comment.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let DnsRecord::OPT {
+            packet_len, flags, ..
+        } = *self
+        {
+            let version = (flags >> 16) & 0xFF;
+            let dnssec_ok = if flags & 0x8000 != 0 { " do" } else { "" };
+            return write!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                version, dnssec_ok, packet_len
+            );
+        }
+
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t",
+            presentation_name(self.get_domain()),
+            self.get_ttl().unwrap_or(0),
+            self.get_querytype()
+        )?;
+
+        match *self {
+            DnsRecord::A { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::AAAA { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => {
+                write!(f, "{}", presentation_name(host))
+            }
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => write!(f, "{} {}", priority, presentation_name(host)),
+            DnsRecord::SOA {
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                presentation_name(m_name),
+                presentation_name(r_name),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            // Unknown records use the generic syntax of RFC 3597.
+            DnsRecord::UNKNOWN { ref data, .. } if data.is_empty() => write!(f, "\\# 0"),
+            DnsRecord::UNKNOWN { ref data, .. } => {
+                write!(f, "\\# {} {}", data.len(), hex(data))
+            }
+            DnsRecord::OPT { .. } => Ok(()),
+        }
+    }
+}
+
+impl FromStr for DnsRecord {
+    type Err = Error;
+
+    /// Parses a record from a single zone file entry, such as
+    /// `example.com. 300 IN MX 10 mail.example.com.`, which is also what
+    /// `Display` produces. Names are always taken to be absolute.
+    fn from_str(s: &str) -> Result<DnsRecord> {
+        parse_record(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BytePacketBuffer;
+
+    use super::*;
+
+    #[test]
+    fn it_reads_compressed_names_within_rdata() {
+        let mut buffer = BytePacketBuffer::new();
+        let record = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, // example.com
+            0, 2, 0, 1, 0, 0, 1, 44, 0, 5, // NS, IN, TTL 300, RDLENGTH 5
+            2, b'n', b's', 0xC0, 0x00, // "ns" and a pointer back to example.com
+        ];
+        buffer.buf[..record.len()].copy_from_slice(&record);
+
+        assert_eq!(
+            DnsRecord::read(&mut buffer).unwrap(),
+            DnsRecord::NS {
+                domain: "example.com".into(),
+                host: "ns.example.com".into(),
+                ttl: 300,
+            }
+        );
+        assert_eq!(buffer.pos(), record.len());
+    }
+
+    #[test]
+    fn it_rejects_rdata_shorter_than_declared() {
+        let mut buffer = BytePacketBuffer::new();
+        let record = [
+            2, b'n', b's', 0, // ns
+            0, 2, 0, 1, 0, 0, 1, 44, 0, 5, // NS, IN, TTL 300, RDLENGTH 5
+            1, b'a', 0, 0, 0, // "a." followed by two stray bytes
+        ];
+        buffer.buf[..record.len()].copy_from_slice(&record);
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn it_rejects_rdata_longer_than_declared() {
+        let mut buffer = BytePacketBuffer::new();
+        let record = [
+            2, b'n', b's', 0, // ns
+            0, 2, 0, 1, 0, 0, 1, 44, 0, 2, // NS, IN, TTL 300, RDLENGTH 2
+            1, b'a', 0, // "a.", one byte more than declared
+        ];
+        buffer.buf[..record.len()].copy_from_slice(&record);
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_soa_records() {
+        let soa = DnsRecord::SOA {
+            domain: "example.com".into(),
+            m_name: "ns1.example.com".into(),
+            r_name: "hostmaster.example.com".into(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 900,
+            expire: 1_209_600,
+            minimum: 300,
+            ttl: 3600,
+        };
+
+        let mut buffer = BytePacketBuffer::new();
+        soa.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), soa);
+        assert_eq!(soa.get_querytype(), QueryType::SOA);
+        assert_eq!(soa.get_domain(), "example.com");
+    }
+
+    #[test]
+    fn it_formats_records_in_presentation_format() {
+        let records = [
+            (
+                DnsRecord::A {
+                    domain: "example.com".into(),
+                    addr: "192.0.2.1".parse().unwrap(),
+                    ttl: 300,
+                },
+                "example.com.\t300\tIN\tA\t192.0.2.1",
+            ),
+            (
+                DnsRecord::MX {
+                    domain: "example.com".into(),
+                    priority: 10,
+                    host: "mail.example.com".into(),
+                    ttl: 3600,
+                },
+                "example.com.\t3600\tIN\tMX\t10 mail.example.com.",
+            ),
+            (
+                DnsRecord::SOA {
+                    domain: "".into(),
+                    m_name: "a.root-servers.net".into(),
+                    r_name: "nstld.verisign-grs.com".into(),
+                    serial: 2024010101,
+                    refresh: 1800,
+                    retry: 900,
+                    expire: 604800,
+                    minimum: 86400,
+                    ttl: 86400,
+                },
+                ".\t86400\tIN\tSOA\ta.root-servers.net. nstld.verisign-grs.com. \
+                 2024010101 1800 900 604800 86400",
+            ),
+            (
+                DnsRecord::UNKNOWN {
+                    domain: "example.com".into(),
+                    qtype: 99,
+                    data: vec![0xde, 0xad, 0xbe, 0xef, 0x01],
+                    ttl: 60,
+                },
+                "example.com.\t60\tIN\tTYPE99\t\\# 5 deadbeef01",
+            ),
+        ];
+
+        for (record, expected) in records.iter() {
+            assert_eq!(record.to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn it_parses_records_in_presentation_format() {
+        let record: DnsRecord = "example.com. 300 IN MX 10 mail.example.com.".parse().unwrap();
+        assert_eq!(
+            record,
+            DnsRecord::MX {
+                domain: "example.com".into(),
+                priority: 10,
+                host: "mail.example.com".into(),
+                ttl: 300,
+            }
+        );
+
+        let record: DnsRecord = "www.Example.com 1h aaaa 2001:db8::1".parse().unwrap();
+        assert_eq!(
+            record,
+            DnsRecord::AAAA {
+                domain: "www.example.com".into(),
+                addr: "2001:db8::1".parse().unwrap(),
+                ttl: 3600,
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_what_it_formats() {
+        let lines = [
+            "example.com.\t300\tIN\tA\t192.0.2.1",
+            "example.com.\t300\tIN\tAAAA\t2001:db8::1",
+            "example.com.\t300\tIN\tNS\tns1.example.com.",
+            "www.example.com.\t300\tIN\tCNAME\texample.com.",
+            "1.2.0.192.in-addr.arpa.\t300\tIN\tPTR\twww.example.com.",
+            "example.com.\t300\tIN\tMX\t10 mail.example.com.",
+            "example.com.\t3600\tIN\tSOA\tns1.example.com. hostmaster.example.com. \
+             1 7200 900 1209600 300",
+            "example.com.\t300\tIN\tTYPE99\t\\# 3 abcdef",
+            "example.com.\t300\tIN\tTYPE99\t\\# 0",
+        ];
+
+        for line in lines.iter() {
+            let record: DnsRecord = line.parse().unwrap();
+            assert_eq!(record.to_string(), *line);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_records() {
+        assert!("example.com. IN A 192.0.2.1".parse::<DnsRecord>().is_err());
+        assert!("example.com. 300 IN A 192.0.2".parse::<DnsRecord>().is_err());
+        assert!("example.com. 300 IN FOO bar".parse::<DnsRecord>().is_err());
+        assert!("$INCLUDE /etc/passwd".parse::<DnsRecord>().is_err());
+        assert!("a. 1 A 192.0.2.1\nb. 1 A 192.0.2.2".parse::<DnsRecord>().is_err());
+        assert!("".parse::<DnsRecord>().is_err());
+    }
+}
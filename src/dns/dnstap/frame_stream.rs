@@ -0,0 +1,122 @@
+//! Frame Streams, the framing dnstap is carried in
+//!
+//! Data frames are prefixed with their length. Control frames are escaped by
+//! a zero length, followed by their own length, their type and any fields. A
+//! file is simply a START frame, the data frames and a STOP frame, while a
+//! socket first negotiates the content type with a READY and ACCEPT
+//! exchange, and ends with a FINISH from the reader.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub(crate) const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+pub(crate) const CONTROL_ACCEPT: u32 = 0x01;
+pub(crate) const CONTROL_START: u32 = 0x02;
+pub(crate) const CONTROL_STOP: u32 = 0x03;
+pub(crate) const CONTROL_READY: u32 = 0x04;
+pub(crate) const CONTROL_FINISH: u32 = 0x05;
+
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// Control frames are tiny, so anything larger is a sign of a confused peer.
+const MAX_CONTROL_LEN: usize = 512;
+
+/// Writes a control frame, carrying our content type unless it's a STOP or
+/// FINISH frame.
+pub(crate) fn write_control<W: Write + ?Sized>(
+    writer: &mut W,
+    control_type: u32,
+) -> Result<()> {
+    let mut frame = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP && control_type != CONTROL_FINISH {
+        frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+
+    writer.write_all(&0u32.to_be_bytes())?;
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame)
+}
+
+/// Reads a control frame, returning its type. A frame listing content types
+/// has to include ours.
+pub(crate) fn read_control<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut word = [0; 4];
+    reader.read_exact(&mut word)?;
+    if u32::from_be_bytes(word) != 0 {
+        return Err(invalid("Expected a control frame"));
+    }
+
+    reader.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=MAX_CONTROL_LEN).contains(&len) {
+        return Err(invalid("Invalid control frame length"));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+
+    let control_type = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    let mut fields = &frame[4..];
+    let mut content_types = Vec::new();
+    while !fields.is_empty() {
+        if fields.len() < 8 {
+            return Err(invalid("Truncated control frame field"));
+        }
+        let field_type = u32::from_be_bytes([fields[0], fields[1], fields[2], fields[3]]);
+        let field_len = u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]) as usize;
+        let value = match fields.get(8..8 + field_len) {
+            Some(x) => x,
+            None => return Err(invalid("Truncated control frame field")),
+        };
+        if field_type == FIELD_CONTENT_TYPE {
+            content_types.push(value);
+        }
+        fields = &fields[8 + field_len..];
+    }
+
+    if !content_types.is_empty() && !content_types.contains(&CONTENT_TYPE) {
+        return Err(invalid("Peer doesn't accept dnstap"));
+    }
+
+    Ok(control_type)
+}
+
+pub(crate) fn write_data<W: Write + ?Sized>(writer: &mut W, data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn it_round_trips_control_frames() {
+        let mut buffer = Vec::new();
+        write_control(&mut buffer, CONTROL_READY).unwrap();
+        write_control(&mut buffer, CONTROL_STOP).unwrap();
+
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 22], &buffer[..20]);
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3], &buffer[42..]);
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(CONTROL_READY, read_control(&mut reader).unwrap());
+        assert_eq!(CONTROL_STOP, read_control(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_other_content_types() {
+        let mut frame = vec![0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 4];
+        frame.extend_from_slice(b"json");
+
+        assert!(read_control(&mut Cursor::new(frame)).is_err());
+        assert!(read_control(&mut Cursor::new(vec![0, 0, 0, 4, 1, 2, 3, 4])).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Dnstap messages and their protocol buffer encoding
+//!
+//! The schema (dnstap.proto) only takes a handful of scalar and bytes fields,
+//! so messages are encoded by hand rather than with generated code.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dns::resolve::Transport;
+
+/// The kinds of messages dnstap distinguishes, named after who sent the query
+/// and the role of the one answering it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DnstapMessageType {
+    AuthQuery,
+    AuthResponse,
+    ResolverQuery,
+    ResolverResponse,
+    ClientQuery,
+    ClientResponse,
+    ForwarderQuery,
+    ForwarderResponse,
+    StubQuery,
+    StubResponse,
+    ToolQuery,
+    ToolResponse,
+}
+
+impl DnstapMessageType {
+    pub fn to_num(&self) -> u64 {
+        match *self {
+            DnstapMessageType::AuthQuery => 1,
+            DnstapMessageType::AuthResponse => 2,
+            DnstapMessageType::ResolverQuery => 3,
+            DnstapMessageType::ResolverResponse => 4,
+            DnstapMessageType::ClientQuery => 5,
+            DnstapMessageType::ClientResponse => 6,
+            DnstapMessageType::ForwarderQuery => 7,
+            DnstapMessageType::ForwarderResponse => 8,
+            DnstapMessageType::StubQuery => 9,
+            DnstapMessageType::StubResponse => 10,
+            DnstapMessageType::ToolQuery => 11,
+            DnstapMessageType::ToolResponse => 12,
+        }
+    }
+
+    /// The type of the response to a query of this type.
+    pub fn response(&self) -> DnstapMessageType {
+        match *self {
+            DnstapMessageType::AuthQuery => DnstapMessageType::AuthResponse,
+            DnstapMessageType::ResolverQuery => DnstapMessageType::ResolverResponse,
+            DnstapMessageType::ClientQuery => DnstapMessageType::ClientResponse,
+            DnstapMessageType::ForwarderQuery => DnstapMessageType::ForwarderResponse,
+            DnstapMessageType::StubQuery => DnstapMessageType::StubResponse,
+            DnstapMessageType::ToolQuery => DnstapMessageType::ToolResponse,
+            x => x,
+        }
+    }
+
+    fn names() -> [(DnstapMessageType, &'static str); 12] {
+        [
+            (DnstapMessageType::AuthQuery, "auth-query"),
+            (DnstapMessageType::AuthResponse, "auth-response"),
+            (DnstapMessageType::ResolverQuery, "resolver-query"),
+            (DnstapMessageType::ResolverResponse, "resolver-response"),
+            (DnstapMessageType::ClientQuery, "client-query"),
+            (DnstapMessageType::ClientResponse, "client-response"),
+            (DnstapMessageType::ForwarderQuery, "forwarder-query"),
+            (DnstapMessageType::ForwarderResponse, "forwarder-response"),
+            (DnstapMessageType::StubQuery, "stub-query"),
+            (DnstapMessageType::StubResponse, "stub-response"),
+            (DnstapMessageType::ToolQuery, "tool-query"),
+            (DnstapMessageType::ToolResponse, "tool-response"),
+        ]
+    }
+}
+
+impl fmt::Display for DnstapMessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = DnstapMessageType::names();
+        let name = names
+            .iter()
+            .find(|&&(x, _)| x == *self)
+            .map(|&(_, name)| name)
+            .unwrap_or_default();
+
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DnstapMessageType {
+    type Err = Error;
+
+    /// Parses names such as `client-query`, as used in the configuration.
+    fn from_str(s: &str) -> Result<DnstapMessageType, Error> {
+        DnstapMessageType::names()
+            .iter()
+            .find(|&&(_, name)| name == s)
+            .map(|&(x, _)| x)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown dnstap message type {}", s),
+                )
+            })
+    }
+}
+
+/// A single query or response seen on the wire.
+#[derive(Clone, Debug)]
+pub struct DnstapMessage {
+    pub message_type: DnstapMessageType,
+    pub transport: Transport,
+    /// The address the query was sent from.
+    pub query_address: SocketAddr,
+    /// The address the query was sent to.
+    pub response_address: SocketAddr,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub query_message: Option<Vec<u8>>,
+    pub response_message: Option<Vec<u8>>,
+}
+
+impl DnstapMessage {
+    /// A query of `message_type` sent at `query_time`.
+    pub fn query(
+        message_type: DnstapMessageType,
+        transport: Transport,
+        query_address: SocketAddr,
+        response_address: SocketAddr,
+        query_time: SystemTime,
+        message: &[u8],
+    ) -> DnstapMessage {
+        DnstapMessage {
+            message_type,
+            transport,
+            query_address,
+            response_address,
+            query_time,
+            response_time: None,
+            query_message: Some(message.to_vec()),
+            response_message: None,
+        }
+    }
+
+    /// The response to a query of `message_type`, received just now.
+    pub fn response(
+        message_type: DnstapMessageType,
+        transport: Transport,
+        query_address: SocketAddr,
+        response_address: SocketAddr,
+        query_time: SystemTime,
+        message: &[u8],
+    ) -> DnstapMessage {
+        DnstapMessage {
+            message_type: message_type.response(),
+            transport,
+            query_address,
+            response_address,
+            query_time,
+            response_time: Some(SystemTime::now()),
+            query_message: None,
+            response_message: Some(message.to_vec()),
+        }
+    }
+
+    /// Encodes the message as a `Dnstap` protocol buffer, ready to be sent
+    /// as a Frame Streams data frame.
+    pub fn encode(&self, identity: Option<&[u8]>) -> Vec<u8> {
+        let mut message = Vec::new();
+        write_varint_field(&mut message, 1, self.message_type.to_num());
+
+        let family = match self.query_address.ip() {
+            IpAddr::V4(_) => 1,
+            IpAddr::V6(_) => 2,
+        };
+        let protocol = match self.transport {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+        };
+        write_varint_field(&mut message, 2, family);
+        write_varint_field(&mut message, 3, protocol);
+        write_bytes_field(&mut message, 4, &ip_octets(self.query_address.ip()));
+        write_bytes_field(&mut message, 5, &ip_octets(self.response_address.ip()));
+        write_varint_field(&mut message, 6, u64::from(self.query_address.port()));
+        write_varint_field(&mut message, 7, u64::from(self.response_address.port()));
+
+        write_time_fields(&mut message, 8, self.query_time);
+        if let Some(ref query) = self.query_message {
+            write_bytes_field(&mut message, 10, query);
+        }
+        if let Some(time) = self.response_time {
+            write_time_fields(&mut message, 12, time);
+        }
+        if let Some(ref response) = self.response_message {
+            write_bytes_field(&mut message, 14, response);
+        }
+
+        let mut dnstap = Vec::new();
+        if let Some(identity) = identity {
+            write_bytes_field(&mut dnstap, 1, identity);
+        }
+        write_bytes_field(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        write_bytes_field(&mut dnstap, 14, &message);
+        // The only type of dnstap payload there is: MESSAGE.
+        write_varint_field(&mut dnstap, 15, 1);
+
+        dnstap
+    }
+}
+
+fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+// Fields are keyed by their number and wire type: 0 for varints, 2 for
+// length-delimited bytes and 5 for 32-bit fixed values.
+fn write_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(buffer, field << 3);
+    write_varint(buffer, value);
+}
+
+fn write_bytes_field(buffer: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(buffer, (field << 3) | 2);
+    write_varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value);
+}
+
+// Writes a time as seconds in field `field`, and nanoseconds as a fixed32 in
+// the field after it.
+fn write_time_fields(buffer: &mut Vec<u8>, field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    write_varint_field(buffer, field, since_epoch.as_secs());
+    write_varint(buffer, ((field + 1) << 3) | 5);
+    buffer.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn it_encodes_messages() {
+        let query_time = UNIX_EPOCH + Duration::new(300, 5);
+        let message = DnstapMessage::query(
+            DnstapMessageType::ClientQuery,
+            Transport::Udp,
+            "192.0.2.1:1234".parse().unwrap(),
+            "192.0.2.53:53".parse().unwrap(),
+            query_time,
+            &[0xAB],
+        );
+
+        let expected_message = [
+            0x08, 5, // type: CLIENT_QUERY
+            0x10, 1, // socket_family: INET
+            0x18, 1, // socket_protocol: UDP
+            0x22, 4, 192, 0, 2, 1, // query_address
+            0x2A, 4, 192, 0, 2, 53, // response_address
+            0x30, 0xD2, 0x09, // query_port: 1234
+            0x38, 53, // response_port
+            0x40, 0xAC, 0x02, // query_time_sec: 300
+            0x4D, 5, 0, 0, 0, // query_time_nsec
+            0x52, 1, 0xAB, // query_message
+        ];
+        let version = env!("CARGO_PKG_VERSION").as_bytes();
+
+        let mut expected = vec![0x0A, 2, b'n', b's', 0x12, version.len() as u8];
+        expected.extend_from_slice(version);
+        expected.extend_from_slice(&[0x72, expected_message.len() as u8]);
+        expected.extend_from_slice(&expected_message);
+        expected.extend_from_slice(&[0x78, 1]);
+
+        assert_eq!(expected, message.encode(Some(b"ns")));
+    }
+
+    #[test]
+    fn it_parses_message_types() {
+        for &(message_type, name) in DnstapMessageType::names().iter() {
+            assert_eq!(message_type, name.parse().unwrap());
+            assert_eq!(name, message_type.to_string());
+        }
+        assert_eq!(
+            DnstapMessageType::ResolverResponse,
+            DnstapMessageType::ResolverQuery.response()
+        );
+        assert!("client".parse::<DnstapMessageType>().is_err());
+    }
+}
@@ -0,0 +1,386 @@
+//! Logging of queries and responses in dnstap format
+//!
+//! Messages are handed to a writer thread over a bounded queue, so that a slow
+//! or absent collector never holds up a lookup: when the queue is full the
+//! message is dropped and counted instead. The thread encodes them as
+//! protocol buffers and writes them as Frame Streams, either to a file or to a
+//! collector listening on a Unix socket, reconnecting whenever the connection
+//! is lost.
+
+mod frame_stream;
+mod message;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use self::frame_stream::{read_control, write_control, write_data, CONTROL_ACCEPT,
+                         CONTROL_FINISH, CONTROL_READY, CONTROL_START, CONTROL_STOP};
+pub use self::message::{DnstapMessage, DnstapMessageType};
+use crate::dns::resolve::Transport;
+
+// How many messages may wait for the writer before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+// How long to wait before connecting to the collector again after failing to.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// How long the collector gets to answer our side of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where dnstap messages are written to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnstapOutput {
+    /// A file, created or truncated when logging starts.
+    File(PathBuf),
+    /// The Unix socket of a collector such as `dnstap` or `fstrm_capture`.
+    Socket(PathBuf),
+}
+
+/// A dnstap log, taking the message types it was configured with.
+pub struct Dnstap {
+    types: HashSet<DnstapMessageType>,
+    sender: SyncSender<DnstapMessage>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    /// Starts logging the given message types to `output`, naming this server
+    /// `identity` in every message if given. A file that can't be created is
+    /// an error, while a collector that isn't listening yet is retried.
+    pub fn new(
+        output: DnstapOutput,
+        types: Vec<DnstapMessageType>,
+        identity: Option<String>,
+    ) -> Result<Dnstap> {
+        let writer = match output {
+            DnstapOutput::File(ref path) => Some(open_file(File::create(path)?)?),
+            DnstapOutput::Socket(_) => None,
+        };
+
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut output = Output {
+            output,
+            identity: identity.map(String::into_bytes),
+            writer,
+            retry_at: Instant::now(),
+            dropped: dropped.clone(),
+        };
+        thread::Builder::new()
+            .name("dnstap".to_string())
+            .spawn(move || output.run(receiver))?;
+
+        Ok(Dnstap {
+            types: types.into_iter().collect(),
+            sender,
+            dropped,
+        })
+    }
+
+    /// Whether messages of `message_type` are logged.
+    pub fn wants(&self, message_type: DnstapMessageType) -> bool {
+        self.types.contains(&message_type)
+    }
+
+    /// Queues `message` for the writer, unless its type isn't logged.
+    pub fn log(&self, message: DnstapMessage) {
+        if !self.wants(message.message_type) {
+            return;
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// How many messages were dropped, as the queue was full or there was
+    /// nowhere to write them.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// The dnstap log every lookup in the process writes to, if any.
+static DNSTAP: RwLock<Option<Arc<Dnstap>>> = RwLock::new(None);
+
+/// Sets the dnstap log every lookup in the process, and the server, log their
+/// queries and responses to.
+pub fn set_dnstap(dnstap: Arc<Dnstap>) {
+    *DNSTAP.write().unwrap() = Some(dnstap);
+}
+
+pub(crate) fn dnstap() -> Option<Arc<Dnstap>> {
+    DNSTAP.read().unwrap().clone()
+}
+
+// Logs the query `message`, sent as `role` from `local` to `server`.
+pub(crate) fn log_query(
+    role: DnstapMessageType,
+    transport: Transport,
+    local: SocketAddr,
+    server: SocketAddr,
+    query_time: SystemTime,
+    message: &[u8],
+) {
+    if let Some(dnstap) = dnstap() {
+        if dnstap.wants(role) {
+            let query = DnstapMessage::query(role, transport, local, server, query_time, message);
+            dnstap.log(query);
+        }
+    }
+}
+
+// Logs the response `message` to a query logged by `log_query`.
+pub(crate) fn log_response(
+    role: DnstapMessageType,
+    transport: Transport,
+    local: SocketAddr,
+    server: SocketAddr,
+    query_time: SystemTime,
+    message: &[u8],
+) {
+    if let Some(dnstap) = dnstap() {
+        if dnstap.wants(role.response()) {
+            let response =
+                DnstapMessage::response(role, transport, local, server, query_time, message);
+            dnstap.log(response);
+        }
+    }
+}
+
+enum Writer {
+    File(BufWriter<File>),
+    Socket(BufWriter<UnixStream>),
+}
+
+impl Writer {
+    fn get_mut(&mut self) -> &mut dyn Write {
+        match *self {
+            Writer::File(ref mut x) => x,
+            Writer::Socket(ref mut x) => x,
+        }
+    }
+
+    // Ends the stream, waiting for the collector to acknowledge that.
+    fn finish(mut self) -> Result<()> {
+        write_control(self.get_mut(), CONTROL_STOP)?;
+        self.get_mut().flush()?;
+        if let Writer::Socket(ref mut x) = self {
+            x.get_ref().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            if read_control(x.get_mut())? != CONTROL_FINISH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Collector didn't finish the stream",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn open_file(file: File) -> Result<Writer> {
+    let mut writer = BufWriter::new(file);
+    write_control(&mut writer, CONTROL_START)?;
+    writer.flush()?;
+
+    Ok(Writer::File(writer))
+}
+
+// Connects to the collector at `path`, agreeing on the content type before
+// starting the stream.
+fn connect(path: &PathBuf) -> Result<Writer> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    write_control(&mut stream, CONTROL_READY)?;
+    if read_control(&mut stream)? != CONTROL_ACCEPT {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Collector didn't accept the stream",
+        ));
+    }
+    write_control(&mut stream, CONTROL_START)?;
+
+    Ok(Writer::Socket(BufWriter::new(stream)))
+}
+
+// The writer thread's end of a dnstap log.
+struct Output {
+    output: DnstapOutput,
+    identity: Option<Vec<u8>>,
+    writer: Option<Writer>,
+    retry_at: Instant,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Output {
+    fn run(&mut self, receiver: Receiver<DnstapMessage>) {
+        // Once the log is dropped the stream is ended properly.
+        while let Ok(message) = receiver.recv() {
+            self.write(&message);
+            // Flushing after every message would cost a syscall each, so
+            // whatever else is queued is written first.
+            while let Ok(message) = receiver.try_recv() {
+                self.write(&message);
+            }
+            self.flush();
+        }
+
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finish() {
+                debug!(error:% = e; "Failed to end dnstap stream");
+            }
+        }
+    }
+
+    fn write(&mut self, message: &DnstapMessage) {
+        if self.writer.is_none() {
+            self.reconnect();
+        }
+
+        let writer = match self.writer {
+            Some(ref mut x) => x,
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let data = message.encode(self.identity.as_deref());
+        let written = write_data(writer.get_mut(), &data);
+        if let Err(e) = written {
+            self.failed(e);
+        }
+    }
+
+    fn flush(&mut self) {
+        let flushed = match self.writer {
+            Some(ref mut x) => x.get_mut().flush(),
+            None => Ok(()),
+        };
+        if let Err(e) = flushed {
+            self.failed(e);
+        }
+    }
+
+    fn reconnect(&mut self) {
+        let path = match self.output {
+            DnstapOutput::Socket(ref path) => path,
+            // A file isn't reopened, as that would truncate it.
+            DnstapOutput::File(_) => return,
+        };
+        if Instant::now() < self.retry_at {
+            return;
+        }
+
+        match connect(path) {
+            Ok(writer) => {
+                info!(socket:? = path; "Connected to dnstap collector");
+                self.writer = Some(writer);
+            }
+            Err(e) => {
+                warn!(socket:? = path, error:% = e; "Failed to connect to dnstap collector");
+                self.retry_at = Instant::now() + RECONNECT_DELAY;
+            }
+        }
+    }
+
+    fn failed(&mut self, e: std::io::Error) {
+        warn!(output:? = self.output, error:% = e; "Failed to write dnstap message");
+        self.writer = None;
+        self.retry_at = Instant::now() + RECONNECT_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+
+    use super::*;
+
+    fn client_query() -> DnstapMessage {
+        DnstapMessage::query(
+            DnstapMessageType::ClientQuery,
+            Transport::Udp,
+            "192.0.2.1:1234".parse().unwrap(),
+            "192.0.2.53:53".parse().unwrap(),
+            SystemTime::now(),
+            &[0; 12],
+        )
+    }
+
+    #[test]
+    fn it_writes_to_files() {
+        let path = env::temp_dir().join(format!("dnsafe-dnstap-{}.fstrm", process::id()));
+        let output = DnstapOutput::File(path.clone());
+        let dnstap = Dnstap::new(output, vec![DnstapMessageType::ClientQuery], None).unwrap();
+
+        dnstap.log(client_query());
+        let mut response = client_query();
+        response.message_type = DnstapMessageType::ClientResponse;
+        dnstap.log(response);
+        drop(dnstap);
+
+        // The writer ends the stream once it has written everything queued.
+        let mut written = Vec::new();
+        for _ in 0..100 {
+            written = fs::read(&path).unwrap();
+            if written.ends_with(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&path).unwrap();
+
+        let data = client_query().encode(None);
+        let start_len = 8 + 34;
+        assert_eq!(start_len + 4 + data.len() + 12, written.len());
+        assert_eq!(&(data.len() as u32).to_be_bytes(), &written[start_len..start_len + 4]);
+    }
+
+    #[test]
+    fn it_shakes_hands_with_collectors() {
+        let path = env::temp_dir().join(format!("dnsafe-dnstap-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let output = DnstapOutput::Socket(path.clone());
+        let types = vec![DnstapMessageType::ClientQuery];
+        let dnstap = Dnstap::new(output, types, Some("ns1".to_string())).unwrap();
+        dnstap.log(client_query());
+
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(CONTROL_READY, read_control(&mut stream).unwrap());
+        write_control(&mut stream, CONTROL_ACCEPT).unwrap();
+        assert_eq!(CONTROL_START, read_control(&mut stream).unwrap());
+
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).unwrap();
+        assert!(data.starts_with(b"\x0a\x03ns1"));
+
+        drop(dnstap);
+        assert_eq!(CONTROL_STOP, read_control(&mut stream).unwrap());
+        write_control(&mut stream, CONTROL_FINISH).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::PacketBuffer;
+use crate::ExtendedErrorCode;
+
+/// A single option from the RDATA of an OPT pseudo-record.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EdnsOption {
+    UNKNOWN {
+        code: u16,
+        data: Vec<u8>,
+    },
+    ECS {
+        source_prefix: u8,
+        scope_prefix: u8,
+        addr: IpAddr,
+    }, // 8
+    COOKIE {
+        client: [u8; 8],
+        server: Vec<u8>,
+    }, // 10
+    EDE {
+        info_code: ExtendedErrorCode,
+        extra_text: String,
+    }, // 15
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match *self {
+            EdnsOption::UNKNOWN { code, .. } => code,
+            EdnsOption::ECS { .. } => 8,
+            EdnsOption::COOKIE { .. } => 10,
+            EdnsOption::EDE { .. } => 15,
+        }
+    }
+
+    /// Reads every option contained in the next `data_len` bytes.
+    pub fn read_all<T: PacketBuffer>(buffer: &mut T, data_len: u16) -> Result<Vec<EdnsOption>> {
+        let end = buffer.pos() + data_len as usize;
+
+        let mut options = Vec::new();
+        while buffer.pos() + 4 <= end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+            buffer.step(len)?;
+
+            options.push(EdnsOption::from_data(code, data));
+        }
+
+        // Anything left over is too short to be an option header. Step over it
+        // so the length check in `DnsRecord::read` reports the inconsistency.
+        if buffer.pos() < end {
+            let pos = buffer.pos();
+            buffer.step(end - pos)?;
+        }
+
+        Ok(options)
+    }
+
+    fn from_data(code: u16, data: Vec<u8>) -> EdnsOption {
+        match code {
+            // The address is only sent as far as the source prefix reaches, the
+            // rest of it is implied to be zero.
+            8 if data.len() >= 4 => {
+                let family = (u16::from(data[0]) << 8) | u16::from(data[1]);
+                let source_prefix = data[2];
+                let scope_prefix = data[3];
+                let addr = &data[4..];
+
+                match family {
+                    1 if addr.len() <= 4 && source_prefix <= 32 => {
+                        let mut octets = [0; 4];
+                        octets[..addr.len()].copy_from_slice(addr);
+                        EdnsOption::ECS {
+                            source_prefix,
+                            scope_prefix,
+                            addr: IpAddr::V4(Ipv4Addr::from(octets)),
+                        }
+                    }
+                    2 if addr.len() <= 16 && source_prefix <= 128 => {
+                        let mut octets = [0; 16];
+                        octets[..addr.len()].copy_from_slice(addr);
+                        EdnsOption::ECS {
+                            source_prefix,
+                            scope_prefix,
+                            addr: IpAddr::V6(Ipv6Addr::from(octets)),
+                        }
+                    }
+                    _ => EdnsOption::UNKNOWN { code, data },
+                }
+            }
+            // A client cookie is always 8 bytes, optionally followed by a server
+            // cookie of 8 to 32 bytes. Anything else is kept as UNKNOWN so the
+            // receiver can tell that the option was malformed.
+            10 if data.len() == 8 || (data.len() >= 16 && data.len() <= 40) => {
+                let mut client = [0; 8];
+                client.copy_from_slice(&data[..8]);
+                EdnsOption::COOKIE {
+                    client,
+                    server: data[8..].to_vec(),
+                }
+            }
+            15 if data.len() >= 2 => EdnsOption::EDE {
+                info_code: ExtendedErrorCode::from_num(
+                    (u16::from(data[0]) << 8) | u16::from(data[1]),
+                ),
+                extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+            },
+            _ => EdnsOption::UNKNOWN { code, data },
+        }
+    }
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u16(self.code())?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        match *self {
+            EdnsOption::UNKNOWN { ref data, .. } => for b in data {
+                buffer.write_u8(*b)?;
+            },
+            EdnsOption::ECS {
+                source_prefix,
+                scope_prefix,
+                addr,
+            } => {
+                let (family, octets) = match addr {
+                    IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+                    IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+                };
+                buffer.write_u16(family)?;
+                buffer.write_u8(source_prefix)?;
+                buffer.write_u8(scope_prefix)?;
+
+                let len = (source_prefix as usize).div_ceil(8).min(octets.len());
+                for b in &octets[..len] {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            EdnsOption::COOKIE {
+                ref client,
+                ref server,
+            } => for b in client.iter().chain(server.iter()) {
+                buffer.write_u8(*b)?;
+            },
+            EdnsOption::EDE {
+                info_code,
+                ref extra_text,
+            } => {
+                buffer.write_u16(info_code.to_num())?;
+                for b in extra_text.as_bytes() {
+                    buffer.write_u8(*b)?;
+                }
+            }
+        }
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for EdnsOption {
+    // Formats the option as a line of dig's OPT pseudo-section.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EdnsOption::UNKNOWN { code, ref data } => write!(f, "OPT={}: {}", code, hex(data)),
+            EdnsOption::ECS {
+                source_prefix,
+                scope_prefix,
+                addr,
+            } => write!(f, "CLIENT-SUBNET: {}/{}/{}", addr, source_prefix, scope_prefix),
+            EdnsOption::COOKIE {
+                ref client,
+                ref server,
+            } => write!(f, "COOKIE: {}{}", hex(client), hex(server)),
+            EdnsOption::EDE {
+                info_code,
+                ref extra_text,
+            } => {
+                write!(f, "EDE: {}", info_code.to_num())?;
+                if let Some(description) = info_code.description() {
+                    write!(f, " ({})", description)?;
+                }
+                if !extra_text.is_empty() {
+                    write!(f, ": ({})", extra_text)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,142 @@
+/// Info codes carried by the Extended DNS Error option (RFC 8914).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ExtendedErrorCode {
+    Unknown(u16),
+    Other,                      // 0
+    UnsupportedDnskeyAlgorithm, // 1
+    UnsupportedDsDigestType,    // 2
+    StaleAnswer,                // 3
+    ForgedAnswer,               // 4
+    DnssecIndeterminate,        // 5
+    DnssecBogus,                // 6
+    SignatureExpired,           // 7
+    SignatureNotYetValid,       // 8
+    DnskeyMissing,              // 9
+    RrsigsMissing,              // 10
+    NoZoneKeyBitSet,            // 11
+    NsecMissing,                // 12
+    CachedError,                // 13
+    NotReady,                   // 14
+    Blocked,                    // 15
+    Censored,                   // 16
+    Filtered,                   // 17
+    Prohibited,                 // 18
+    StaleNxdomainAnswer,        // 19
+    NotAuthoritative,           // 20
+    NotSupported,               // 21
+    NoReachableAuthority,       // 22
+    NetworkError,               // 23
+    InvalidData,                // 24
+}
+
+impl ExtendedErrorCode {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            ExtendedErrorCode::Unknown(x) => x,
+            ExtendedErrorCode::Other => 0,
+            ExtendedErrorCode::UnsupportedDnskeyAlgorithm => 1,
+            ExtendedErrorCode::UnsupportedDsDigestType => 2,
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::ForgedAnswer => 4,
+            ExtendedErrorCode::DnssecIndeterminate => 5,
+            ExtendedErrorCode::DnssecBogus => 6,
+            ExtendedErrorCode::SignatureExpired => 7,
+            ExtendedErrorCode::SignatureNotYetValid => 8,
+            ExtendedErrorCode::DnskeyMissing => 9,
+            ExtendedErrorCode::RrsigsMissing => 10,
+            ExtendedErrorCode::NoZoneKeyBitSet => 11,
+            ExtendedErrorCode::NsecMissing => 12,
+            ExtendedErrorCode::CachedError => 13,
+            ExtendedErrorCode::NotReady => 14,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Censored => 16,
+            ExtendedErrorCode::Filtered => 17,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::StaleNxdomainAnswer => 19,
+            ExtendedErrorCode::NotAuthoritative => 20,
+            ExtendedErrorCode::NotSupported => 21,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::InvalidData => 24,
+        }
+    }
+
+    pub fn from_num(num: u16) -> ExtendedErrorCode {
+        match num {
+            0 => ExtendedErrorCode::Other,
+            1 => ExtendedErrorCode::UnsupportedDnskeyAlgorithm,
+            2 => ExtendedErrorCode::UnsupportedDsDigestType,
+            3 => ExtendedErrorCode::StaleAnswer,
+            4 => ExtendedErrorCode::ForgedAnswer,
+            5 => ExtendedErrorCode::DnssecIndeterminate,
+            6 => ExtendedErrorCode::DnssecBogus,
+            7 => ExtendedErrorCode::SignatureExpired,
+            8 => ExtendedErrorCode::SignatureNotYetValid,
+            9 => ExtendedErrorCode::DnskeyMissing,
+            10 => ExtendedErrorCode::RrsigsMissing,
+            11 => ExtendedErrorCode::NoZoneKeyBitSet,
+            12 => ExtendedErrorCode::NsecMissing,
+            13 => ExtendedErrorCode::CachedError,
+            14 => ExtendedErrorCode::NotReady,
+            15 => ExtendedErrorCode::Blocked,
+            16 => ExtendedErrorCode::Censored,
+            17 => ExtendedErrorCode::Filtered,
+            18 => ExtendedErrorCode::Prohibited,
+            19 => ExtendedErrorCode::StaleNxdomainAnswer,
+            20 => ExtendedErrorCode::NotAuthoritative,
+            21 => ExtendedErrorCode::NotSupported,
+            22 => ExtendedErrorCode::NoReachableAuthority,
+            23 => ExtendedErrorCode::NetworkError,
+            24 => ExtendedErrorCode::InvalidData,
+            _ => ExtendedErrorCode::Unknown(num),
+        }
+    }
+
+    /// The name of the code in the IANA registry, or `None` for unassigned
+    /// codes.
+    pub fn description(&self) -> Option<&'static str> {
+        match *self {
+            ExtendedErrorCode::Unknown(_) => None,
+            ExtendedErrorCode::Other => Some("Other Error"),
+            ExtendedErrorCode::UnsupportedDnskeyAlgorithm => Some("Unsupported DNSKEY Algorithm"),
+            ExtendedErrorCode::UnsupportedDsDigestType => Some("Unsupported DS Digest Type"),
+            ExtendedErrorCode::StaleAnswer => Some("Stale Answer"),
+            ExtendedErrorCode::ForgedAnswer => Some("Forged Answer"),
+            ExtendedErrorCode::DnssecIndeterminate => Some("DNSSEC Indeterminate"),
+            ExtendedErrorCode::DnssecBogus => Some("DNSSEC Bogus"),
+            ExtendedErrorCode::SignatureExpired => Some("Signature Expired"),
+            ExtendedErrorCode::SignatureNotYetValid => Some("Signature Not Yet Valid"),
+            ExtendedErrorCode::DnskeyMissing => Some("DNSKEY Missing"),
+            ExtendedErrorCode::RrsigsMissing => Some("RRSIGs Missing"),
+            ExtendedErrorCode::NoZoneKeyBitSet => Some("No Zone Key Bit Set"),
+            ExtendedErrorCode::NsecMissing => Some("NSEC Missing"),
+            ExtendedErrorCode::CachedError => Some("Cached Error"),
+            ExtendedErrorCode::NotReady => Some("Not Ready"),
+            ExtendedErrorCode::Blocked => Some("Blocked"),
+            ExtendedErrorCode::Censored => Some("Censored"),
+            ExtendedErrorCode::Filtered => Some("Filtered"),
+            ExtendedErrorCode::Prohibited => Some("Prohibited"),
+            ExtendedErrorCode::StaleNxdomainAnswer => Some("Stale NXDomain Answer"),
+            ExtendedErrorCode::NotAuthoritative => Some("Not Authoritative"),
+            ExtendedErrorCode::NotSupported => Some("Not Supported"),
+            ExtendedErrorCode::NoReachableAuthority => Some("No Reachable Authority"),
+            ExtendedErrorCode::NetworkError => Some("Network Error"),
+            ExtendedErrorCode::InvalidData => Some("Invalid Data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_describes_codes_by_their_registered_names() {
+        assert_eq!(
+            ExtendedErrorCode::NoReachableAuthority.description(),
+            Some("No Reachable Authority")
+        );
+        assert_eq!(ExtendedErrorCode::from_num(19).description(), Some("Stale NXDomain Answer"));
+        assert_eq!(ExtendedErrorCode::from_num(99).description(), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! Answering from files in the hosts(5) format
+//!
+//! Each line holds an address followed by the names it belongs to, and `#`
+//! starts a comment that runs to the end of the line:
+//!
+//! ```text
+//! 192.0.2.10   www.example.com www
+//! 0.0.0.0      ads.example.net  # blocked
+//! ```
+//!
+//! Such files are often collected from elsewhere, so lines that can't be
+//! parsed are skipped rather than failing the whole file.
+//!
+//! A `HostsFile` answers A and AAAA queries for the names it lists, and PTR
+//! queries for the reverse names of its addresses with the first name listed
+//! for them, which hosts(5) calls the canonical one.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, Result};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+
+// Entries can change whenever the file does, so they aren't cached for long.
+const HOSTS_TTL: u32 = 60;
+
+/// An address and the names listed for it, lowercase and without a trailing
+/// dot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HostsEntry {
+    pub addr: IpAddr,
+    pub names: Vec<String>,
+}
+
+/// Parses the contents of a hosts file.
+pub(crate) fn parse_hosts(data: &str) -> Vec<HostsEntry> {
+    let mut entries = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(x) => &line[..x],
+            None => line,
+        };
+        let mut tokens = line.split_whitespace();
+        let addr = match tokens.next() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let addr = match addr.parse::<IpAddr>() {
+            Ok(x) => x,
+            Err(_) => {
+                debug!(line = i + 1, addr; "Skipping hosts entry with an invalid address");
+                continue;
+            }
+        };
+        let names: Vec<String> = tokens
+            .map(|x| x.trim_end_matches('.').to_lowercase())
+            .filter(|x| !x.is_empty())
+            .collect();
+        if names.is_empty() {
+            continue;
+        }
+
+        entries.push(HostsEntry { addr, names });
+    }
+
+    entries
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    // The addresses of each name, in the order they are listed.
+    addrs: HashMap<String, Vec<IpAddr>>,
+    // The canonical name of each address, by its reverse name.
+    names: HashMap<String, String>,
+}
+
+impl Entries {
+    fn parse(data: &str) -> Entries {
+        let mut entries = Entries::default();
+        for entry in parse_hosts(data) {
+            for name in &entry.names {
+                let addrs = entries.addrs.entry(name.clone()).or_default();
+                if !addrs.contains(&entry.addr) {
+                    addrs.push(entry.addr);
+                }
+            }
+            // An address listed again keeps its first canonical name.
+            entries
+                .names
+                .entry(reverse_name(entry.addr))
+                .or_insert_with(|| entry.names[0].clone());
+        }
+
+        entries
+    }
+}
+
+/// The names and addresses of a hosts file, kept up to date with the file.
+#[derive(Debug)]
+pub struct HostsFile {
+    path: Option<PathBuf>,
+    modified: Mutex<Option<SystemTime>>,
+    entries: RwLock<Entries>,
+}
+
+impl HostsFile {
+    /// Reads a hosts file, to be reloaded whenever it changes.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<HostsFile> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let data = read(&path)?;
+
+        Ok(HostsFile {
+            path: Some(path),
+            modified: Mutex::new(modified),
+            entries: RwLock::new(Entries::parse(&data)),
+        })
+    }
+
+    /// Parses the contents of a hosts file, which can't be reloaded.
+    pub fn parse(data: &str) -> HostsFile {
+        HostsFile {
+            path: None,
+            modified: Mutex::new(None),
+            entries: RwLock::new(Entries::parse(data)),
+        }
+    }
+
+    /// The number of names listed.
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the file again if it has changed since it was last read,
+    /// returning whether it had. The previous entries are kept if the file
+    /// can't be read.
+    pub fn reload(&self) -> Result<bool> {
+        let path = match self.path {
+            Some(ref x) => x,
+            None => return Ok(false),
+        };
+
+        let modified = modified(path);
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return Ok(false);
+        }
+
+        // The file is parsed before taking the `entries` lock, so queries
+        // carry on with the old entries in the meantime. The time is only
+        // recorded once the file has been read, so a failed read is retried
+        // on the next check even if the file isn't touched again.
+        let entries = Entries::parse(&read(path)?);
+        *self.entries.write().unwrap() = entries;
+        *last_modified = modified;
+
+        Ok(true)
+    }
+
+    /// Answers a query for a name listed in the file, or for the reverse name
+    /// of an address listed, or returns `None` if neither is. Other types
+    /// than A, AAAA and PTR get an empty answer, as do addresses of the
+    /// other family.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let entries = self.entries.read().unwrap();
+
+        let answers = if let Some(addrs) = entries.addrs.get(&qname) {
+            addrs
+                .iter()
+                .filter_map(|&addr| match (addr, qtype) {
+                    (IpAddr::V4(addr), QueryType::A) => Some(DnsRecord::A {
+                        domain: qname.clone(),
+                        addr,
+                        ttl: HOSTS_TTL,
+                    }),
+                    (IpAddr::V6(addr), QueryType::AAAA) => Some(DnsRecord::AAAA {
+                        domain: qname.clone(),
+                        addr,
+                        ttl: HOSTS_TTL,
+                    }),
+                    _ => None,
+                })
+                .collect()
+        } else if let Some(name) = entries.names.get(&qname) {
+            match qtype {
+                QueryType::PTR => vec![DnsRecord::PTR {
+                    domain: qname.clone(),
+                    host: name.clone(),
+                    ttl: HOSTS_TTL,
+                }],
+                _ => Vec::new(),
+            }
+        } else {
+            return None;
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+        packet.answers = answers;
+
+        Some(packet)
+    }
+}
+
+/// The name PTR queries for `addr` ask for, such as `1.2.0.192.in-addr.arpa`
+/// for `192.0.2.1`.
+pub(crate) fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let o = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xF, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_hosts_files() {
+        let data = "# comment\n\
+                    127.0.0.1 localhost\n\
+                    192.0.2.10\tWWW.example.com. www # web\n\
+                    \n\
+                    not-an-address example.org\n\
+                    2001:db8::1 v6.example.com\n\
+                    198.51.100.1\n";
+        let entries = parse_hosts(data);
+
+        assert_eq!(
+            vec![
+                HostsEntry {
+                    addr: "127.0.0.1".parse().unwrap(),
+                    names: vec!["localhost".to_string()],
+                },
+                HostsEntry {
+                    addr: "192.0.2.10".parse().unwrap(),
+                    names: vec!["www.example.com".to_string(), "www".to_string()],
+                },
+                HostsEntry {
+                    addr: "2001:db8::1".parse().unwrap(),
+                    names: vec!["v6.example.com".to_string()],
+                },
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn it_answers_from_hosts_files() {
+        let hosts = HostsFile::parse(
+            "192.0.2.10 www.example.com www\n\
+             2001:db8::10 www.example.com\n\
+             192.0.2.11 db.example.com www.example.com\n",
+        );
+        let answer = |qname: &str, qtype: QueryType| {
+            hosts
+                .answer(qname, qtype)
+                .map(|x| x.answers.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            Some(vec![
+                "www.example.com.\t60\tIN\tA\t192.0.2.10".to_string(),
+                "www.example.com.\t60\tIN\tA\t192.0.2.11".to_string(),
+            ]),
+            answer("WWW.example.com.", QueryType::A)
+        );
+        assert_eq!(
+            Some(vec!["www.example.com.\t60\tIN\tAAAA\t2001:db8::10".to_string()]),
+            answer("www.example.com", QueryType::AAAA)
+        );
+        assert_eq!(Some(Vec::new()), answer("db.example.com", QueryType::AAAA));
+        assert_eq!(Some(Vec::new()), answer("www", QueryType::MX));
+        assert_eq!(None, answer("mail.example.com", QueryType::A));
+
+        assert_eq!(
+            Some(vec!["11.2.0.192.in-addr.arpa.\t60\tIN\tPTR\tdb.example.com.".to_string()]),
+            answer("11.2.0.192.in-addr.arpa", QueryType::PTR)
+        );
+        let reverse = reverse_name("2001:db8::10".parse().unwrap());
+        assert_eq!(
+            "0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            reverse
+        );
+        assert_eq!(
+            Some(vec![format!("{}.\t60\tIN\tPTR\twww.example.com.", reverse)]),
+            answer(&reverse, QueryType::PTR)
+        );
+        assert!(hosts.answer("12.2.0.192.in-addr.arpa", QueryType::PTR).is_none());
+    }
+
+    #[test]
+    fn it_reloads_changed_files() {
+        let path = std::env::temp_dir().join(format!("dnsafe-hosts-{}", std::process::id()));
+        fs::write(&path, "192.0.2.10 www.example.com\n").unwrap();
+        let hosts = HostsFile::load(&path).unwrap();
+        assert!(!hosts.reload().unwrap());
+
+        fs::write(&path, "192.0.2.20 db.example.com\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+
+        assert!(hosts.reload().unwrap());
+        assert!(hosts.answer("www.example.com", QueryType::A).is_none());
+        assert!(hosts.answer("db.example.com", QueryType::A).is_some());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_retries_failed_reloads() {
+        let path = std::env::temp_dir().join(format!("dnsafe-hosts-retry-{}", std::process::id()));
+        fs::write(&path, "192.0.2.10 www.example.com\n").unwrap();
+        let hosts = HostsFile::load(&path).unwrap();
+
+        // Not valid UTF-8, so the file can't be read.
+        let mtime = SystemTime::now() + std::time::Duration::from_secs(10);
+        fs::write(&path, b"192.0.2.20 \xff.example.com\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(mtime).unwrap();
+        assert!(hosts.reload().is_err());
+        assert!(hosts.answer("www.example.com", QueryType::A).is_some());
+
+        // Fixed without the time changing.
+        fs::write(&path, "192.0.2.20 db.example.com\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(mtime).unwrap();
+        assert!(hosts.reload().unwrap());
+        assert!(hosts.answer("db.example.com", QueryType::A).is_some());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
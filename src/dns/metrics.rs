@@ -0,0 +1,272 @@
+//! Counters and histograms for the queries the process sends and answers
+//!
+//! Every lookup records the queries it sends to other servers here, and the
+//! server records the queries it answers. `render` writes everything in the
+//! Prometheus text format, ready to be served on a `/metrics` endpoint.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::DnsPacket;
+use crate::ExtendedErrorCode;
+
+// The upper bounds of the latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// A distribution of durations over fixed buckets.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, &bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (bucket_labels, labels) = match labels {
+            "" => (String::new(), String::new()),
+            x => (format!("{},", x), format!("{{{}}}", x)),
+        };
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, bucket_labels, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, bucket_labels, self.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct UpstreamMetrics {
+    queries: u64,
+    timeouts: u64,
+    latency: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Answered queries by type, result code and transport.
+    queries: BTreeMap<(String, String, String), u64>,
+    query_latency: Histogram,
+    upstreams: BTreeMap<SocketAddr, UpstreamMetrics>,
+    dnssec: BTreeMap<&'static str, u64>,
+}
+
+/// The metrics of the process. See `metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+/// The metrics every lookup in the process, and the server, record to.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    /// Records a query answered by the server, with the time it took.
+    pub fn record_query(&self, qtype: &str, rcode: &str, transport: &str, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (qtype.to_string(), rcode.to_string(), transport.to_lowercase());
+        *inner.queries.entry(key).or_insert(0) += 1;
+        inner.query_latency.observe(latency);
+    }
+
+    /// Records a query sent to `server`, along with its outcome.
+    pub(crate) fn record_upstream(
+        &self,
+        server: SocketAddr,
+        latency: Duration,
+        response: &Result<DnsPacket>,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let upstream = inner.upstreams.entry(server).or_default();
+        upstream.queries += 1;
+
+        let response = match *response {
+            Ok(ref x) => x,
+            Err(ref e) => {
+                if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock {
+                    upstream.timeouts += 1;
+                }
+                return;
+            }
+        };
+        upstream.latency.observe(latency);
+
+        *inner.dnssec.entry(dnssec_outcome(response)).or_insert(0) += 1;
+    }
+
+    /// Writes every metric in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let inner = self.inner.lock().unwrap();
+
+        header(out, "dnsafe_queries_total", "counter", "Queries answered.");
+        for ((qtype, rcode, transport), count) in &inner.queries {
+            let _ = writeln!(
+                out,
+                "dnsafe_queries_total{{qtype=\"{}\",rcode=\"{}\",transport=\"{}\"}} {}",
+                qtype, rcode, transport, count
+            );
+        }
+
+        header(
+            out,
+            "dnsafe_query_duration_seconds",
+            "histogram",
+            "Time taken to answer queries.",
+        );
+        inner.query_latency.render(out, "dnsafe_query_duration_seconds", "");
+
+        header(
+            out,
+            "dnsafe_upstream_queries_total",
+            "counter",
+            "Queries sent to other servers.",
+        );
+        for (server, upstream) in &inner.upstreams {
+            let _ = writeln!(
+                out,
+                "dnsafe_upstream_queries_total{{server=\"{}\"}} {}",
+                server, upstream.queries
+            );
+        }
+
+        header(
+            out,
+            "dnsafe_upstream_timeouts_total",
+            "counter",
+            "Queries to other servers that timed out.",
+        );
+        for (server, upstream) in &inner.upstreams {
+            let _ = writeln!(
+                out,
+                "dnsafe_upstream_timeouts_total{{server=\"{}\"}} {}",
+                server, upstream.timeouts
+            );
+        }
+
+        header(
+            out,
+            "dnsafe_upstream_duration_seconds",
+            "histogram",
+            "Time taken by other servers to respond.",
+        );
+        for (server, upstream) in &inner.upstreams {
+            let labels = format!("server=\"{}\"", server);
+            upstream.latency.render(out, "dnsafe_upstream_duration_seconds", &labels);
+        }
+
+        header(
+            out,
+            "dnsafe_dnssec_responses_total",
+            "counter",
+            "Responses from other servers by their DNSSEC status.",
+        );
+        for (outcome, count) in &inner.dnssec {
+            let _ = writeln!(
+                out,
+                "dnsafe_dnssec_responses_total{{outcome=\"{}\"}} {}",
+                outcome, count
+            );
+        }
+    }
+}
+
+// Writes the help and type lines introducing a metric.
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+// We don't validate signatures ourselves, so the outcome is what the server
+// reports: `secure` if it set AD, `bogus` or `indeterminate` if it said so
+// with an extended error, and `insecure` otherwise.
+fn dnssec_outcome(response: &DnsPacket) -> &'static str {
+    if response.header.authed_data {
+        return "secure";
+    }
+
+    for (info_code, _) in response.extended_errors() {
+        match info_code {
+            ExtendedErrorCode::DnssecIndeterminate => return "indeterminate",
+            ExtendedErrorCode::DnssecBogus
+            | ExtendedErrorCode::SignatureExpired
+            | ExtendedErrorCode::SignatureNotYetValid
+            | ExtendedErrorCode::DnskeyMissing
+            | ExtendedErrorCode::RrsigsMissing
+            | ExtendedErrorCode::NoZoneKeyBitSet
+            | ExtendedErrorCode::NsecMissing => return "bogus",
+            _ => {}
+        }
+    }
+
+    "insecure"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error;
+
+    use super::*;
+
+    #[test]
+    fn it_renders_histograms() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "server=\"a\"");
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!("latency_bucket{server=\"a\",le=\"0.001\"} 0", lines[0]);
+        assert_eq!("latency_bucket{server=\"a\",le=\"0.005\"} 1", lines[2]);
+        assert_eq!("latency_bucket{server=\"a\",le=\"+Inf\"} 2", lines[12]);
+        assert_eq!("latency_count{server=\"a\"} 2", lines[14]);
+    }
+
+    #[test]
+    fn it_counts_upstream_outcomes() {
+        let metrics = Metrics::default();
+        let server = "192.0.2.53:53".parse().unwrap();
+
+        let mut response = DnsPacket::new();
+        response.header.authed_data = true;
+        metrics.record_upstream(server, Duration::from_millis(20), &Ok(response));
+        let timeout = Err(Error::new(ErrorKind::TimedOut, "Lookup timed out"));
+        metrics.record_upstream(server, Duration::from_secs(5), &timeout);
+        metrics.record_query("A", "NOERROR", "UDP", Duration::from_millis(25));
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+
+        assert!(out.contains("dnsafe_upstream_queries_total{server=\"192.0.2.53:53\"} 2\n"));
+        assert!(out.contains("dnsafe_upstream_timeouts_total{server=\"192.0.2.53:53\"} 1\n"));
+        assert!(out.contains(
+            "dnsafe_upstream_duration_seconds_count{server=\"192.0.2.53:53\"} 1\n"
+        ));
+        assert!(out.contains("dnsafe_dnssec_responses_total{outcome=\"secure\"} 1\n"));
+        assert!(out.contains(
+            "dnsafe_queries_total{qtype=\"A\",rcode=\"NOERROR\",transport=\"udp\"} 1\n"
+        ));
+    }
+}
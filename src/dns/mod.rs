@@ -1,19 +1,45 @@
 mod dns_header;
+mod opcode;
 mod result_code;
 mod query_type;
 mod dns_question;
 mod dns_record;
 mod dns_packet;
+pub mod resolve;
+pub mod dnstap;
+mod edns_option;
+mod extended_error;
 mod buffer;
+mod cache;
+pub mod metrics;
+mod client_subnet;
+mod cookie;
+mod acl;
+mod rate_limit;
+mod hosts;
+mod policy;
+mod zone;
 
 // pub use self::byte_packet_buffer::BytePacketBuffer;
-pub use self::buffer::{BytePacketBuffer, PacketBuffer};
+pub use self::buffer::{BytePacketBuffer, PacketBuffer, StreamPacketBuffer, VectorPacketBuffer};
+pub use self::acl::{AccessControl, AccessList};
+pub use self::cache::Cache;
+pub use self::client_subnet::ClientSubnetConfig;
+pub use self::hosts::HostsFile;
+pub use self::policy::{Policy, PolicyAction, PolicyFormat, PolicyMatch, PolicySource,
+                       PolicyTrigger, PolicyZone};
+pub use self::rate_limit::{RateLimitAction, RateLimitConfig, RateLimiter, ResponseKind};
+pub use self::cookie::{CookieJar, CookieStatus, ServerCookieSecret};
 pub use self::dns_header::DnsHeader;
+pub use self::opcode::Opcode;
 pub use self::result_code::ResultCode;
 pub use self::query_type::QueryType;
 pub use self::dns_record::DnsRecord;
 pub use self::dns_packet::DnsPacket;
 pub use self::dns_question::DnsQuestion;
+pub use self::edns_option::EdnsOption;
+pub use self::extended_error::ExtendedErrorCode;
+pub use self::zone::{parse_zone, parse_zone_file, Zone, ZoneStore};
 
 #[cfg(test)]
 mod test {
@@ -24,7 +50,8 @@ mod test {
     fn it_parses_a_request() {
         let mut input = Cursor::new(include_bytes!("../../tests/query_packet.txt").to_vec());
         let mut buffer = BytePacketBuffer::new();
-        input.read(&mut buffer.buf).unwrap();
+        let len = input.read(&mut buffer.buf).unwrap();
+        assert!(len > 0);
         let record = DnsPacket::from_buffer(&mut buffer).unwrap();
         assert_eq!(record.questions[0].name, "centauri.solutions");
     }
@@ -33,7 +60,8 @@ mod test {
     fn it_parses_a_response() {
         let mut input = Cursor::new(include_bytes!("../../tests/response_packet.txt").to_vec());
         let mut buffer = BytePacketBuffer::new();
-        input.read(&mut buffer.buf).unwrap();
+        let len = input.read(&mut buffer.buf).unwrap();
+        assert!(len > 0);
         let record = DnsPacket::from_buffer(&mut buffer).unwrap();
         assert_eq!(record.questions[0].name, "centauri.solutions");
         assert_eq!(
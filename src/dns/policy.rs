@@ -0,0 +1,773 @@
+//! Response policies: blocklists and Response Policy Zones
+//!
+//! A policy is made up of zones, each loaded from a file in one of three
+//! formats:
+//!
+//! * a hosts file, where names listed with `0.0.0.0`, `127.0.0.1`, `::` or
+//!   `::1` don't exist, and names listed with any other address resolve to it,
+//! * a list of domains, one per line, that don't exist along with every name
+//!   below them,
+//! * a Response Policy Zone (RPZ) in zone file format, where the owner names
+//!   are triggers and the records say what to do when they fire. `CNAME .`
+//!   answers NXDOMAIN, `CNAME *.` answers NODATA, `CNAME rpz-passthru.` lets
+//!   the answer through, `CNAME rpz-drop.` drops the query, and any other
+//!   records are answered instead of the real ones.
+//!
+//! RPZ triggers are query names (`bad.example` or `*.bad.example`), addresses
+//! in answers (`24.0.2.0.192.rpz-ip` for `192.0.2.0/24`, with `zz` standing
+//! for a run of zero groups in IPv6 addresses) and the names of the name
+//! servers an answer came from (`ns.bad.example.rpz-nsdname`). Query names are
+//! checked before anything is resolved, and the other triggers once the answer
+//! is in. The first zone with a matching trigger decides.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use super::client_subnet::{subnet_contains, truncate};
+use super::hosts::parse_hosts;
+use crate::parse_zone;
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::ExtendedErrorCode;
+use crate::QueryType;
+use crate::ResultCode;
+
+// The TTL of the records answered for names in hosts files.
+const HOSTS_TTL: u32 = 300;
+
+/// The format of a policy file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PolicyFormat {
+    Hosts,
+    Domains,
+    Rpz,
+}
+
+impl fmt::Display for PolicyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyFormat::Hosts => write!(f, "hosts"),
+            PolicyFormat::Domains => write!(f, "domains"),
+            PolicyFormat::Rpz => write!(f, "rpz"),
+        }
+    }
+}
+
+impl FromStr for PolicyFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PolicyFormat> {
+        match s {
+            "hosts" => Ok(PolicyFormat::Hosts),
+            "domains" => Ok(PolicyFormat::Domains),
+            "rpz" => Ok(PolicyFormat::Rpz),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown policy format: {}", s),
+            )),
+        }
+    }
+}
+
+/// A file to load a policy zone from. The name identifies the zone in logs
+/// and extended errors, and is the origin of an RPZ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicySource {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: PolicyFormat,
+}
+
+/// What to do with a query once a trigger fires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// Answer that the name doesn't exist.
+    Nxdomain,
+    /// Answer that the name has no records of the type asked for.
+    Nodata,
+    /// Don't answer at all.
+    Drop,
+    /// Answer normally, skipping any other triggers.
+    Passthru,
+    /// Answer with these records, whose names are replaced by the query name.
+    LocalData(Vec<DnsRecord>),
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyAction::Nxdomain => write!(f, "nxdomain"),
+            PolicyAction::Nodata => write!(f, "nodata"),
+            PolicyAction::Drop => write!(f, "drop"),
+            PolicyAction::Passthru => write!(f, "passthru"),
+            PolicyAction::LocalData(_) => write!(f, "local-data"),
+        }
+    }
+}
+
+/// The kinds of triggers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PolicyTrigger {
+    Qname,
+    ResponseIp,
+    NsName,
+}
+
+impl fmt::Display for PolicyTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyTrigger::Qname => write!(f, "qname"),
+            PolicyTrigger::ResponseIp => write!(f, "response-ip"),
+            PolicyTrigger::NsName => write!(f, "ns-name"),
+        }
+    }
+}
+
+/// A trigger that fired, and what it asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyMatch {
+    /// The name of the zone the trigger is in.
+    pub zone: String,
+    pub trigger: PolicyTrigger,
+    pub action: PolicyAction,
+}
+
+impl PolicyMatch {
+    /// Rewrites `packet`, the response to a query for `qname` and `qtype`,
+    /// according to the action. Dropping and passing through are up to the
+    /// caller, and leave the packet alone.
+    pub fn apply(&self, qname: &str, qtype: QueryType, packet: &mut DnsPacket) {
+        let records = match self.action {
+            PolicyAction::Drop | PolicyAction::Passthru => return,
+            PolicyAction::Nxdomain | PolicyAction::Nodata => Vec::new(),
+            PolicyAction::LocalData(ref records) => {
+                // A CNAME can't share its name with anything else.
+                let cname = records.iter().find(|x| x.get_querytype() == QueryType::CNAME);
+                records
+                    .iter()
+                    .filter(|x| match cname {
+                        Some(cname) => x == &cname,
+                        None => x.get_querytype() == qtype,
+                    })
+                    .cloned()
+                    .map(|mut x| {
+                        x.set_domain(qname);
+                        x
+                    })
+                    .collect()
+            }
+        };
+
+        packet.header.authoritative_answer = false;
+        packet.header.rescode = match self.action {
+            PolicyAction::Nxdomain => ResultCode::NXDOMAIN,
+            _ => ResultCode::NOERROR,
+        };
+        packet.answers = records;
+        packet.authorities.clear();
+        packet
+            .resources
+            .retain(|rec| matches!(*rec, DnsRecord::OPT { .. }));
+
+        match self.action {
+            PolicyAction::LocalData(_) => packet.add_extended_error(
+                ExtendedErrorCode::ForgedAnswer,
+                &format!("rewritten by policy {}", self.zone),
+            ),
+            _ => packet.add_extended_error(
+                ExtendedErrorCode::Blocked,
+                &format!("blocked by policy {}", self.zone),
+            ),
+        }
+    }
+}
+
+/// The triggers loaded from a single file.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyZone {
+    name: String,
+    qnames: HashMap<String, PolicyAction>,
+    // Triggers for every name below the key.
+    wildcards: HashMap<String, PolicyAction>,
+    ips: Vec<(IpAddr, u8, PolicyAction)>,
+    ns_names: HashMap<String, PolicyAction>,
+    ns_wildcards: HashMap<String, PolicyAction>,
+}
+
+impl PolicyZone {
+    /// Reads a zone from its file.
+    pub fn load(source: &PolicySource) -> Result<PolicyZone> {
+        let data = fs::read_to_string(&source.path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", source.path.display(), e)))?;
+
+        PolicyZone::parse(&source.name, &data, source.format)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", source.path.display(), e)))
+    }
+
+    /// Parses the contents of a file in `format`.
+    pub fn parse(name: &str, data: &str, format: PolicyFormat) -> Result<PolicyZone> {
+        let mut zone = PolicyZone {
+            name: normalize(name),
+            ..PolicyZone::default()
+        };
+        match format {
+            PolicyFormat::Hosts => zone.parse_hosts(data),
+            PolicyFormat::Domains => zone.parse_domains(data),
+            PolicyFormat::Rpz => zone.parse_rpz(data)?,
+        }
+
+        Ok(zone)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of triggers in the zone.
+    pub fn len(&self) -> usize {
+        self.qnames.len()
+            + self.wildcards.len()
+            + self.ips.len()
+            + self.ns_names.len()
+            + self.ns_wildcards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Names without a dot, such as `localhost`, are left alone so that a
+    // blocklist can't break the machine it runs on.
+    fn parse_hosts(&mut self, data: &str) {
+        for entry in parse_hosts(data) {
+            let blocked = entry.addr.is_unspecified() || entry.addr.is_loopback();
+            for name in entry.names.iter().filter(|x| x.contains('.')) {
+                let record = match entry.addr {
+                    _ if blocked => {
+                        self.qnames.insert(name.clone(), PolicyAction::Nxdomain);
+                        continue;
+                    }
+                    IpAddr::V4(addr) => DnsRecord::A {
+                        domain: name.clone(),
+                        addr,
+                        ttl: HOSTS_TTL,
+                    },
+                    IpAddr::V6(addr) => DnsRecord::AAAA {
+                        domain: name.clone(),
+                        addr,
+                        ttl: HOSTS_TTL,
+                    },
+                };
+                add_local_data(&mut self.qnames, name, record);
+            }
+        }
+    }
+
+    fn parse_domains(&mut self, data: &str) {
+        for line in data.lines() {
+            let line = match line.find('#') {
+                Some(x) => &line[..x],
+                None => line,
+            };
+            for name in line.split_whitespace() {
+                let name = normalize(name.trim_start_matches("*."));
+                if name.is_empty() {
+                    continue;
+                }
+                self.wildcards.insert(name.clone(), PolicyAction::Nxdomain);
+                self.qnames.insert(name, PolicyAction::Nxdomain);
+            }
+        }
+    }
+
+    fn parse_rpz(&mut self, data: &str) -> Result<()> {
+        let suffix = format!(".{}", self.name);
+        for record in parse_zone(data, &self.name)? {
+            let domain = record.get_domain().to_string();
+            // The SOA and NS records at the apex only make it a valid zone.
+            let trigger = match domain.strip_suffix(&suffix) {
+                Some(x) => x,
+                None if domain == self.name => continue,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} is outside of zone {}", domain, self.name),
+                    ))
+                }
+            };
+
+            let action = match record {
+                DnsRecord::CNAME { ref host, .. } if host.is_empty() => PolicyAction::Nxdomain,
+                DnsRecord::CNAME { ref host, .. } if host == "*" => PolicyAction::Nodata,
+                DnsRecord::CNAME { ref host, .. } if host == "rpz-passthru" => {
+                    PolicyAction::Passthru
+                }
+                DnsRecord::CNAME { ref host, .. } if host == "rpz-drop" => PolicyAction::Drop,
+                _ => PolicyAction::LocalData(vec![record.clone()]),
+            };
+
+            if let Some(ip) = trigger.strip_suffix(".rpz-ip") {
+                match parse_ip_trigger(ip) {
+                    Some((addr, prefix)) => self.ips.push((addr, prefix, action)),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid address trigger: {}", trigger),
+                        ))
+                    }
+                }
+            } else if let Some(ns_name) = trigger.strip_suffix(".rpz-nsdname") {
+                match ns_name.strip_prefix("*.") {
+                    Some(x) => add_action(&mut self.ns_wildcards, x, action),
+                    None => add_action(&mut self.ns_names, ns_name, action),
+                }
+            } else if trigger.ends_with(".rpz-client-ip") || trigger.ends_with(".rpz-nsip") {
+                debug!(zone = self.name.as_str(), trigger; "Skipping unsupported trigger");
+            } else {
+                match trigger.strip_prefix("*.") {
+                    Some(x) => add_action(&mut self.wildcards, x, action),
+                    None => add_action(&mut self.qnames, trigger, action),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_qname(&self, qname: &str) -> Option<&PolicyAction> {
+        lookup_name(&self.qnames, &self.wildcards, qname)
+    }
+
+    fn check_ns_name(&self, name: &str) -> Option<&PolicyAction> {
+        lookup_name(&self.ns_names, &self.ns_wildcards, name)
+    }
+
+    // The most specific network containing `addr` decides.
+    fn check_ip(&self, addr: IpAddr) -> Option<&PolicyAction> {
+        self.ips
+            .iter()
+            .filter(|&&(net, prefix, _)| subnet_contains(net, prefix, addr))
+            .max_by_key(|&&(_, prefix, _)| prefix)
+            .map(|(_, _, action)| action)
+    }
+}
+
+// A zone along with where it came from, so that it can be reloaded.
+struct LoadedZone {
+    source: Option<PolicySource>,
+    modified: Option<SystemTime>,
+    zone: Arc<PolicyZone>,
+}
+
+/// The zones making up a policy, in order of precedence.
+pub struct Policy {
+    zones: RwLock<Vec<LoadedZone>>,
+}
+
+impl Policy {
+    /// A policy made of zones that have already been parsed, and therefore
+    /// can't be reloaded.
+    pub fn new(zones: Vec<PolicyZone>) -> Policy {
+        let zones = zones
+            .into_iter()
+            .map(|zone| LoadedZone {
+                source: None,
+                modified: None,
+                zone: Arc::new(zone),
+            })
+            .collect();
+
+        Policy {
+            zones: RwLock::new(zones),
+        }
+    }
+
+    /// Loads a policy from files.
+    pub fn load(sources: Vec<PolicySource>) -> Result<Policy> {
+        let mut zones = Vec::new();
+        for source in sources {
+            let modified = modified(&source);
+            let zone = PolicyZone::load(&source)?;
+            info!(zone = zone.name(), triggers = zone.len(); "Loaded policy zone");
+            zones.push(LoadedZone {
+                source: Some(source),
+                modified,
+                zone: Arc::new(zone),
+            });
+        }
+
+        Ok(Policy {
+            zones: RwLock::new(zones),
+        })
+    }
+
+    /// Reloads the zones whose files have changed since they were loaded,
+    /// returning how many were. A zone that fails to load keeps its previous
+    /// triggers until the file is fixed.
+    pub fn reload(&self) -> usize {
+        let changed: Vec<(usize, PolicySource, Option<SystemTime>)> = {
+            let zones = self.zones.read().unwrap();
+            zones
+                .iter()
+                .enumerate()
+                .filter_map(|(i, loaded)| {
+                    let source = loaded.source.as_ref()?;
+                    let modified = modified(source);
+                    if modified.is_some() && modified != loaded.modified {
+                        Some((i, source.clone(), modified))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        // The files are read without holding the lock, so queries carry on
+        // with the old triggers in the meantime.
+        let mut reloaded = 0;
+        for (i, source, modified) in changed {
+            let zone = match PolicyZone::load(&source) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!(zone = source.name.as_str(), error:% = e; "Failed to reload policy zone");
+                    self.zones.write().unwrap()[i].modified = modified;
+                    continue;
+                }
+            };
+            info!(zone = zone.name(), triggers = zone.len(); "Reloaded policy zone");
+
+            let mut zones = self.zones.write().unwrap();
+            zones[i].modified = modified;
+            zones[i].zone = Arc::new(zone);
+            reloaded += 1;
+        }
+
+        reloaded
+    }
+
+    /// The zones of the policy, in order of precedence.
+    pub fn zones(&self) -> Vec<Arc<PolicyZone>> {
+        let zones = self.zones.read().unwrap();
+        zones.iter().map(|x| x.zone.clone()).collect()
+    }
+
+    /// Checks the name of a query, before it's resolved.
+    pub fn check_qname(&self, qname: &str) -> Option<PolicyMatch> {
+        let qname = normalize(qname);
+        self.zones().iter().find_map(|zone| {
+            zone.check_qname(&qname).map(|action| PolicyMatch {
+                zone: zone.name.clone(),
+                trigger: PolicyTrigger::Qname,
+                action: action.clone(),
+            })
+        })
+    }
+
+    /// Checks a resolved answer: the addresses in it, and the names of the
+    /// name servers it came from, given as `name_servers` by those who
+    /// followed the referrals and as found in the authority section.
+    pub fn check_response(
+        &self,
+        response: &DnsPacket,
+        name_servers: &[String],
+    ) -> Option<PolicyMatch> {
+        let addrs: Vec<IpAddr> = response
+            .answers
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect();
+
+        let mut ns_names: Vec<String> = name_servers.iter().map(|x| normalize(x)).collect();
+        ns_names.extend(response.authorities.iter().filter_map(|rec| match *rec {
+            DnsRecord::NS { ref host, .. } => Some(normalize(host)),
+            _ => None,
+        }));
+
+        for zone in self.zones() {
+            let found = addrs
+                .iter()
+                .find_map(|&addr| zone.check_ip(addr))
+                .map(|action| (PolicyTrigger::ResponseIp, action))
+                .or_else(|| {
+                    ns_names
+                        .iter()
+                        .find_map(|name| zone.check_ns_name(name))
+                        .map(|action| (PolicyTrigger::NsName, action))
+                });
+
+            if let Some((trigger, action)) = found {
+                return Some(PolicyMatch {
+                    zone: zone.name.clone(),
+                    trigger,
+                    action: action.clone(),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+// Records for a name accumulate, while any other action replaces them.
+fn add_action(actions: &mut HashMap<String, PolicyAction>, name: &str, action: PolicyAction) {
+    match (actions.get_mut(name), action) {
+        (Some(&mut PolicyAction::LocalData(ref mut records)), PolicyAction::LocalData(new)) => {
+            records.extend(new);
+        }
+        (_, action) => {
+            actions.insert(name.to_string(), action);
+        }
+    }
+}
+
+fn add_local_data(actions: &mut HashMap<String, PolicyAction>, name: &str, record: DnsRecord) {
+    // A name that is blocked stays blocked, whatever else the file says.
+    if actions.get(name) == Some(&PolicyAction::Nxdomain) {
+        return;
+    }
+    add_action(actions, name, PolicyAction::LocalData(vec![record]));
+}
+
+// An exact match beats a wildcard, and a wildcard closer to the name beats
+// one further up.
+fn lookup_name<'a>(
+    exact: &'a HashMap<String, PolicyAction>,
+    wildcards: &'a HashMap<String, PolicyAction>,
+    name: &str,
+) -> Option<&'a PolicyAction> {
+    if let Some(action) = exact.get(name) {
+        return Some(action);
+    }
+
+    let mut name = name;
+    while let Some(i) = name.find('.') {
+        name = &name[i + 1..];
+        if let Some(action) = wildcards.get(name) {
+            return Some(action);
+        }
+    }
+
+    None
+}
+
+// Parses the network of an address trigger, such as `24.0.2.0.192` for
+// `192.0.2.0/24` or `48.zz.1.db8.2001` for `2001:db8:1::/48`.
+fn parse_ip_trigger(trigger: &str) -> Option<(IpAddr, u8)> {
+    let mut labels = trigger.split('.');
+    let prefix: u8 = labels.next()?.parse().ok()?;
+    let labels: Vec<&str> = labels.rev().collect();
+
+    let octets: Vec<u8> = labels.iter().filter_map(|x| x.parse().ok()).collect();
+    let addr = if labels.len() == 4 && octets.len() == 4 {
+        if prefix > 32 {
+            return None;
+        }
+        IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+    } else {
+        if prefix > 128 || labels.len() > 8 {
+            return None;
+        }
+        let mut groups = Vec::new();
+        for label in &labels {
+            if *label == "zz" {
+                groups.extend(std::iter::repeat_n(0, 9 - labels.len()));
+            } else {
+                groups.push(u16::from_str_radix(label, 16).ok()?);
+            }
+        }
+        if groups.len() != 8 {
+            return None;
+        }
+        let mut segments = [0; 8];
+        segments.copy_from_slice(&groups);
+        IpAddr::V6(Ipv6Addr::from(segments))
+    };
+
+    Some((truncate(addr, prefix), prefix))
+}
+
+// When each file was last modified, to tell whether it needs reloading.
+fn modified(source: &PolicySource) -> Option<SystemTime> {
+    fs::metadata(&source.path).and_then(|x| x.modified()).ok()
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Trace, TraceEvent};
+
+    const RPZ: &str = "$TTL 60\n\
+                       @ SOA ns.rpz.local. admin.rpz.local. 1 3600 600 86400 60\n\
+                       @ NS ns.rpz.local.\n\
+                       bad.example CNAME .\n\
+                       *.bad.example CNAME .\n\
+                       empty.example CNAME *.\n\
+                       ok.bad.example CNAME rpz-passthru.\n\
+                       silent.example CNAME rpz-drop.\n\
+                       portal.example A 192.0.2.80\n\
+                       portal.example AAAA 2001:db8::80\n\
+                       24.0.2.0.198.rpz-ip CNAME .\n\
+                       32.1.2.0.198.rpz-ip CNAME rpz-passthru.\n\
+                       48.zz.1.db8.2001.rpz-ip CNAME *.\n\
+                       ns.evil.example.rpz-nsdname CNAME .\n";
+
+    fn rpz() -> Policy {
+        Policy::new(vec![PolicyZone::parse("rpz.local", RPZ, PolicyFormat::Rpz).unwrap()])
+    }
+
+    fn action(policy: &Policy, qname: &str) -> Option<PolicyAction> {
+        policy.check_qname(qname).map(|x| x.action)
+    }
+
+    #[test]
+    fn it_matches_query_names() {
+        let policy = rpz();
+
+        assert_eq!(Some(PolicyAction::Nxdomain), action(&policy, "bad.example"));
+        assert_eq!(Some(PolicyAction::Nxdomain), action(&policy, "www.Bad.Example."));
+        assert_eq!(Some(PolicyAction::Passthru), action(&policy, "ok.bad.example"));
+        assert_eq!(Some(PolicyAction::Nodata), action(&policy, "empty.example"));
+        assert_eq!(Some(PolicyAction::Drop), action(&policy, "silent.example"));
+        assert_eq!(None, action(&policy, "www.empty.example"));
+        assert_eq!(None, action(&policy, "example"));
+
+        let found = policy.check_qname("portal.example").unwrap();
+        assert_eq!(("rpz.local", PolicyTrigger::Qname), (found.zone.as_str(), found.trigger));
+        match found.action {
+            PolicyAction::LocalData(ref records) => assert_eq!(2, records.len()),
+            ref x => panic!("unexpected action: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_matches_responses() {
+        let policy = rpz();
+        let response = |addr: &str| {
+            let mut packet = DnsPacket::new();
+            let record = match addr.parse().unwrap() {
+                IpAddr::V4(addr) => DnsRecord::A {
+                    domain: "www.example".to_string(),
+                    addr,
+                    ttl: 60,
+                },
+                IpAddr::V6(addr) => DnsRecord::AAAA {
+                    domain: "www.example".to_string(),
+                    addr,
+                    ttl: 60,
+                },
+            };
+            packet.answers.push(record);
+            packet
+        };
+        let check = |packet: &DnsPacket, name_servers: &[String]| {
+            policy
+                .check_response(packet, name_servers)
+                .map(|x| (x.trigger, x.action))
+        };
+        let none = Vec::new();
+
+        assert_eq!(
+            Some((PolicyTrigger::ResponseIp, PolicyAction::Nxdomain)),
+            check(&response("198.0.2.7"), &none)
+        );
+        // The most specific network wins.
+        assert_eq!(
+            Some((PolicyTrigger::ResponseIp, PolicyAction::Passthru)),
+            check(&response("198.0.2.1"), &none)
+        );
+        assert_eq!(
+            Some((PolicyTrigger::ResponseIp, PolicyAction::Nodata)),
+            check(&response("2001:db8:1::5"), &none)
+        );
+        assert_eq!(None, check(&response("2001:db8:2::5"), &none));
+
+        let mut trace = Trace::new();
+        trace.push(TraceEvent::Referral {
+            zone: "example".to_string(),
+            name_server: "ns.evil.example".to_string(),
+            glue: None,
+        });
+        assert_eq!(
+            Some((PolicyTrigger::NsName, PolicyAction::Nxdomain)),
+            check(&response("192.0.2.1"), &trace.name_servers())
+        );
+    }
+
+    #[test]
+    fn it_reads_blocklists() {
+        let hosts = "0.0.0.0 ads.example tracker.example\n\
+                     127.0.0.1 localhost\n\
+                     192.0.2.10 intranet.example\n";
+        let domains = "# blocked with every subdomain\n\
+                       ads.example\n\
+                       *.malware.example\n";
+        let policy = Policy::new(vec![
+            PolicyZone::parse("hosts", hosts, PolicyFormat::Hosts).unwrap(),
+            PolicyZone::parse("domains", domains, PolicyFormat::Domains).unwrap(),
+        ]);
+
+        assert_eq!(3, policy.zones()[0].len());
+        assert_eq!(None, action(&policy, "localhost"));
+        assert_eq!("hosts", policy.check_qname("ads.example").unwrap().zone);
+        assert_eq!("domains", policy.check_qname("www.ads.example").unwrap().zone);
+        assert_eq!(Some(PolicyAction::Nxdomain), action(&policy, "malware.example"));
+        assert_eq!(Some(PolicyAction::Nxdomain), action(&policy, "a.b.malware.example"));
+
+        let found = policy.check_qname("intranet.example").unwrap();
+        let mut packet = DnsPacket::new();
+        found.apply("intranet.example", QueryType::A, &mut packet);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(1, packet.answers.len());
+
+        let mut packet = DnsPacket::new();
+        found.apply("intranet.example", QueryType::AAAA, &mut packet);
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            vec![(ExtendedErrorCode::ForgedAnswer, "rewritten by policy hosts")],
+            packet.extended_errors()
+        );
+    }
+
+    #[test]
+    fn it_reloads_changed_files() {
+        let path = std::env::temp_dir().join(format!("dnsafe-policy-{}", std::process::id()));
+        fs::write(&path, "ads.example\n").unwrap();
+        let policy = Policy::load(vec![PolicySource {
+            name: "blocklist".to_string(),
+            path: path.clone(),
+            format: PolicyFormat::Domains,
+        }])
+        .unwrap();
+        assert_eq!(0, policy.reload());
+
+        // Make sure the change shows up in the modification time, whatever
+        // its resolution.
+        fs::write(&path, "tracker.example\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(1, policy.reload());
+        assert_eq!(None, action(&policy, "ads.example"));
+        assert_eq!(Some(PolicyAction::Nxdomain), action(&policy, "tracker.example"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
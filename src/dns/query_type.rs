@@ -1,11 +1,18 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
+    PTR,   // 12
     MX,    // 15
     AAAA,  // 28
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -15,8 +22,11 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
         }
     }
 
@@ -25,9 +35,75 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
+
+impl fmt::Display for QueryType {
+    // Types without a mnemonic use the generic syntax of RFC 3597.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+            QueryType::A => write!(f, "A"),
+            QueryType::NS => write!(f, "NS"),
+            QueryType::CNAME => write!(f, "CNAME"),
+            QueryType::SOA => write!(f, "SOA"),
+            QueryType::PTR => write!(f, "PTR"),
+            QueryType::MX => write!(f, "MX"),
+            QueryType::AAAA => write!(f, "AAAA"),
+            QueryType::OPT => write!(f, "OPT"),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = Error;
+
+    /// Parses a type mnemonic, ignoring case, or the generic `TYPEn` syntax.
+    fn from_str(s: &str) -> Result<QueryType, Error> {
+        let name = s.to_uppercase();
+        let qtype = match name.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "MX" => QueryType::MX,
+            "AAAA" => QueryType::AAAA,
+            "OPT" => QueryType::OPT,
+            _ => match name.strip_prefix("TYPE").map(str::parse) {
+                Some(Ok(num)) => QueryType::from_num(num),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown type {}", s),
+                    ))
+                }
+            },
+        };
+
+        Ok(qtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_query_types() {
+        assert_eq!(QueryType::AAAA, "aaaa".parse().unwrap());
+        assert_eq!(QueryType::SOA, "SOA".parse().unwrap());
+        assert_eq!(QueryType::PTR, "ptr".parse().unwrap());
+        assert_eq!(QueryType::UNKNOWN(16), "TYPE16".parse().unwrap());
+        assert_eq!(QueryType::MX, "TYPE15".parse().unwrap());
+        assert!("TXT".parse::<QueryType>().is_err());
+        assert!("TYPE".parse::<QueryType>().is_err());
+    }
+}
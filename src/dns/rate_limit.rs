@@ -0,0 +1,249 @@
+//! Response rate limiting
+//!
+//! Spoofing the source of a UDP query turns any server into a reflector, so
+//! the responses sent to each client network are limited. Every network gets
+//! a bucket of credits per kind of response, refilled at the configured rate.
+//! Once a bucket runs dry, responses are dropped, except that every `slip`th
+//! one is sent truncated instead: a legitimate client then retries over TCP,
+//! which can't be spoofed, while the victim of a reflection attack only
+//! receives a fraction of small responses.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::client_subnet::truncate;
+use crate::DnsPacket;
+use crate::ResultCode;
+
+// Buckets are only pruned once there are this many, so that a handful of
+// clients never pay for it.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// The responses each client network may receive per second, of each
+    /// kind.
+    pub responses_per_second: u32,
+    /// One in this many limited responses is sent truncated rather than
+    /// dropped. Zero drops them all.
+    pub slip: u32,
+    /// The prefix lengths client networks are grouped by.
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Only logs the responses that would be limited, for tuning.
+    pub log_only: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            responses_per_second: 5,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            log_only: false,
+        }
+    }
+}
+
+/// The kinds of responses that are limited separately, so that a flood of one
+/// kind doesn't starve the others.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ResponseKind {
+    Answer,
+    Nxdomain,
+    Error,
+}
+
+impl ResponseKind {
+    pub fn of(packet: &DnsPacket) -> ResponseKind {
+        match packet.rescode() {
+            ResultCode::NOERROR => ResponseKind::Answer,
+            ResultCode::NXDOMAIN => ResponseKind::Nxdomain,
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+impl fmt::Display for ResponseKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResponseKind::Answer => write!(f, "answer"),
+            ResponseKind::Nxdomain => write!(f, "nxdomain"),
+            ResponseKind::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    Send,
+    /// Send it truncated and without records.
+    Slip,
+    Drop,
+}
+
+struct Bucket {
+    credits: f64,
+    updated: Instant,
+    // The responses limited since the bucket last ran dry.
+    limited: u32,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(IpAddr, ResponseKind), Bucket>>,
+    slipped: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            slipped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Decides what to do with a response of `kind` to `client`. In log-only
+    /// mode the decision is still made, and counted, but it's up to the
+    /// caller to send the response anyway.
+    pub fn check(&self, client: IpAddr, kind: ResponseKind) -> RateLimitAction {
+        self.check_at(client, kind, Instant::now())
+    }
+
+    /// Number of responses sent truncated.
+    pub fn slipped(&self) -> u64 {
+        self.slipped.load(Ordering::Relaxed)
+    }
+
+    /// Number of responses dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn check_at(&self, client: IpAddr, kind: ResponseKind, now: Instant) -> RateLimitAction {
+        let rate = f64::from(self.config.responses_per_second);
+        let prefix = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix.min(32),
+            IpAddr::V6(_) => self.config.ipv6_prefix.min(128),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Buckets that have refilled completely are no different from new
+            // ones.
+            buckets.retain(|_, bucket| refill(bucket, rate, now) < rate);
+        }
+
+        let bucket = buckets
+            .entry((truncate(client, prefix), kind))
+            .or_insert(Bucket {
+                credits: rate,
+                updated: now,
+                limited: 0,
+            });
+        bucket.credits = refill(bucket, rate, now);
+        bucket.updated = now;
+
+        if bucket.credits >= 1.0 {
+            bucket.credits -= 1.0;
+            bucket.limited = 0;
+            return RateLimitAction::Send;
+        }
+
+        bucket.limited += 1;
+        if self.config.slip > 0 && bucket.limited.is_multiple_of(self.config.slip) {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            RateLimitAction::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            RateLimitAction::Drop
+        }
+    }
+}
+
+// The credits of `bucket` at `now`, which never exceed a second's worth.
+fn refill(bucket: &Bucket, rate: f64, now: Instant) -> f64 {
+    let elapsed = now
+        .checked_duration_since(bucket.updated)
+        .unwrap_or(Duration::from_secs(0));
+    (bucket.credits + elapsed.as_secs_f64() * rate).min(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            responses_per_second: 2,
+            slip,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn it_limits_client_networks() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let client = "192.0.2.1".parse().unwrap();
+        let neighbour = "192.0.2.200".parse().unwrap();
+
+        let actions: Vec<RateLimitAction> = (0..5)
+            .map(|i| match i % 2 {
+                0 => limiter.check_at(client, ResponseKind::Answer, now),
+                _ => limiter.check_at(neighbour, ResponseKind::Answer, now),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                RateLimitAction::Send,
+                RateLimitAction::Send,
+                RateLimitAction::Drop,
+                RateLimitAction::Slip,
+                RateLimitAction::Drop,
+            ],
+            actions
+        );
+        assert_eq!((1, 2), (limiter.slipped(), limiter.dropped()));
+
+        // Other kinds of responses and other networks have buckets of their own.
+        let other = "198.51.100.1".parse().unwrap();
+        assert_eq!(
+            RateLimitAction::Send,
+            limiter.check_at(client, ResponseKind::Nxdomain, now)
+        );
+        assert_eq!(RateLimitAction::Send, limiter.check_at(other, ResponseKind::Answer, now));
+
+        // Credits come back over time.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(RateLimitAction::Send, limiter.check_at(client, ResponseKind::Answer, later));
+        assert_eq!(RateLimitAction::Drop, limiter.check_at(client, ResponseKind::Answer, later));
+    }
+
+    #[test]
+    fn it_drops_everything_without_slip() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let client = "2001:db8::1".parse().unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(RateLimitAction::Send, limiter.check_at(client, ResponseKind::Error, now));
+        }
+        for _ in 0..4 {
+            assert_eq!(RateLimitAction::Drop, limiter.check_at(client, ResponseKind::Error, now));
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//! Non-blocking variants of the lookups in `client`
+//!
+//! The futures are driven by `async-io`, which brings its own reactor, so they
+//! can be awaited from any executor. Each lookup only holds a socket while it
+//! waits, allowing thousands of them to be in flight without a thread each.
+
+use std::io::Result;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Instant, SystemTime};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::client::{bind_addr, name_server_addr, new_query, next_step, not_an_answer,
+                    read_response, retry_for, root_server, server_addr, tcp_message, timed_out,
+                    write_query, NextStep, QueryOptions, Retry};
+use super::trace::{Trace, Transport};
+use crate::dns::dnstap::{log_query, log_response};
+use crate::dns::metrics::metrics;
+use crate::BytePacketBuffer;
+use crate::DnstapMessageType;
+use crate::DnsPacket;
+use crate::EdnsOption;
+use crate::QueryType;
+use crate::VectorPacketBuffer;
+
+/// Async variant of `lookup`.
+///
+/// The server address has to be given as an IP address to avoid blocking, as
+/// host names are resolved through the system resolver.
+pub async fn lookup_async(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<DnsPacket> {
+    lookup_with_options_async(qname, qtype, server, &[]).await
+}
+
+/// Async variant of `lookup_with_options`.
+pub async fn lookup_with_options_async(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    options: &[EdnsOption],
+) -> Result<DnsPacket> {
+    let server = server_addr(server)?;
+    let query = QueryOptions {
+        options: options.to_vec(),
+        ..QueryOptions::default()
+    };
+
+    let mut packet = new_query(qname, qtype, &query);
+    let role = DnstapMessageType::ToolQuery;
+    udp_query(&mut packet, server, &query, role, &mut Trace::new()).await
+}
+
+/// Async variant of `lookup_with`.
+pub async fn lookup_with_async(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    query: &QueryOptions,
+) -> Result<DnsPacket> {
+    lookup_with_traced_async(qname, qtype, server, query, &mut Trace::new()).await
+}
+
+/// Async variant of `lookup_with_traced`.
+pub async fn lookup_with_traced_async(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    query: &QueryOptions,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let role = DnstapMessageType::ToolQuery;
+    lookup_at_async(qname, qtype, server_addr(server)?, query, role, trace).await
+}
+
+// Async variant of `lookup_at_with`.
+async fn lookup_at_async(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    query: &QueryOptions,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let mut packet = new_query(qname, qtype, query);
+
+    if !query.tcp {
+        let response = udp_query(&mut packet, server, query, role, trace).await?;
+        if !response.header.truncated_message {
+            return Ok(response);
+        }
+    }
+
+    with_retries(None, &mut packet, server, query, role, trace).await
+}
+
+async fn udp_query(
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let socket = Async::<UdpSocket>::bind(bind_addr(server))?;
+    with_retries(Some(&socket), packet, server, query, role, trace).await
+}
+
+// Async variant of `with_retries`, sending the query through `socket`, or
+// over a TCP connection of its own without one.
+async fn with_retries(
+    socket: Option<&Async<UdpSocket>>,
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let response = exchange(socket, packet, server, query, query.edns, role, trace).await?;
+    if !query.edns {
+        return Ok(response);
+    }
+
+    match retry_for(&response) {
+        Retry::No => Ok(response),
+        Retry::WithEdns => exchange(socket, packet, server, query, true, role, trace).await,
+        Retry::WithoutEdns => exchange(socket, packet, server, query, false, role, trace).await,
+    }
+}
+
+// Runs a single exchange of `packet` with `server`, noting it in `trace`.
+async fn exchange(
+    socket: Option<&Async<UdpSocket>>,
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let start = Instant::now();
+    let (transport, response) = match socket {
+        Some(socket) => {
+            let response = exchange_udp(socket, packet, server, query, edns, role).await;
+            (Transport::Udp, response)
+        }
+        None => {
+            let response = exchange_tcp(packet, server, query, edns, role).await;
+            (Transport::Tcp, response)
+        }
+    };
+    trace.query(packet, server, transport, start.elapsed(), &response);
+    metrics().record_upstream(server, start.elapsed(), &response);
+
+    response
+}
+
+async fn exchange_udp(
+    socket: &Async<UdpSocket>,
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+    role: DnstapMessageType,
+) -> Result<DnsPacket> {
+    let req_buffer = write_query(packet, server, query, edns)?;
+    let query_time = SystemTime::now();
+    socket
+        .send_to(&req_buffer.buf[0..req_buffer.pos], server)
+        .await?;
+
+    let local = socket.get_ref().local_addr()?;
+    let message = &req_buffer.buf[0..req_buffer.pos];
+    log_query(role, Transport::Udp, local, server, query_time, message);
+
+    let deadline = Instant::now() + query.timeout();
+    loop {
+        let mut res_buffer = BytePacketBuffer::new();
+        let received = future::or(socket.recv_from(&mut res_buffer.buf), async {
+            Timer::at(deadline).await;
+            Err(timed_out())
+        });
+        let (len, src) = received.await?;
+
+        if let Some(response) = read_response(packet, server, src, &mut res_buffer)? {
+            let message = &res_buffer.buf[0..len];
+            log_response(role, Transport::Udp, local, server, query_time, message);
+            return Ok(response);
+        }
+    }
+}
+
+async fn exchange_tcp(
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+    role: DnstapMessageType,
+) -> Result<DnsPacket> {
+    let req_buffer = write_query(packet, server, query, edns)?;
+    let message = tcp_message(&req_buffer);
+    let query_time = SystemTime::now();
+
+    // The whole exchange shares a single deadline, from connecting to reading
+    // the last byte of the response.
+    let deadline = Instant::now() + query.timeout();
+    let received = future::or(
+        async {
+            let mut stream = Async::<TcpStream>::connect(server).await?;
+            stream.write_all(&message).await?;
+
+            let local = stream.get_ref().local_addr()?;
+            log_query(role, Transport::Tcp, local, server, query_time, &message[2..]);
+
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await?;
+            let mut res_buffer = VectorPacketBuffer::new();
+            res_buffer.buffer = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut res_buffer.buffer).await?;
+
+            Ok((local, res_buffer))
+        },
+        async {
+            Timer::at(deadline).await;
+            Err(timed_out())
+        },
+    );
+    let (local, mut res_buffer) = received.await?;
+
+    match read_response(packet, server, server, &mut res_buffer)? {
+        Some(response) => {
+            let message = &res_buffer.buffer;
+            log_response(role, Transport::Tcp, local, server, query_time, message);
+            Ok(response)
+        }
+        None => Err(not_an_answer()),
+    }
+}
+
+/// Async variant of `recursive_lookup`.
+pub async fn recursive_lookup_async(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+    recursive_lookup_with_options_async(qname, qtype, &[]).await
+}
+
+/// Async variant of `recursive_lookup_with_options`.
+pub async fn recursive_lookup_with_options_async(
+    qname: &str,
+    qtype: QueryType,
+    options: &[EdnsOption],
+) -> Result<DnsPacket> {
+    recursive_lookup_traced_async(qname, qtype, options, &mut Trace::new()).await
+}
+
+/// Async variant of `recursive_lookup_traced`.
+pub async fn recursive_lookup_traced_async(
+    qname: &str,
+    qtype: QueryType,
+    options: &[EdnsOption],
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let mut server = SocketAddr::new(root_server(), 53);
+    let query = QueryOptions {
+        options: options.to_vec(),
+        ..QueryOptions::default()
+    };
+
+    loop {
+        debug!(qname, qtype:%, server:%; "Attempting lookup");
+
+        let role = DnstapMessageType::ResolverQuery;
+        let response = lookup_at_async(qname, qtype, server, &query, role, trace).await?;
+
+        let new_ns_name = match next_step(qname, &response, trace)? {
+            NextStep::Done => return Ok(response),
+            NextStep::Server(x) => {
+                server = x;
+                continue;
+            }
+            NextStep::ResolveServer(x) => x,
+        };
+
+        // The nested lookup has to be boxed, as the future would otherwise have
+        // to contain itself.
+        let mut ns_trace = Trace::new();
+        let recursive_response =
+            Box::pin(recursive_lookup_traced_async(&new_ns_name, QueryType::A, &[], &mut ns_trace))
+                .await;
+
+        match name_server_addr(new_ns_name, ns_trace, recursive_response, trace)? {
+            Some(x) => server = x,
+            None => return Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+    use std::task::Poll;
+    use std::thread;
+
+    use super::*;
+    use crate::{lookup, lookup_with_traced, DnsRecord, PacketBuffer};
+
+    // Waits for `count` queries before answering any of them with 192.0.2.1,
+    // echoing each query's cookie option like a real server would.
+    fn serve(count: usize) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..count {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+                requests.push((DnsPacket::from_buffer(&mut req_buffer).unwrap(), src));
+            }
+
+            for (request, src) in requests {
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: "192.0.2.1".parse().unwrap(),
+                    ttl: 60,
+                });
+                response.resources = request.resources.clone();
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer).unwrap();
+                let data = res_buffer.get_range(0, res_buffer.pos()).unwrap();
+                socket.send_to(data, src).unwrap();
+            }
+        });
+
+        port
+    }
+
+    // Answers queries over UDP with a truncated response, and over TCP with
+    // one too large for a datagram.
+    fn serve_truncated() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let socket = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+
+        fn response_to(request: &DnsPacket, count: u8) -> DnsPacket {
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.truncated_message = count == 0;
+            response.questions = request.questions.clone();
+            for i in 0..count {
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: Ipv4Addr::new(192, 0, 2, i),
+                    ttl: 60,
+                });
+            }
+
+            response
+        }
+
+        thread::spawn(move || loop {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response_to(&request, 0).write(&mut res_buffer).unwrap();
+            socket.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut req_buffer = VectorPacketBuffer::new();
+                req_buffer.buffer = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut req_buffer.buffer).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                response_to(&request, 50).write(&mut res_buffer).unwrap();
+                let mut message = (res_buffer.buffer.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&res_buffer.buffer);
+                stream.write_all(&message).unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    fn it_matches_the_blocking_lookup() {
+        let blocking = lookup("example.com", QueryType::A, ("127.0.0.1", serve(1))).unwrap();
+        let nonblocking = future::block_on(lookup_async(
+            "example.com",
+            QueryType::A,
+            ("127.0.0.1", serve(1)),
+        )).unwrap();
+
+        assert_eq!(blocking.answers, nonblocking.answers);
+        assert_eq!(nonblocking.get_random_a(), Some("192.0.2.1".to_string()));
+    }
+
+    #[test]
+    fn it_matches_the_blocking_lookup_of_truncated_responses() {
+        let server = ("127.0.0.1", serve_truncated());
+        let query = QueryOptions::default();
+
+        let mut blocking_trace = Trace::new();
+        let blocking =
+            lookup_with_traced("example.com", QueryType::A, server, &query, &mut blocking_trace);
+        let mut trace = Trace::new();
+        let nonblocking = future::block_on(lookup_with_traced_async(
+            "example.com",
+            QueryType::A,
+            server,
+            &query,
+            &mut trace,
+        ));
+
+        let nonblocking = nonblocking.unwrap();
+        assert_eq!(blocking.unwrap().answers, nonblocking.answers);
+        assert_eq!(50, nonblocking.answers.len());
+
+        let transports = |trace: &Trace| -> Vec<Transport> {
+            trace.queries().iter().map(|x| x.transport).collect()
+        };
+        assert_eq!(vec![Transport::Udp, Transport::Tcp], transports(&trace));
+        assert_eq!(transports(&blocking_trace), transports(&trace));
+
+        // Without the option to retry, both return the truncated response.
+        let blocking = lookup("example.com", QueryType::A, server).unwrap();
+        let nonblocking = future::block_on(lookup_async("example.com", QueryType::A, server));
+        let nonblocking = nonblocking.unwrap();
+        assert!(blocking.header.truncated_message && nonblocking.header.truncated_message);
+        assert!(blocking.answers.is_empty() && nonblocking.answers.is_empty());
+    }
+
+    #[test]
+    fn it_runs_lookups_concurrently() {
+        // The server only answers once every query has arrived, so this can
+        // only succeed if all lookups are waiting at the same time.
+        let port = serve(100);
+
+        let names = (0..100)
+            .map(|i| format!("host{}.example.com", i))
+            .collect::<Vec<_>>();
+        let mut lookups = names
+            .iter()
+            .map(|name| Box::pin(lookup_async(name, QueryType::A, ("127.0.0.1", port))))
+            .collect::<Vec<_>>();
+        let mut results = lookups.iter().map(|_| None).collect::<Vec<_>>();
+
+        future::block_on(future::poll_fn(|cx| {
+            let mut pending = false;
+            for (lookup, result) in lookups.iter_mut().zip(results.iter_mut()) {
+                if result.is_none() {
+                    match lookup.as_mut().poll(cx) {
+                        Poll::Ready(x) => *result = Some(x),
+                        Poll::Pending => pending = true,
+                    }
+                }
+            }
+
+            if pending {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }));
+
+        for (name, result) in names.iter().zip(results) {
+            let response = result.unwrap().unwrap();
+            assert_eq!(&response.questions[0].name, name);
+        }
+    }
+
+    #[test]
+    fn its_futures_can_move_between_threads() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        assert_send(&lookup_async("example.com", QueryType::A, ("127.0.0.1", 53)));
+        assert_send(&recursive_lookup_async("example.com", QueryType::A));
+    }
+}
@@ -0,0 +1,754 @@
+//! Blocking lookups over UDP and TCP, and the pieces shared with the async variant
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::trace::{Trace, TraceEvent, Transport};
+use crate::dns::dnstap::{log_query, log_response};
+use crate::dns::metrics::metrics;
+use crate::DnstapMessageType;
+use crate::BytePacketBuffer;
+use crate::CookieJar;
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::EdnsOption;
+use crate::PacketBuffer;
+use crate::QueryType;
+use crate::ResultCode;
+use crate::VectorPacketBuffer;
+
+// How long lookups wait for a response from a server, in milliseconds.
+static LOOKUP_TIMEOUT: AtomicU64 = AtomicU64::new(5000);
+
+/// Sets how long every lookup in the process, blocking or not, waits for a
+/// response from a server. Defaults to five seconds.
+pub fn set_lookup_timeout(timeout: Duration) {
+    LOOKUP_TIMEOUT.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn lookup_timeout() -> Duration {
+    Duration::from_millis(LOOKUP_TIMEOUT.load(Ordering::Relaxed))
+}
+
+// The server recursive lookups start from, `a.root-servers.net` unless changed.
+static ROOT_SERVER: RwLock<Option<IpAddr>> = RwLock::new(None);
+
+/// Sets the server every recursive lookup in the process starts from.
+pub fn set_root_server(server: IpAddr) {
+    *ROOT_SERVER.write().unwrap() = Some(server);
+}
+
+/// The server recursive lookups start from.
+pub fn root_server() -> IpAddr {
+    match *ROOT_SERVER.read().unwrap() {
+        Some(server) => server,
+        None => IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+    }
+}
+
+/// How `lookup_with` asks its question. The defaults match `lookup`.
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    /// The class of the question, IN by default.
+    pub qclass: u16,
+    /// Sets RD, asking the server to resolve the name on our behalf.
+    pub recursion_desired: bool,
+    /// Sets CD, asking a validating server to skip DNSSEC validation.
+    pub checking_disabled: bool,
+    /// Sets the DO bit, asking for DNSSEC records along with the answer.
+    pub dnssec_ok: bool,
+    /// Whether the query carries an OPT record. Without one, no cookie or
+    /// other options are sent either.
+    pub edns: bool,
+    /// Sends the query over TCP from the start, instead of only after the
+    /// UDP response turns out to be truncated.
+    pub tcp: bool,
+    /// Options sent along with our cookie.
+    pub options: Vec<EdnsOption>,
+    /// How long to wait for a response, instead of the timeout set with
+    /// `set_lookup_timeout`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            qclass: 1,
+            recursion_desired: true,
+            checking_disabled: false,
+            dnssec_ok: false,
+            edns: true,
+            tcp: false,
+            options: Vec::new(),
+            timeout: None,
+        }
+    }
+}
+
+impl QueryOptions {
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or_else(lookup_timeout)
+    }
+}
+
+// The cookies `lookup` uses towards each upstream server, shared by every lookup
+// in the process so that server cookies are remembered between queries.
+fn cookie_jar() -> &'static CookieJar {
+    static JAR: OnceLock<CookieJar> = OnceLock::new();
+    JAR.get_or_init(CookieJar::new)
+}
+
+pub fn lookup(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<DnsPacket> {
+    lookup_with_options(qname, qtype, server, &[])
+}
+
+/// Like `lookup`, but sends the given EDNS options along with our cookie.
+pub fn lookup_with_options(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    options: &[EdnsOption],
+) -> Result<DnsPacket> {
+    let role = DnstapMessageType::ToolQuery;
+    lookup_at(qname, qtype, server_addr(server)?, options, role, &mut Trace::new())
+}
+
+/// Like `lookup`, but with control over the flags, class, EDNS options and
+/// transport of the query. A truncated UDP response is retried over TCP, so
+/// the full response is returned even if it doesn't fit in a datagram.
+pub fn lookup_with(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    query: &QueryOptions,
+) -> Result<DnsPacket> {
+    lookup_with_traced(qname, qtype, server, query, &mut Trace::new())
+}
+
+/// Like `lookup_with`, noting every query sent to the server in `trace`.
+pub fn lookup_with_traced(
+    qname: &str,
+    qtype: QueryType,
+    server: (&str, u16),
+    query: &QueryOptions,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let role = DnstapMessageType::ToolQuery;
+    lookup_at_with(qname, qtype, server_addr(server)?, query, role, trace)
+}
+
+// Like `lookup_at`, but with control over the query. Truncated UDP responses
+// are retried over TCP.
+pub(crate) fn lookup_at_with(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    query: &QueryOptions,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let mut packet = new_query(qname, qtype, query);
+
+    if !query.tcp {
+        let response = udp_query(&mut packet, server, query, role, trace)?;
+        if !response.header.truncated_message {
+            return Ok(response);
+        }
+    }
+
+    with_retries(query, |edns| {
+        traced(trace, server, Transport::Tcp, &mut packet, |packet| {
+            exchange_tcp(packet, server, query, edns, role)
+        })
+    })
+}
+
+// Looks up a question at a server that has already been resolved to an address,
+// logging the exchange to dnstap as `role`.
+pub(crate) fn lookup_at(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    options: &[EdnsOption],
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let query = QueryOptions {
+        options: options.to_vec(),
+        ..QueryOptions::default()
+    };
+
+    let mut packet = new_query(qname, qtype, &query);
+    udp_query(&mut packet, server, &query, role, trace)
+}
+
+fn udp_query(
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    role: DnstapMessageType,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    // Every lookup uses its own socket on a random port, so that concurrent
+    // lookups don't interfere and responses are harder to spoof.
+    let socket = UdpSocket::bind(bind_addr(server))?;
+
+    with_retries(query, |edns| {
+        traced(trace, server, Transport::Udp, packet, |packet| {
+            exchange(&socket, packet, server, query, edns, role)
+        })
+    })
+}
+
+// Runs a single exchange of `packet` with `server`, noting it in `trace`.
+fn traced<F>(
+    trace: &mut Trace,
+    server: SocketAddr,
+    transport: Transport,
+    packet: &mut DnsPacket,
+    exchange: F,
+) -> Result<DnsPacket>
+where
+    F: FnOnce(&mut DnsPacket) -> Result<DnsPacket>,
+{
+    let start = Instant::now();
+    let response = exchange(packet);
+    trace.query(packet, server, transport, start.elapsed(), &response);
+    metrics().record_upstream(server, start.elapsed(), &response);
+
+    response
+}
+
+// Runs `exchange`, repeating it once if the response asks for that.
+fn with_retries<F>(query: &QueryOptions, mut exchange: F) -> Result<DnsPacket>
+where
+    F: FnMut(bool) -> Result<DnsPacket>,
+{
+    let response = exchange(query.edns)?;
+    if !query.edns {
+        return Ok(response);
+    }
+
+    match retry_for(&response) {
+        Retry::No => Ok(response),
+        Retry::WithEdns => exchange(true),
+        Retry::WithoutEdns => exchange(false),
+    }
+}
+
+// Sends a single query and waits for the response. With `edns` set the query
+// carries our cookie for the server along with the requested options, and a
+// response echoing somebody else's client cookie is rejected as spoofed.
+fn exchange(
+    socket: &UdpSocket,
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+    role: DnstapMessageType,
+) -> Result<DnsPacket> {
+    let req_buffer = write_query(packet, server, query, edns)?;
+    let query_time = SystemTime::now();
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+
+    let local = socket.local_addr()?;
+    let message = &req_buffer.buf[0..req_buffer.pos];
+    log_query(role, Transport::Udp, local, server, query_time, message);
+
+    // Without a deadline an unresponsive server would block the lookup forever.
+    let deadline = Instant::now() + query.timeout();
+    loop {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(x) if x > Duration::from_millis(0) => x,
+            _ => return Err(timed_out()),
+        };
+        socket.set_read_timeout(Some(remaining))?;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        let (len, src) = socket.recv_from(&mut res_buffer.buf)?;
+        if let Some(response) = read_response(packet, server, src, &mut res_buffer)? {
+            let message = &res_buffer.buf[0..len];
+            log_response(role, Transport::Udp, local, server, query_time, message);
+            return Ok(response);
+        }
+    }
+}
+
+// Like `exchange`, but over a TCP connection of its own. Messages are prefixed
+// with their length, and may be up to 64k long.
+fn exchange_tcp(
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+    role: DnstapMessageType,
+) -> Result<DnsPacket> {
+    let req_buffer = write_query(packet, server, query, edns)?;
+
+    let query_time = SystemTime::now();
+    let mut stream = TcpStream::connect_timeout(&server, query.timeout())?;
+    stream.set_read_timeout(Some(query.timeout()))?;
+    stream.set_write_timeout(Some(query.timeout()))?;
+
+    let message = tcp_message(&req_buffer);
+    stream.write_all(&message)?;
+
+    let local = stream.local_addr()?;
+    log_query(role, Transport::Tcp, local, server, query_time, &message[2..]);
+
+    let mut len = [0; 2];
+    let mut res_buffer = VectorPacketBuffer::new();
+    let received = stream.read_exact(&mut len).and_then(|_| {
+        res_buffer.buffer = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut res_buffer.buffer)
+    });
+    match received {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Err(timed_out()),
+        Err(e) => return Err(e),
+    }
+
+    match read_response(packet, server, server, &mut res_buffer)? {
+        Some(response) => {
+            let message = &res_buffer.buffer;
+            log_response(role, Transport::Tcp, local, server, query_time, message);
+            Ok(response)
+        }
+        None => Err(not_an_answer()),
+    }
+}
+
+// The query in `req_buffer` as sent over TCP, prefixed with its length.
+pub(crate) fn tcp_message(req_buffer: &BytePacketBuffer) -> Vec<u8> {
+    let mut message = (req_buffer.pos as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&req_buffer.buf[0..req_buffer.pos]);
+
+    message
+}
+
+// What to do after receiving a response.
+pub(crate) enum Retry {
+    No,
+    // A server that no longer accepts our server cookie sends a fresh one
+    // along with BADCOOKIE, so a single retry is enough.
+    WithEdns,
+    // Servers that don't implement EDNS at all reject the OPT record outright,
+    // in which case we fall back to a plain query.
+    WithoutEdns,
+}
+
+pub(crate) fn retry_for(response: &DnsPacket) -> Retry {
+    let rescode = response.rescode();
+    if rescode == ResultCode::BADCOOKIE {
+        Retry::WithEdns
+    } else if response.get_opt().is_none()
+        && (rescode == ResultCode::FORMERR || rescode == ResultCode::NOTIMP)
+    {
+        Retry::WithoutEdns
+    } else {
+        Retry::No
+    }
+}
+
+pub(crate) fn server_addr(server: (&str, u16)) -> Result<SocketAddr> {
+    match server.to_socket_addrs()?.next() {
+        Some(x) => Ok(x),
+        None => Err(Error::new(ErrorKind::InvalidInput, "No address for server")),
+    }
+}
+
+// The local address to send queries to `server` from, on a random port.
+pub(crate) fn bind_addr(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+pub(crate) fn new_query(qname: &str, qtype: QueryType, query: &QueryOptions) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+
+    packet.header.id = rand::random();
+    packet.header.questions = 1;
+    packet.header.recursion_desired = query.recursion_desired;
+    packet.header.checking_disabled = query.checking_disabled;
+
+    let mut question = DnsQuestion::new(qname.to_string(), qtype);
+    question.qclass = query.qclass;
+    packet.questions.push(question);
+
+    packet
+}
+
+pub(crate) fn write_query(
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    query: &QueryOptions,
+    edns: bool,
+) -> Result<BytePacketBuffer> {
+    packet.resources.clear();
+    if edns {
+        let mut opt_options = vec![cookie_jar().option_for(server.ip())];
+        opt_options.extend_from_slice(&query.options);
+
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: if query.dnssec_ok { 0x8000 } else { 0 },
+            options: opt_options,
+        });
+    }
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    Ok(req_buffer)
+}
+
+// Parses a datagram received while waiting for the response to `query`.
+// Anything that doesn't come from the server we asked, or doesn't answer our
+// query, is ignored by returning `None`.
+pub(crate) fn read_response<T: PacketBuffer>(
+    query: &DnsPacket,
+    server: SocketAddr,
+    src: SocketAddr,
+    res_buffer: &mut T,
+) -> Result<Option<DnsPacket>> {
+    if src != server {
+        return Ok(None);
+    }
+
+    // Anything that isn't the answer to our query is ignored rather than
+    // failing the lookup, so junk from an off-path sender can't stop us from
+    // receiving the real response.
+    let response = match DnsPacket::from_buffer(res_buffer) {
+        Ok(x) => x,
+        Err(e) => {
+            debug!(server:%, error:% = e; "Ignoring unparsable response");
+            return Ok(None);
+        }
+    };
+    if response.header.id != query.header.id || !echoes_question(query, &response) {
+        return Ok(None);
+    }
+
+    if !cookie_jar().update(server.ip(), response.edns_options()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Response echoed a client cookie we didn't send",
+        ));
+    }
+
+    Ok(Some(response))
+}
+
+// Whether `response` repeats the question of `query`. Servers that couldn't
+// make sense of the query may leave the question out of their error.
+fn echoes_question(query: &DnsPacket, response: &DnsPacket) -> bool {
+    if response.questions.is_empty() {
+        return matches!(response.rescode(), ResultCode::FORMERR | ResultCode::NOTIMP);
+    }
+
+    response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(a, b)| {
+                a.name.eq_ignore_ascii_case(&b.name) && a.qtype == b.qtype && a.qclass == b.qclass
+            })
+}
+
+pub(crate) fn timed_out() -> Error {
+    Error::new(ErrorKind::TimedOut, "Lookup timed out")
+}
+
+// A TCP connection only carries the response to our query, so anything else
+// fails the exchange.
+pub(crate) fn not_an_answer() -> Error {
+    Error::new(ErrorKind::InvalidData, "Response doesn't answer our query")
+}
+
+// The steps `recursive_lookup` can take after receiving a response.
+pub(crate) enum NextStep {
+    // The response is final and should be returned.
+    Done,
+    // Continue with the name server at the given address.
+    Server(SocketAddr),
+    // Continue with a name server whose address has to be looked up first.
+    ResolveServer(String),
+}
+
+// Decides where a recursive lookup goes after `response`, noting any referral
+// in `trace`. The blocking and async lookups only differ in how they send
+// queries, so both follow referrals through here.
+pub(crate) fn next_step(qname: &str, response: &DnsPacket, trace: &mut Trace) -> Result<NextStep> {
+    // If there are entries in the answer section, and no errors, we are done!
+    if !response.answers.is_empty() && response.rescode() == ResultCode::NOERROR {
+        return Ok(NextStep::Done);
+    }
+
+    // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
+    // way of telling us that the name doesn't exist.
+    if response.rescode() == ResultCode::NXDOMAIN {
+        return Ok(NextStep::Done);
+    }
+
+    // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
+    // record in the additional section. If this succeeds, we can switch name server
+    // and retry the loop.
+    if let Some(new_ns) = response.get_resolved_ns(qname) {
+        let server = server_addr((new_ns.as_str(), 53))?;
+        trace.referral(response, qname, None, Some(server.ip()));
+        return Ok(NextStep::Server(server));
+    }
+
+    // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
+    // we'll go with what the last server told us.
+    match response.get_unresolved_ns(qname) {
+        Some(x) => {
+            trace.referral(response, qname, Some(&x), None);
+            Ok(NextStep::ResolveServer(x))
+        }
+        None => Ok(NextStep::Done),
+    }
+}
+
+// Notes the lookup of `name_server`'s address in `trace`, and picks a random
+// address from its result to continue with. Without one, the recursive lookup
+// ends with the last response it got.
+pub(crate) fn name_server_addr(
+    name_server: String,
+    ns_trace: Trace,
+    response: Result<DnsPacket>,
+    trace: &mut Trace,
+) -> Result<Option<SocketAddr>> {
+    trace.push(TraceEvent::NameServerLookup {
+        name_server,
+        trace: ns_trace,
+    });
+
+    match response?.get_random_a() {
+        Some(x) => server_addr((x.as_str(), 53)).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+    recursive_lookup_with_options(qname, qtype, &[])
+}
+
+/// Like `recursive_lookup_with_options`, noting every query sent, every
+/// referral followed and every name server address looked up in `trace`.
+pub fn recursive_lookup_traced(
+    qname: &str,
+    qtype: QueryType,
+    options: &[EdnsOption],
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let root = SocketAddr::new(root_server(), 53);
+    recursive_lookup_from(qname, qtype, options, root, trace)
+}
+
+/// Like `recursive_lookup`, but sends the given EDNS options to every server
+/// along the way. Lookups of name server addresses don't carry them.
+pub fn recursive_lookup_with_options(
+    qname: &str,
+    qtype: QueryType,
+    options: &[EdnsOption],
+) -> Result<DnsPacket> {
+    // Unless told otherwise, we're always starting with *a.root-servers.net*.
+    recursive_lookup_traced(qname, qtype, options, &mut Trace::new())
+}
+
+// Resolves a question by following referrals, starting at `start` rather than
+// at the root.
+pub(crate) fn recursive_lookup_from(
+    qname: &str,
+    qtype: QueryType,
+    options: &[EdnsOption],
+    start: SocketAddr,
+    trace: &mut Trace,
+) -> Result<DnsPacket> {
+    let mut server = start;
+    let query = QueryOptions {
+        options: options.to_vec(),
+        ..QueryOptions::default()
+    };
+
+    // Since it might take an arbitrary number of steps, we enter an unbounded loop.
+    loop {
+        debug!(qname, qtype:%, server:%; "Attempting lookup");
+
+        // The next step is to send the query to the active server.
+        let role = DnstapMessageType::ResolverQuery;
+        let response = lookup_at_with(qname, qtype, server, &query, role, trace)?;
+
+        let new_ns_name = match next_step(qname, &response, trace)? {
+            NextStep::Done => return Ok(response),
+            NextStep::Server(x) => {
+                server = x;
+                continue;
+            }
+            NextStep::ResolveServer(x) => x,
+        };
+
+        // Here we go down the rabbit hole by starting _another_ lookup sequence in the
+        // midst of our current one. Hopefully, this will give us the IP of an appropriate
+        // name server.
+        let mut ns_trace = Trace::new();
+        let recursive_response =
+            recursive_lookup_traced(&new_ns_name, QueryType::A, &[], &mut ns_trace);
+
+        // Finally, we pick a random ip from the result, and restart the loop. If no such
+        // record is available, we again return the last result we got.
+        match name_server_addr(new_ns_name, ns_trace, recursive_response, trace)? {
+            Some(x) => server = x,
+            None => return Ok(response),
+        }
+    }
+} // End of recursive_lookup
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+
+    use super::*;
+
+    // The response to `request`, with `count` answers.
+    fn response_to(request: &DnsPacket, count: u8) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.questions = request.questions.clone();
+        for i in 0..count {
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                addr: Ipv4Addr::new(192, 0, 2, i),
+                ttl: 60,
+            });
+        }
+
+        response
+    }
+
+    // Answers queries over UDP with a truncated response, and over TCP with
+    // one too large for a datagram.
+    fn serve() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let socket = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+
+        thread::spawn(move || loop {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = response_to(&request, 0);
+            response.header.truncated_message = true;
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.write(&mut res_buffer).unwrap();
+            socket.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut req_buffer = VectorPacketBuffer::new();
+                req_buffer.buffer = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut req_buffer.buffer).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                response_to(&request, 50).write(&mut res_buffer).unwrap();
+                let mut message = (res_buffer.buffer.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&res_buffer.buffer);
+                stream.write_all(&message).unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    fn it_retries_truncated_responses_over_tcp() {
+        let port = serve();
+
+        let response = lookup("example.com", QueryType::A, ("127.0.0.1", port)).unwrap();
+        assert!(response.header.truncated_message);
+        assert!(response.answers.is_empty());
+
+        let query = QueryOptions::default();
+        let mut trace = Trace::new();
+        let server = ("127.0.0.1", port);
+        let response = lookup_with_traced("example.com", QueryType::A, server, &query, &mut trace);
+        assert_eq!(50, response.unwrap().answers.len());
+
+        let transports: Vec<Transport> = trace.queries().iter().map(|x| x.transport).collect();
+        assert_eq!(vec![Transport::Udp, Transport::Tcp], transports);
+    }
+
+    #[test]
+    fn it_ignores_responses_to_other_queries() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            // A datagram that doesn't parse, and a response for another name
+            // with the right ID, before the actual response.
+            let mut junk = request.header.id.to_be_bytes().to_vec();
+            junk.extend_from_slice(&[0x80, 0, 0, 1]);
+            socket.send_to(&junk, src).unwrap();
+
+            let mut other = response_to(&request, 1);
+            other.questions[0].name = "example.net".into();
+            let mut res_buffer = BytePacketBuffer::new();
+            other.write(&mut res_buffer).unwrap();
+            socket.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response_to(&request, 2).write(&mut res_buffer).unwrap();
+            socket.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+        });
+
+        let response = lookup("Example.com", QueryType::A, ("127.0.0.1", port)).unwrap();
+        assert_eq!(2, response.answers.len());
+    }
+
+    #[test]
+    fn it_sets_the_requested_flags() {
+        let query = QueryOptions {
+            qclass: 3,
+            recursion_desired: false,
+            checking_disabled: true,
+            dnssec_ok: true,
+            ..QueryOptions::default()
+        };
+        let server = "127.0.0.1:53".parse().unwrap();
+
+        let mut packet = new_query("example.com", QueryType::SOA, &query);
+        let mut buffer = write_query(&mut packet, server, &query, true).unwrap();
+        buffer.seek(0).unwrap();
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(!packet.header.recursion_desired);
+        assert!(packet.header.checking_disabled);
+        assert_eq!(3, packet.questions[0].qclass);
+        match packet.get_opt() {
+            Some(&DnsRecord::OPT { flags, .. }) => assert_eq!(0x8000, flags),
+            x => panic!("Expected an OPT record, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Forwarding queries to a set of upstream resolvers
+//!
+//! Rather than walking down from the root, a forwarder hands every query to one
+//! of its upstreams and lets it do the recursion. Upstreams that keep failing
+//! are taken out of rotation for a while, and the remaining ones are tried in
+//! turn until one of them answers.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::client::lookup_at;
+use super::trace::Trace;
+use crate::DnsPacket;
+use crate::DnstapMessageType;
+use crate::EdnsOption;
+use crate::QueryType;
+use crate::ResultCode;
+
+// The number of consecutive failures after which an upstream is considered down.
+const MAX_FAILURES: u32 = 3;
+
+// How long an upstream that is down is only tried as a last resort.
+const DOWN_TIME: Duration = Duration::from_secs(30);
+
+/// How a forwarder picks the upstream to send a query to first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamSelection {
+    /// Take turns, spreading queries evenly across the upstreams.
+    RoundRobin,
+    /// Prefer the upstream that has been answering fastest.
+    LowestLatency,
+}
+
+#[derive(Clone, Debug, Default)]
+struct UpstreamHealth {
+    // Failures since the last successful query.
+    failures: u32,
+    // Set once the upstream has failed too often in a row.
+    down_until: Option<Instant>,
+    // Smoothed round trip time, like TCP keeps it (RFC 6298).
+    srtt: Option<Duration>,
+}
+
+/// Sends queries to upstream resolvers, failing over between them.
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    selection: UpstreamSelection,
+    health: Mutex<Vec<UpstreamHealth>>,
+    next: AtomicUsize,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<SocketAddr>, selection: UpstreamSelection) -> Forwarder {
+        let health = vec![UpstreamHealth::default(); upstreams.len()];
+
+        Forwarder {
+            upstreams,
+            selection,
+            health: Mutex::new(health),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[SocketAddr] {
+        &self.upstreams
+    }
+
+    /// The upstreams that aren't currently considered down.
+    pub fn healthy(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+
+        self.upstreams
+            .iter()
+            .zip(health.iter())
+            .filter(|&(_, x)| !is_down(x, now))
+            .map(|(&addr, _)| addr)
+            .collect()
+    }
+
+    /// Looks up a question at the upstreams, trying them one after the other
+    /// until one answers. An upstream answering `SERVFAIL` or `REFUSED` counts
+    /// as a failure, but its answer is passed on if nobody does better.
+    pub fn forward(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        options: &[EdnsOption],
+    ) -> Result<DnsPacket> {
+        self.forward_traced(qname, qtype, options, &mut Trace::new())
+    }
+
+    /// Like `forward`, noting every query sent to the upstreams in `trace`.
+    pub fn forward_traced(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        options: &[EdnsOption],
+        trace: &mut Trace,
+    ) -> Result<DnsPacket> {
+        let mut last_response = None;
+        let mut last_error = Error::new(ErrorKind::InvalidInput, "No upstream servers");
+        let role = DnstapMessageType::ForwarderQuery;
+
+        for i in self.order() {
+            let start = Instant::now();
+            match lookup_at(qname, qtype, self.upstreams[i], options, role, trace) {
+                Ok(response) => match response.rescode() {
+                    ResultCode::SERVFAIL | ResultCode::REFUSED => {
+                        self.record_failure(i);
+                        last_response = Some(response);
+                    }
+                    _ => {
+                        self.record_success(i, start.elapsed());
+                        return Ok(response);
+                    }
+                },
+                Err(e) => {
+                    self.record_failure(i);
+                    last_error = e;
+                }
+            }
+        }
+
+        match last_response {
+            Some(x) => Ok(x),
+            None => Err(last_error),
+        }
+    }
+
+    // The order to try the upstreams in. Upstreams that are down come last,
+    // the ones due back soonest first, so that a query still gets through when
+    // every upstream has been failing.
+    fn order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+        let count = self.upstreams.len();
+
+        let mut order: Vec<usize> = match self.selection {
+            UpstreamSelection::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|i| (start + i) % count.max(1)).collect()
+            }
+            UpstreamSelection::LowestLatency => {
+                // Upstreams we haven't heard from yet get tried first, so we
+                // learn how fast they are.
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by_key(|&i| health[i].srtt.unwrap_or_default());
+                order
+            }
+        };
+
+        // The sort is stable, so the healthy upstreams keep their order.
+        order.sort_by_key(|&i| match health[i].down_until {
+            Some(until) if until > now => Some(until),
+            _ => None,
+        });
+
+        order
+    }
+
+    fn record_success(&self, i: usize, rtt: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let upstream = &mut health[i];
+
+        if upstream.down_until.take().is_some() {
+            info!(upstream:% = self.upstreams[i]; "Upstream is back up");
+        }
+        upstream.failures = 0;
+        upstream.srtt = Some(match upstream.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    fn record_failure(&self, i: usize) {
+        let mut health = self.health.lock().unwrap();
+        let upstream = &mut health[i];
+
+        upstream.failures += 1;
+        if upstream.failures >= MAX_FAILURES {
+            if upstream.down_until.is_none() {
+                warn!(upstream:% = self.upstreams[i], failures = upstream.failures;
+                      "Upstream is down");
+            }
+            upstream.down_until = Some(Instant::now() + DOWN_TIME);
+        }
+    }
+}
+
+fn is_down(health: &UpstreamHealth, now: Instant) -> bool {
+    match health.down_until {
+        Some(until) => until > now,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use super::*;
+    use crate::{BytePacketBuffer, DnsRecord, PacketBuffer};
+
+    // Answers every query with `rescode`, and with 192.0.2.1 if that's NOERROR.
+    fn serve(rescode: ResultCode) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.rescode = rescode;
+            response.questions = request.questions.clone();
+            if rescode == ResultCode::NOERROR {
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: "192.0.2.1".parse().unwrap(),
+                    ttl: 60,
+                });
+            }
+            response.resources = request.resources.clone();
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.write(&mut res_buffer).unwrap();
+            let data = res_buffer.get_range(0, res_buffer.pos()).unwrap();
+            socket.send_to(data, src).unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn it_fails_over_to_the_next_upstream() {
+        let broken = serve(ResultCode::SERVFAIL);
+        let working = serve(ResultCode::NOERROR);
+        let forwarder = Forwarder::new(vec![broken, working], UpstreamSelection::LowestLatency);
+
+        for _ in 0..MAX_FAILURES {
+            let response = forwarder.forward("example.com", QueryType::A, &[]).unwrap();
+            assert_eq!(response.get_random_a(), Some("192.0.2.1".to_string()));
+        }
+
+        // Having failed repeatedly, the broken upstream is no longer asked first.
+        assert_eq!(vec![working], forwarder.healthy());
+        assert_eq!(vec![1, 0], forwarder.order());
+    }
+
+    #[test]
+    fn it_passes_on_failures_when_every_upstream_fails() {
+        let forwarder = Forwarder::new(
+            vec![serve(ResultCode::REFUSED)],
+            UpstreamSelection::RoundRobin,
+        );
+
+        let response = forwarder.forward("example.com", QueryType::A, &[]).unwrap();
+        assert_eq!(ResultCode::REFUSED, response.rescode());
+
+        let forwarder = Forwarder::new(vec![], UpstreamSelection::RoundRobin);
+        assert!(forwarder.forward("example.com", QueryType::A, &[]).is_err());
+    }
+
+    #[test]
+    fn it_takes_turns_between_upstreams() {
+        let upstreams = vec![
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+            "192.0.2.3:53".parse().unwrap(),
+        ];
+        let forwarder = Forwarder::new(upstreams, UpstreamSelection::RoundRobin);
+
+        assert_eq!(vec![0, 1, 2], forwarder.order());
+        assert_eq!(vec![1, 2, 0], forwarder.order());
+        assert_eq!(vec![2, 0, 1], forwarder.order());
+    }
+
+    #[test]
+    fn it_prefers_the_fastest_upstream() {
+        let upstreams = vec![
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+            "192.0.2.3:53".parse().unwrap(),
+        ];
+        let forwarder = Forwarder::new(upstreams, UpstreamSelection::LowestLatency);
+
+        forwarder.record_success(0, Duration::from_millis(40));
+        forwarder.record_success(1, Duration::from_millis(10));
+        forwarder.record_success(2, Duration::from_millis(20));
+        assert_eq!(vec![1, 2, 0], forwarder.order());
+
+        for _ in 0..MAX_FAILURES {
+            forwarder.record_failure(1);
+        }
+        assert_eq!(vec![2, 0, 1], forwarder.order());
+    }
+}
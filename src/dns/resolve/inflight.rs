@@ -0,0 +1,210 @@
+//! Coalescing of identical resolutions
+//!
+//! When many clients ask the same question at once, only the first of them
+//! resolves it while the others wait for its outcome. Besides saving upstream
+//! queries, this keeps an attacker from having many identical queries
+//! outstanding at once, which would make it easier to guess a matching id and
+//! port for a spoofed response.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::EdnsOption;
+use crate::QueryType;
+
+// Errors can't be cloned, so waiting lookups get a copy of their kind and
+// description instead.
+type Outcome<T> = std::result::Result<T, (ErrorKind, String)>;
+
+// Resolutions are identical if they ask the same question and send the same
+// EDNS options, e.g. the same client subnet, upstream.
+type Key = (String, QueryType, u16, Vec<EdnsOption>);
+
+struct Pending<T> {
+    outcome: Mutex<Option<Outcome<T>>>,
+    done: Condvar,
+}
+
+impl<T> Default for Pending<T> {
+    fn default() -> Self {
+        Pending {
+            outcome: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+}
+
+/// The resolutions currently in flight. Usually they produce a packet, but
+/// anything else the waiting clients need from a resolution can be shared
+/// along with it.
+pub struct InflightQueries<T = DnsPacket> {
+    pending: Mutex<HashMap<Key, Arc<Pending<T>>>>,
+}
+
+impl<T> Default for InflightQueries<T> {
+    fn default() -> Self {
+        InflightQueries {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl InflightQueries {
+    pub fn new() -> InflightQueries {
+        InflightQueries::default()
+    }
+}
+
+impl<T: Clone> InflightQueries<T> {
+    /// Resolves `question` by calling `resolve`, unless an identical resolution
+    /// is already in flight, in which case its outcome is awaited and shared.
+    pub fn resolve<F>(
+        &self,
+        question: &DnsQuestion,
+        options: &[EdnsOption],
+        resolve: F,
+    ) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let key = (
+            question.name.to_lowercase(),
+            question.qtype,
+            question.qclass,
+            options.to_vec(),
+        );
+
+        let (pending, leader) = {
+            let mut inflight = self.pending.lock().unwrap();
+            match inflight.get(&key) {
+                Some(x) => (x.clone(), false),
+                None => {
+                    let pending = Arc::new(Pending::default());
+                    inflight.insert(key.clone(), pending.clone());
+                    (pending, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut outcome = pending.outcome.lock().unwrap();
+            while outcome.is_none() {
+                outcome = pending.done.wait(outcome).unwrap();
+            }
+
+            return match *outcome {
+                Some(Ok(ref x)) => Ok(x.clone()),
+                Some(Err((kind, ref description))) => Err(Error::new(kind, description.clone())),
+                None => unreachable!(),
+            };
+        }
+
+        // The guard wakes up the waiting lookups even if `resolve` panics.
+        let mut guard = Leader {
+            inflight: self,
+            key: Some(key),
+            pending,
+            outcome: None,
+        };
+
+        let result = resolve();
+        guard.outcome = Some(match result {
+            Ok(ref x) => Ok(x.clone()),
+            Err(ref e) => Err((e.kind(), e.to_string())),
+        });
+
+        result
+    }
+
+    /// The number of distinct resolutions currently in flight.
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct Leader<'a, T> {
+    inflight: &'a InflightQueries<T>,
+    key: Option<Key>,
+    pending: Arc<Pending<T>>,
+    outcome: Option<Outcome<T>>,
+}
+
+impl<'a, T> Drop for Leader<'a, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.inflight.pending.lock().unwrap().remove(&key);
+        }
+
+        let outcome = self
+            .outcome
+            .take()
+            .unwrap_or_else(|| Err((ErrorKind::Other, "Resolution was aborted".to_string())));
+        *self.pending.outcome.lock().unwrap() = Some(outcome);
+        self.pending.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn it_shares_the_outcome_of_identical_resolutions() {
+        let inflight = Arc::new(InflightQueries::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles = (0..8)
+            .map(|_| {
+                let inflight = inflight.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let question = DnsQuestion::new("Example.com".to_string(), QueryType::A);
+                    barrier.wait();
+                    inflight.resolve(&question, &[], || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        // Give the other threads time to line up behind us.
+                        thread::sleep(Duration::from_millis(200));
+                        let mut packet = DnsPacket::new();
+                        packet.header.id = 42;
+                        Ok(packet)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap().header.id, 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn it_keeps_different_questions_apart() {
+        let inflight = InflightQueries::new();
+        let a = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let aaaa = DnsQuestion::new("example.com".to_string(), QueryType::AAAA);
+
+        let result = inflight.resolve(&a, &[], || {
+            inflight.resolve(&aaaa, &[], || {
+                Err(Error::new(ErrorKind::TimedOut, "Lookup timed out"))
+            })
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(inflight.is_empty());
+    }
+}
@@ -0,0 +1,21 @@
+//! Lookups against upstream name servers
+
+mod async_client;
+mod client;
+mod forwarder;
+mod inflight;
+mod routes;
+mod stub;
+mod trace;
+
+pub use self::async_client::{lookup_async, lookup_with_async, lookup_with_options_async,
+                             lookup_with_traced_async, recursive_lookup_async,
+                             recursive_lookup_traced_async, recursive_lookup_with_options_async};
+pub use self::forwarder::{Forwarder, UpstreamSelection};
+pub use self::inflight::InflightQueries;
+pub use self::routes::{ZoneRoute, ZoneRoutes};
+pub use self::stub::{ResolvConf, StubResolver, RESOLV_CONF};
+pub use self::client::{lookup, lookup_with, lookup_with_options, lookup_with_traced,
+                       recursive_lookup, recursive_lookup_traced, recursive_lookup_with_options,
+                       root_server, set_lookup_timeout, set_root_server, QueryOptions};
+pub use self::trace::{QueryTrace, Trace, TraceEvent, Transport};
@@ -0,0 +1,152 @@
+//! Routing queries for particular zones to particular servers
+//!
+//! Names such as `corp.internal` or `10.in-addr.arpa` can't be resolved from
+//! the public root, so queries for them have to go to servers that know about
+//! them. Each configured zone covers itself and every name below it, and a
+//! query is routed by the longest zone its name falls under.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+
+use super::client::recursive_lookup_from;
+use super::forwarder::Forwarder;
+use super::trace::Trace;
+use crate::DnsPacket;
+use crate::EdnsOption;
+use crate::QueryType;
+
+/// Where queries for a zone are sent.
+pub enum ZoneRoute {
+    /// Pass queries on to resolvers that do the recursion themselves.
+    Forward(Forwarder),
+    /// Resolve queries ourselves, but starting at the zone's own name servers
+    /// rather than at the root. The servers are tried in order.
+    Stub(Vec<SocketAddr>),
+}
+
+impl ZoneRoute {
+    pub fn resolve(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        options: &[EdnsOption],
+    ) -> Result<DnsPacket> {
+        self.resolve_traced(qname, qtype, options, &mut Trace::new())
+    }
+
+    /// Like `resolve`, noting every query sent in `trace`.
+    pub fn resolve_traced(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        options: &[EdnsOption],
+        trace: &mut Trace,
+    ) -> Result<DnsPacket> {
+        match *self {
+            ZoneRoute::Forward(ref forwarder) => {
+                forwarder.forward_traced(qname, qtype, options, trace)
+            }
+            ZoneRoute::Stub(ref servers) => {
+                let mut last_error = Error::new(ErrorKind::InvalidInput, "No name servers");
+                for &server in servers {
+                    match recursive_lookup_from(qname, qtype, options, server, trace) {
+                        Ok(x) => return Ok(x),
+                        Err(e) => last_error = e,
+                    }
+                }
+
+                Err(last_error)
+            }
+        }
+    }
+}
+
+/// A table of zones and where to send queries for them.
+#[derive(Default)]
+pub struct ZoneRoutes {
+    zones: HashMap<String, ZoneRoute>,
+}
+
+impl ZoneRoutes {
+    pub fn new() -> ZoneRoutes {
+        ZoneRoutes::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// Routes queries for `zone` and the names below it. `.` stands for the
+    /// root zone, and therefore for every name not covered by another zone.
+    pub fn add(&mut self, zone: &str, route: ZoneRoute) {
+        self.zones.insert(normalize(zone), route);
+    }
+
+    /// The most specific zone `qname` falls under, along with its route.
+    pub fn route_for(&self, qname: &str) -> Option<(&str, &ZoneRoute)> {
+        let qname = normalize(qname);
+        let mut name = qname.as_str();
+
+        loop {
+            if let Some((zone, route)) = self.zones.get_key_value(name) {
+                return Some((zone.as_str(), route));
+            }
+            if name.is_empty() {
+                return None;
+            }
+
+            // Move up to the parent, until only the root is left.
+            name = match name.find('.') {
+                Some(i) => &name[i + 1..],
+                None => "",
+            };
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stub(addr: &str) -> ZoneRoute {
+        ZoneRoute::Stub(vec![addr.parse().unwrap()])
+    }
+
+    fn zone_for<'a>(routes: &'a ZoneRoutes, qname: &str) -> Option<&'a str> {
+        routes.route_for(qname).map(|(zone, _)| zone)
+    }
+
+    #[test]
+    fn it_routes_by_the_longest_matching_zone() {
+        let mut routes = ZoneRoutes::new();
+        routes.add("corp.internal", stub("10.0.0.1:53"));
+        routes.add("lab.corp.internal.", stub("10.0.1.1:53"));
+        routes.add("10.in-addr.arpa", stub("10.0.0.2:53"));
+
+        assert_eq!(Some("corp.internal"), zone_for(&routes, "corp.internal"));
+        assert_eq!(Some("corp.internal"), zone_for(&routes, "www.Corp.Internal."));
+        assert_eq!(Some("lab.corp.internal"), zone_for(&routes, "host.lab.corp.internal"));
+        assert_eq!(Some("10.in-addr.arpa"), zone_for(&routes, "4.3.2.10.in-addr.arpa"));
+        assert_eq!(None, zone_for(&routes, "example.com"));
+        assert_eq!(None, zone_for(&routes, "notcorp.internal"));
+    }
+
+    #[test]
+    fn it_falls_back_to_the_root_zone() {
+        let mut routes = ZoneRoutes::new();
+        routes.add(".", stub("192.0.2.1:53"));
+        routes.add("corp.internal", stub("10.0.0.1:53"));
+
+        assert_eq!(Some(""), zone_for(&routes, "example.com"));
+        assert_eq!(Some("corp.internal"), zone_for(&routes, "www.corp.internal"));
+    }
+}
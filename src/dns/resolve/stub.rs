@@ -0,0 +1,475 @@
+//! Stub resolution through the name servers listed in resolv.conf
+//!
+//! Rather than walking down from the root, a stub resolver asks the servers the
+//! system is configured with, the way the C library does. It understands the
+//! parts of resolv(5) that decide which names are asked for and where:
+//!
+//! ```text
+//! nameserver 192.0.2.53
+//! nameserver 2001:db8::53
+//! search example.com example.net
+//! options ndots:2 timeout:3 attempts:2 rotate edns0
+//! ```
+//!
+//! Other keywords and options are ignored, as are lines that can't be parsed.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::client::{lookup_with, QueryOptions};
+use crate::DnsPacket;
+use crate::QueryType;
+use crate::ResultCode;
+
+/// Where the system's resolver configuration lives.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// Like the C library, only the first few name servers listed are used.
+const MAX_NAMESERVERS: usize = 3;
+
+// The limits the C library puts on `ndots`, `timeout` and `attempts`.
+const MAX_NDOTS: u8 = 15;
+const MAX_TIMEOUT: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The settings of a resolv.conf file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvConf {
+    /// The servers to ask, in order. Without any, the local host is asked.
+    pub nameservers: Vec<SocketAddr>,
+    /// The domains appended to names that aren't fully qualified, from either
+    /// `search` or `domain`, whichever comes last.
+    pub search: Vec<String>,
+    /// Names with at least this many dots are tried as they are before the
+    /// search list is applied to them.
+    pub ndots: u8,
+    /// How long to wait for a response from each server.
+    pub timeout: Duration,
+    /// How many times to go through the servers before giving up.
+    pub attempts: u32,
+    /// Spreads queries across the servers, rather than always asking the
+    /// first one first.
+    pub rotate: bool,
+    /// Whether queries carry an OPT record.
+    pub edns0: bool,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf {
+            nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)],
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+            edns0: false,
+        }
+    }
+}
+
+impl ResolvConf {
+    /// Reads the system's configuration from `/etc/resolv.conf`.
+    pub fn system() -> Result<ResolvConf> {
+        ResolvConf::load(RESOLV_CONF)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ResolvConf> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        Ok(ResolvConf::parse(&data))
+    }
+
+    /// Parses the contents of a resolv.conf file. Anything not set by it keeps
+    /// the C library's default.
+    pub fn parse(data: &str) -> ResolvConf {
+        let mut conf = ResolvConf {
+            nameservers: Vec::new(),
+            ..ResolvConf::default()
+        };
+
+        for (i, line) in data.lines().enumerate() {
+            let line = match line.find(['#', ';']) {
+                Some(x) => &line[..x],
+                None => line,
+            };
+            let mut tokens = line.split_whitespace();
+            let keyword = match tokens.next() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match keyword {
+                "nameserver" => {
+                    let addr = tokens.next().unwrap_or_default();
+                    match addr.parse::<IpAddr>() {
+                        Ok(_) if conf.nameservers.len() >= MAX_NAMESERVERS => {
+                            debug!(line = i + 1, addr; "Ignoring extra name server");
+                        }
+                        Ok(x) => conf.nameservers.push(SocketAddr::new(x, 53)),
+                        Err(_) => {
+                            debug!(line = i + 1, addr; "Skipping invalid name server");
+                        }
+                    }
+                }
+                "domain" | "search" => {
+                    conf.search = tokens
+                        .map(|x| x.trim_end_matches('.').to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                }
+                "options" => {
+                    for option in tokens {
+                        conf.set_option(option);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if conf.nameservers.is_empty() {
+            conf.nameservers = ResolvConf::default().nameservers;
+        }
+
+        conf
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = match option.split_once(':') {
+            Some((name, value)) => (name, value.parse::<u32>().ok()),
+            None => (option, None),
+        };
+
+        match (name, value) {
+            ("ndots", Some(x)) => self.ndots = x.min(u32::from(MAX_NDOTS)) as u8,
+            ("timeout", Some(x)) => {
+                self.timeout = Duration::from_secs(u64::from(x).min(MAX_TIMEOUT))
+            }
+            ("attempts", Some(x)) => self.attempts = x.min(MAX_ATTEMPTS),
+            ("rotate", None) => self.rotate = true,
+            ("edns0", None) => self.edns0 = true,
+            _ => debug!(option; "Ignoring unsupported resolver option"),
+        }
+    }
+
+    /// The names to ask for when looking up `name`, in order. A name ending in
+    /// a dot is only tried as it is. Otherwise the search list is applied to
+    /// it, after trying it as it is if it has at least `ndots` dots, or before
+    /// if it doesn't.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if name.ends_with('.') {
+            return vec![name.trim_end_matches('.').to_string()];
+        }
+
+        let searched = self.search.iter().map(|domain| format!("{}.{}", name, domain));
+        let dots = name.matches('.').count();
+        if dots >= usize::from(self.ndots) {
+            std::iter::once(name.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.to_string())).collect()
+        }
+    }
+}
+
+/// Looks up names through the servers of a resolv.conf file, failing over
+/// between them.
+pub struct StubResolver {
+    conf: ResolvConf,
+    next: AtomicUsize,
+}
+
+impl StubResolver {
+    pub fn new(conf: ResolvConf) -> StubResolver {
+        StubResolver {
+            conf,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A resolver using the servers in `/etc/resolv.conf`.
+    pub fn system() -> Result<StubResolver> {
+        Ok(StubResolver::new(ResolvConf::system()?))
+    }
+
+    pub fn conf(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    /// Looks up `name`, applying the search list to it. The first candidate
+    /// name that exists with records of `qtype` is answered for. Like the C
+    /// library, a candidate that can't be looked up doesn't keep the others
+    /// from being tried. When no candidate is answered for, the response for
+    /// the first name that exists is returned, then the first that failed
+    /// with `SERVFAIL` or `REFUSED`, then the last `NXDOMAIN`, and only when
+    /// no server responded at all the last error.
+    pub fn lookup(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let mut nodata = None;
+        let mut failure = None;
+        let mut nxdomain = None;
+        let mut last_error = Error::new(ErrorKind::InvalidInput, "Nothing to look up");
+
+        for qname in self.conf.candidates(name) {
+            let response = match self.query(&qname, qtype) {
+                Ok(x) => x,
+                Err(e) => {
+                    last_error = e;
+                    continue;
+                }
+            };
+            match response.rescode() {
+                ResultCode::NXDOMAIN => nxdomain = Some(response),
+                ResultCode::NOERROR if response.answers.is_empty() => {
+                    nodata.get_or_insert(response);
+                }
+                ResultCode::SERVFAIL | ResultCode::REFUSED => {
+                    failure.get_or_insert(response);
+                }
+                _ => return Ok(response),
+            }
+        }
+
+        match nodata.or(failure).or(nxdomain) {
+            Some(x) => Ok(x),
+            None => Err(last_error),
+        }
+    }
+
+    /// Looks up a single name as it is, trying the servers one after the other
+    /// until one answers. A server answering `SERVFAIL` or `REFUSED` is passed
+    /// over, but its answer is returned if nobody does better. Only servers
+    /// that fail to respond are asked again, up to `attempts` times.
+    pub fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let query = QueryOptions {
+            edns: self.conf.edns0,
+            timeout: Some(self.conf.timeout),
+            ..QueryOptions::default()
+        };
+
+        let mut last_response = None;
+        let mut last_error = Error::new(ErrorKind::InvalidInput, "No name servers");
+        for _ in 0..self.conf.attempts.max(1) {
+            for server in self.order() {
+                let addr = server.ip().to_string();
+                match lookup_with(qname, qtype, (&addr, server.port()), &query) {
+                    Ok(response) => match response.rescode() {
+                        ResultCode::SERVFAIL | ResultCode::REFUSED => {
+                            last_response = Some(response);
+                        }
+                        _ => return Ok(response),
+                    },
+                    Err(e) => last_error = e,
+                }
+            }
+
+            if let Some(response) = last_response {
+                return Ok(response);
+            }
+        }
+
+        Err(last_error)
+    }
+
+    // The servers in the order to ask them, starting with the next one in turn
+    // if the configuration asks for that.
+    fn order(&self) -> Vec<SocketAddr> {
+        let servers = &self.conf.nameservers;
+        let start = if self.conf.rotate {
+            self.next.fetch_add(1, Ordering::Relaxed)
+        } else {
+            0
+        };
+
+        (0..servers.len())
+            .map(|i| servers[(start + i) % servers.len()])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use super::*;
+    use crate::{BytePacketBuffer, DnsRecord, PacketBuffer};
+
+    // Answers every query with the result code `answer` picks for its name,
+    // and with 192.0.2.1 if that's NOERROR.
+    fn serve<F>(answer: F) -> SocketAddr
+    where
+        F: Fn(&str) -> ResultCode + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+            let qname = request.questions[0].name.clone();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.rescode = answer(&qname);
+            response.questions = request.questions.clone();
+            if response.header.rescode == ResultCode::NOERROR {
+                response.answers.push(DnsRecord::A {
+                    domain: qname,
+                    addr: "192.0.2.1".parse().unwrap(),
+                    ttl: 60,
+                });
+            }
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.write(&mut res_buffer).unwrap();
+            let data = res_buffer.get_range(0, res_buffer.pos()).unwrap();
+            socket.send_to(data, src).unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn it_parses_resolv_conf() {
+        let conf = ResolvConf::parse(
+            "# generated\n\
+             nameserver 192.0.2.53\n\
+             nameserver 2001:db8::53 ; second\n\
+             nameserver not-an-address\n\
+             search example.com. example.net\n\
+             options ndots:20 timeout:2 attempts:9\n\
+             options rotate edns0 inet6 ndots:x\n\
+             nameserver 192.0.2.54\n\
+             nameserver 192.0.2.55\n",
+        );
+
+        assert_eq!(
+            ResolvConf {
+                nameservers: vec![
+                    "192.0.2.53:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap(),
+                    "192.0.2.54:53".parse().unwrap(),
+                ],
+                search: vec!["example.com".to_string(), "example.net".to_string()],
+                ndots: 15,
+                timeout: Duration::from_secs(2),
+                attempts: 5,
+                rotate: true,
+                edns0: true,
+            },
+            conf
+        );
+
+        let conf = ResolvConf::parse("search example.com\ndomain example.org\n");
+        assert_eq!(vec!["example.org".to_string()], conf.search);
+        assert_eq!(ResolvConf::default(), ResolvConf::parse(""));
+    }
+
+    #[test]
+    fn it_applies_the_search_list() {
+        let conf = ResolvConf {
+            search: vec!["example.com".to_string(), "example.net".to_string()],
+            ..ResolvConf::default()
+        };
+
+        assert_eq!(
+            vec!["www.example.com", "www.example.net", "www"],
+            conf.candidates("www")
+        );
+        assert_eq!(
+            vec!["www.example.org", "www.example.org.example.com", "www.example.org.example.net"],
+            conf.candidates("www.example.org")
+        );
+        assert_eq!(vec!["www"], conf.candidates("www."));
+
+        let conf = ResolvConf { ndots: 3, ..conf };
+        assert_eq!(
+            vec!["www.example.org.example.com", "www.example.org.example.net", "www.example.org"],
+            conf.candidates("www.example.org")
+        );
+    }
+
+    #[test]
+    fn it_fails_over_between_name_servers() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let broken = serve(|_| ResultCode::SERVFAIL);
+        let working = serve(|qname| match qname {
+            "www.example.net" => ResultCode::NOERROR,
+            _ => ResultCode::NXDOMAIN,
+        });
+        let resolver = StubResolver::new(ResolvConf {
+            nameservers: vec![silent.local_addr().unwrap(), broken, working],
+            search: vec!["example.com".to_string(), "example.net".to_string()],
+            timeout: Duration::from_millis(100),
+            ..ResolvConf::default()
+        });
+
+        let response = resolver.lookup("www", QueryType::A).unwrap();
+        assert_eq!(response.get_random_a(), Some("192.0.2.1".to_string()));
+        assert_eq!("www.example.net", response.questions[0].name);
+
+        let response = resolver.lookup("mail", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, response.rescode());
+        assert_eq!("mail", response.questions[0].name);
+
+        let resolver = StubResolver::new(ResolvConf {
+            nameservers: vec![broken],
+            ..ResolvConf::default()
+        });
+        let response = resolver.lookup("www.example.net", QueryType::A).unwrap();
+        assert_eq!(ResultCode::SERVFAIL, response.rescode());
+    }
+
+    #[test]
+    fn it_moves_on_to_the_next_search_domain_after_a_failure() {
+        let server = serve(|qname| match qname {
+            x if x.ends_with(".example.com") => ResultCode::SERVFAIL,
+            "www.example.net" => ResultCode::NOERROR,
+            _ => ResultCode::NXDOMAIN,
+        });
+        let resolver = StubResolver::new(ResolvConf {
+            nameservers: vec![server],
+            search: vec!["example.com".to_string(), "example.net".to_string()],
+            ..ResolvConf::default()
+        });
+
+        let response = resolver.lookup("www", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, response.rescode());
+        assert_eq!("www.example.net", response.questions[0].name);
+
+        // Without an answer anywhere, the failure is passed on rather than
+        // the NXDOMAIN of the other names.
+        let response = resolver.lookup("mail", QueryType::A).unwrap();
+        assert_eq!(ResultCode::SERVFAIL, response.rescode());
+        assert_eq!("mail.example.com", response.questions[0].name);
+    }
+
+    #[test]
+    fn it_rotates_between_name_servers() {
+        let conf = ResolvConf::parse(
+            "nameserver 192.0.2.1\n\
+             nameserver 192.0.2.2\n\
+             options rotate\n",
+        );
+        let first: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let second: SocketAddr = "192.0.2.2:53".parse().unwrap();
+
+        let resolver = StubResolver::new(conf.clone());
+        assert_eq!(vec![first, second], resolver.order());
+        assert_eq!(vec![second, first], resolver.order());
+
+        let resolver = StubResolver::new(ResolvConf {
+            rotate: false,
+            ..conf
+        });
+        assert_eq!(vec![first, second], resolver.order());
+        assert_eq!(vec![first, second], resolver.order());
+    }
+}
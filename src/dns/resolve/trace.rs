@@ -0,0 +1,317 @@
+//! Records of how lookups arrived at their answers
+//!
+//! A lookup passed a `Trace` notes every query it sends, with the server,
+//! transport, latency and outcome, along with the referrals it follows and the
+//! name server addresses it has to look up on the way. Callers with a cache of
+//! their own note the questions they answered from it.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResultCode;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Transport::Udp => write!(f, "UDP"),
+            Transport::Tcp => write!(f, "TCP"),
+        }
+    }
+}
+
+/// A single query sent to a name server.
+#[derive(Clone, Debug)]
+pub struct QueryTrace {
+    pub qname: String,
+    pub qtype: QueryType,
+    pub server: SocketAddr,
+    pub transport: Transport,
+    pub latency: Duration,
+    /// The result code of the response, or the error if there was none.
+    pub rescode: Result<ResultCode, String>,
+    /// Whether the response had the AD bit set. Answers aren't validated
+    /// here, so this is only what the server claims.
+    pub authenticated: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum TraceEvent {
+    Query(QueryTrace),
+    /// Continuing at a name server for `zone`, at the address given as glue
+    /// in the referral if there was one.
+    Referral {
+        zone: String,
+        name_server: String,
+        glue: Option<IpAddr>,
+    },
+    /// The address of a name server had to be looked up first, as traced.
+    NameServerLookup { name_server: String, trace: Trace },
+    /// The question was answered from a cache without any queries.
+    CacheHit { qname: String, qtype: QueryType },
+}
+
+/// The steps taken to resolve a question, in order.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    pub fn push(&mut self, event: TraceEvent) {
+        self.events.push(event);
+    }
+
+    /// The queries sent, including those for name server addresses.
+    pub fn queries(&self) -> Vec<&QueryTrace> {
+        let mut queries = Vec::new();
+        for event in &self.events {
+            match *event {
+                TraceEvent::Query(ref query) => queries.push(query),
+                TraceEvent::NameServerLookup { ref trace, .. } => queries.extend(trace.queries()),
+                _ => {}
+            }
+        }
+
+        queries
+    }
+
+    /// The name servers that referrals led to, in the order they were
+    /// followed.
+    pub fn name_servers(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .filter_map(|event| match *event {
+                TraceEvent::Referral {
+                    ref name_server, ..
+                } if !name_server.is_empty() => Some(name_server.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn query(
+        &mut self,
+        query: &DnsPacket,
+        server: SocketAddr,
+        transport: Transport,
+        latency: Duration,
+        response: &std::io::Result<DnsPacket>,
+    ) {
+        let (qname, qtype) = match query.questions.first() {
+            Some(question) => (question.name.clone(), question.qtype),
+            None => (String::new(), QueryType::UNKNOWN(0)),
+        };
+        let (rescode, authenticated) = match *response {
+            Ok(ref response) => (Ok(response.rescode()), response.header.authed_data),
+            Err(ref e) => (Err(e.to_string()), false),
+        };
+
+        self.push(TraceEvent::Query(QueryTrace {
+            qname,
+            qtype,
+            server,
+            transport,
+            latency,
+            rescode,
+            authenticated,
+        }));
+    }
+
+    // Notes the referral `response` gave for `qname`, to `name_server` or to
+    // the name server `glue` belongs to.
+    pub(crate) fn referral(
+        &mut self,
+        response: &DnsPacket,
+        qname: &str,
+        name_server: Option<&str>,
+        glue: Option<IpAddr>,
+    ) {
+        let name_server = match (name_server, glue) {
+            (Some(name_server), _) => name_server.to_string(),
+            (None, Some(glue)) => response
+                .resources
+                .iter()
+                .filter_map(|rec| match *rec {
+                    DnsRecord::A { ref domain, addr, .. } if IpAddr::V4(addr) == glue => {
+                        Some(domain.clone())
+                    }
+                    _ => None,
+                })
+                .next()
+                .unwrap_or_default(),
+            (None, None) => String::new(),
+        };
+        let zone = response
+            .authorities
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::NS {
+                    ref domain,
+                    ref host,
+                    ..
+                } if *host == name_server && qname.ends_with(domain.as_str()) => {
+                    Some(domain.clone())
+                }
+                _ => None,
+            })
+            .next()
+            .unwrap_or_default();
+
+        self.push(TraceEvent::Referral {
+            zone,
+            name_server,
+            glue,
+        });
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        for event in &self.events {
+            write!(f, "{:1$}", "", indent)?;
+            match *event {
+                TraceEvent::Query(ref query) => {
+                    write!(
+                        f,
+                        "{} {} at {} over {}: ",
+                        query.qname, query.qtype, query.server, query.transport
+                    )?;
+                    match query.rescode {
+                        Ok(rescode) => write!(f, "{}", rescode)?,
+                        Err(ref e) => write!(f, "{}", e)?,
+                    }
+                    if query.authenticated {
+                        write!(f, ", authenticated")?;
+                    }
+                    writeln!(f, " in {} ms", query.latency.as_millis())?;
+                }
+                TraceEvent::Referral {
+                    ref zone,
+                    ref name_server,
+                    glue,
+                } => {
+                    write!(f, "referral for {}. to {}.", zone, name_server)?;
+                    match glue {
+                        Some(glue) => writeln!(f, " with glue {}", glue)?,
+                        None => writeln!(f, " without glue")?,
+                    }
+                }
+                TraceEvent::NameServerLookup {
+                    ref name_server,
+                    ref trace,
+                } => {
+                    writeln!(f, "looking up {}.:", name_server)?;
+                    trace.fmt_indented(f, indent + 2)?;
+                }
+                TraceEvent::CacheHit { ref qname, qtype } => {
+                    writeln!(f, "{} {} from the cache", qname, qtype)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Trace {
+    // Formats the trace with one line per event, indenting nested lookups.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::*;
+
+    fn referral() -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        });
+        response.resources.push(DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 3600,
+        });
+
+        response
+    }
+
+    #[test]
+    fn it_identifies_referrals() {
+        let response = referral();
+        let mut trace = Trace::new();
+        trace.referral(&response, "www.example.com", None, Some("192.0.2.1".parse().unwrap()));
+        trace.referral(&response, "www.example.com", Some("ns1.example.com"), None);
+
+        let zones: Vec<(&str, &str)> = trace
+            .events()
+            .iter()
+            .map(|event| match *event {
+                TraceEvent::Referral {
+                    ref zone,
+                    ref name_server,
+                    ..
+                } => (zone.as_str(), name_server.as_str()),
+                ref x => panic!("Expected a referral, got {:?}", x),
+            })
+            .collect();
+        assert_eq!(
+            vec![("example.com", "ns1.example.com"), ("example.com", "ns1.example.com")],
+            zones
+        );
+    }
+
+    #[test]
+    fn it_formats_nested_lookups() {
+        let mut query = DnsPacket::new();
+        query.questions.push(crate::DnsQuestion::new(
+            "ns1.example.net".to_string(),
+            QueryType::A,
+        ));
+        let server = "192.0.2.53:53".parse().unwrap();
+
+        let mut ns_trace = Trace::new();
+        let timeout = Err(Error::new(ErrorKind::TimedOut, "Lookup timed out"));
+        ns_trace.query(&query, server, Transport::Udp, Duration::from_millis(5), &timeout);
+
+        let mut trace = Trace::new();
+        trace.push(TraceEvent::NameServerLookup {
+            name_server: "ns1.example.net".to_string(),
+            trace: ns_trace,
+        });
+        trace.push(TraceEvent::CacheHit {
+            qname: "example.com".to_string(),
+            qtype: QueryType::MX,
+        });
+
+        assert_eq!(1, trace.queries().len());
+        assert_eq!(
+            "looking up ns1.example.net.:\n  \
+             ns1.example.net A at 192.0.2.53:53 over UDP: Lookup timed out in 5 ms\n\
+             example.com MX from the cache\n",
+            trace.to_string()
+        );
+    }
+}
@@ -0,0 +1,704 @@
+//! Reading master files as described in RFC 1035, section 5
+//!
+//! Each entry is either a directive (`$ORIGIN`, `$TTL` or `$INCLUDE`) or a
+//! resource record:
+//!
+//! ```text
+//! [owner] [ttl] [class] type rdata...
+//! ```
+//!
+//! An entry that starts with whitespace belongs to the same owner as the
+//! previous one. Names without a trailing dot are relative to the current
+//! origin, and `@` stands for the origin itself. Parentheses let an entry span
+//! several lines, and `;` starts a comment that runs to the end of the line.
+//!
+//! Besides their usual syntax, records of any type may be given in the generic
+//! syntax of RFC 3597, such as `TYPE99 \# 2 abcd` or `A \# 4 c0000201`. Other
+//! types than A, AAAA, NS, CNAME, PTR, MX and SOA can only be given that way,
+//! and records of such types in their usual syntax are skipped with a warning.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::DnsRecord;
+use crate::PacketBuffer;
+use crate::QueryType;
+use crate::VectorPacketBuffer;
+
+// How deep `$INCLUDE` directives may nest before we assume they form a loop.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Parses the records in a zone file. `origin` is the origin names are
+/// relative to until the file sets another one with `$ORIGIN`, and included
+/// files are looked up relative to the directory of `path`.
+pub fn parse_zone_file<P: AsRef<Path>>(path: P, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = ZoneParser::new(origin);
+    parser.parse_file(path.as_ref(), 0)?;
+
+    Ok(parser.records)
+}
+
+/// Parses the records in the contents of a zone file. Included files are
+/// looked up relative to the working directory.
+pub fn parse_zone(data: &str, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = ZoneParser::new(origin);
+    parser.parse(data, Path::new("."), 0)?;
+
+    Ok(parser.records)
+}
+
+/// Parses a single record in presentation format, such as
+/// `example.com. 300 IN MX 10 mail.example.com.`. Relative names are taken
+/// to be relative to the root, and the TTL is required.
+pub(crate) fn parse_record(data: &str) -> Result<DnsRecord> {
+    let mut entries = entries(data.trim_start())?.into_iter();
+    let entry = match (entries.next(), entries.next()) {
+        (Some(entry), None) => entry,
+        (None, _) => return Err(syntax(1, "no record given")),
+        (Some(_), Some(entry)) => return Err(syntax(entry.line, "more than one record given")),
+    };
+    if entry.tokens[0].starts_with('$') {
+        return Err(syntax(entry.line, "directives aren't records"));
+    }
+
+    ZoneParser::new("").record(&entry)
+}
+
+/// Parses a TTL or another period of time, either as a plain number of
+/// seconds or with units as in `1h30m`.
+pub(crate) fn parse_ttl(value: &str) -> Option<u32> {
+    if value.is_empty() || !value.as_bytes()[0].is_ascii_digit() {
+        return None;
+    }
+    if let Ok(x) = value.parse() {
+        return Some(x);
+    }
+
+    let mut total: u32 = 0;
+    let mut number: Option<u32> = None;
+    for c in value.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604_800,
+            _ => return None,
+        };
+        total = total.checked_add(number.take()?.checked_mul(unit)?)?;
+    }
+
+    // A trailing number without a unit counts as seconds.
+    total.checked_add(number.unwrap_or(0))
+}
+
+// A single entry of the file, with any parentheses already resolved.
+struct Entry {
+    line: usize,
+    // Whether the entry started with whitespace, and thus has no owner.
+    continued: bool,
+    tokens: Vec<String>,
+}
+
+// Splits a file into entries.
+fn entries(data: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (i, line) in data.lines().enumerate() {
+        let line_no = i + 1;
+        let mut chars = line.chars().peekable();
+
+        if current.is_none() {
+            current = Some(Entry {
+                line: line_no,
+                continued: line.starts_with([' ', '\t']),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().unwrap();
+
+        let mut token = String::new();
+        let mut in_token = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    // Quoted strings may contain anything but an unescaped quote.
+                    in_token = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                if let Some(x) = chars.next() {
+                                    token.push(x);
+                                }
+                            }
+                            Some(x) => token.push(x),
+                            None => return Err(syntax(line_no, "unterminated string")),
+                        }
+                    }
+                }
+                '\\' => {
+                    in_token = true;
+                    token.push(c);
+                    if let Some(x) = chars.next() {
+                        token.push(x);
+                    }
+                }
+                ';' => break,
+                '(' | ')' | ' ' | '\t' => {
+                    if in_token {
+                        entry.tokens.push(token.clone());
+                        token.clear();
+                        in_token = false;
+                    }
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        if depth == 0 {
+                            return Err(syntax(line_no, "unbalanced parentheses"));
+                        }
+                        depth -= 1;
+                    }
+                }
+                _ => {
+                    in_token = true;
+                    token.push(c);
+                }
+            }
+        }
+        if in_token {
+            entry.tokens.push(token);
+        }
+
+        // Within parentheses the entry carries on with the next line.
+        if depth == 0 {
+            let entry = current.take().unwrap();
+            if !entry.tokens.is_empty() {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if depth > 0 {
+        let line = current.map(|x| x.line).unwrap_or(0);
+        return Err(syntax(line, "unbalanced parentheses"));
+    }
+
+    Ok(entries)
+}
+
+struct ZoneParser {
+    origin: String,
+    // The TTL set by `$TTL`.
+    default_ttl: Option<u32>,
+    // The TTL of the previous record, used when there's no `$TTL`.
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    records: Vec<DnsRecord>,
+}
+
+impl ZoneParser {
+    fn new(origin: &str) -> ZoneParser {
+        ZoneParser {
+            origin: normalize(origin),
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+            records: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        self.parse(&data, dir, depth)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    fn parse(&mut self, data: &str, dir: &Path, depth: usize) -> Result<()> {
+        for entry in entries(data)? {
+            match entry.tokens[0].as_str() {
+                x if x.starts_with('$') => self.directive(&entry, dir, depth)?,
+                _ => match self.record(&entry) {
+                    Ok(record) => self.records.push(record),
+                    // Zone files commonly hold types we can't represent, such
+                    // as TXT or SRV, which shouldn't keep the rest from loading.
+                    Err(ref e) if e.kind() == ErrorKind::Unsupported => {
+                        warn!(zone = self.origin.as_str(), error:% = e; "Skipping record");
+                    }
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn directive(&mut self, entry: &Entry, dir: &Path, depth: usize) -> Result<()> {
+        let args = &entry.tokens[1..];
+        match (entry.tokens[0].to_uppercase().as_str(), args.len()) {
+            ("$ORIGIN", 1) => {
+                self.origin = self.name(&args[0], entry.line)?;
+            }
+            ("$TTL", 1) => match parse_ttl(&args[0]) {
+                Some(x) => self.default_ttl = Some(x),
+                None => return Err(syntax(entry.line, "invalid TTL")),
+            },
+            ("$INCLUDE", 1) | ("$INCLUDE", 2) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(syntax(entry.line, "too many nested includes"));
+                }
+
+                let mut path = PathBuf::from(&args[0]);
+                if path.is_relative() {
+                    path = dir.join(path);
+                }
+
+                // An origin given to the include only applies to the included
+                // file, and so does any `$ORIGIN` within it.
+                let origin = self.origin.clone();
+                if args.len() == 2 {
+                    self.origin = self.name(&args[1], entry.line)?;
+                }
+                let result = self.parse_file(&path, depth + 1);
+                self.origin = origin;
+                result?;
+            }
+            (directive, _) => {
+                return Err(syntax(
+                    entry.line,
+                    &format!("invalid directive: {}", directive),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record(&mut self, entry: &Entry) -> Result<DnsRecord> {
+        let line = entry.line;
+        let mut tokens = entry.tokens.iter().map(|x| x.as_str());
+
+        let domain = if entry.continued {
+            match self.last_owner {
+                Some(ref x) => x.clone(),
+                None => return Err(syntax(line, "record without an owner")),
+            }
+        } else {
+            self.name(tokens.next().unwrap(), line)?
+        };
+
+        // The TTL and the class are both optional, and may come in either order.
+        let mut ttl = None;
+        let mut rtype = None;
+        for token in tokens.by_ref() {
+            if let Some(x) = parse_ttl(token) {
+                if ttl.is_some() {
+                    return Err(syntax(line, "duplicate TTL"));
+                }
+                ttl = Some(x);
+                continue;
+            }
+            match token.to_uppercase().as_str() {
+                "IN" => continue,
+                "CH" | "HS" | "CS" => return Err(syntax(line, "only class IN is supported")),
+                x => {
+                    rtype = Some(x.to_string());
+                    break;
+                }
+            }
+        }
+
+        let rtype = match rtype {
+            Some(x) => x,
+            None => return Err(syntax(line, "missing record type")),
+        };
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(x) => x,
+            None => return Err(syntax(line, "no TTL given and no $TTL set")),
+        };
+        let rdata: Vec<&str> = tokens.collect();
+
+        let record = self.rdata(domain.clone(), ttl, &rtype, &rdata, line);
+
+        // A skipped record still provides the owner and TTL of the next one.
+        self.last_owner = Some(domain);
+        self.last_ttl = Some(ttl);
+
+        record
+    }
+
+    fn rdata(
+        &self,
+        domain: String,
+        ttl: u32,
+        rtype: &str,
+        rdata: &[&str],
+        line: usize,
+    ) -> Result<DnsRecord> {
+        if rdata.first() == Some(&"\\#") {
+            return self.generic(domain, ttl, rtype, &rdata[1..], line);
+        }
+
+        let expected = match rtype {
+            "A" | "AAAA" | "NS" | "CNAME" | "PTR" => 1,
+            "MX" => 2,
+            "SOA" => 7,
+            _ => return Err(unsupported(line, rtype)),
+        };
+        if rdata.len() != expected {
+            return Err(syntax(
+                line,
+                &format!("{} record needs {} fields, got {}", rtype, expected, rdata.len()),
+            ));
+        }
+
+        let number = |x: &str| parse_ttl(x).ok_or_else(|| syntax(line, "invalid number"));
+
+        Ok(match rtype {
+            "A" => DnsRecord::A {
+                domain,
+                addr: rdata[0]
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| syntax(line, "invalid IPv4 address"))?,
+                ttl,
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain,
+                addr: rdata[0]
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| syntax(line, "invalid IPv6 address"))?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: self.name(rdata[0], line)?,
+                ttl,
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                host: self.name(rdata[0], line)?,
+                ttl,
+            },
+            "PTR" => DnsRecord::PTR {
+                domain,
+                host: self.name(rdata[0], line)?,
+                ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: rdata[0]
+                    .parse()
+                    .map_err(|_| syntax(line, "invalid MX preference"))?,
+                host: self.name(rdata[1], line)?,
+                ttl,
+            },
+            _ => DnsRecord::SOA {
+                domain,
+                m_name: self.name(rdata[0], line)?,
+                r_name: self.name(rdata[1], line)?,
+                serial: rdata[2]
+                    .parse()
+                    .map_err(|_| syntax(line, "invalid serial"))?,
+                refresh: number(rdata[3])?,
+                retry: number(rdata[4])?,
+                expire: number(rdata[5])?,
+                minimum: number(rdata[6])?,
+                ttl,
+            },
+        })
+    }
+
+    // Parses data in the generic syntax of RFC 3597, a length followed by the
+    // data in hex, and decodes it like a record read from a message.
+    fn generic(
+        &self,
+        domain: String,
+        ttl: u32,
+        rtype: &str,
+        rdata: &[&str],
+        line: usize,
+    ) -> Result<DnsRecord> {
+        let qtype = match rtype.parse::<QueryType>() {
+            Ok(QueryType::OPT) | Err(_) => return Err(unsupported(line, rtype)),
+            Ok(x) => x,
+        };
+        let len = match rdata.first().map(|x| x.parse::<u16>()) {
+            Some(Ok(x)) => x as usize,
+            _ => return Err(syntax(line, "invalid data length")),
+        };
+        let data = parse_hex(&rdata[1..].concat())
+            .ok_or_else(|| syntax(line, "invalid hex data"))?;
+        if data.len() != len {
+            return Err(syntax(
+                line,
+                &format!("data is {} bytes long, not {}", data.len(), len),
+            ));
+        }
+
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname(&domain)?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_u16(len as u16)?;
+        for b in &data {
+            buffer.write_u8(*b)?;
+        }
+        buffer.seek(0)?;
+
+        DnsRecord::read(&mut buffer)
+            .map_err(|e| syntax(line, &format!("invalid {} data: {}", rtype, e)))
+    }
+
+    // Turns a name as written in the file into an absolute one, without the
+    // trailing dot like the rest of the crate uses them.
+    fn name(&self, name: &str, line: usize) -> Result<String> {
+        let absolute = if name == "@" {
+            self.origin.clone()
+        } else if name == "." {
+            String::new()
+        } else if let Some(name) = name.strip_suffix('.') {
+            name.to_lowercase()
+        } else if self.origin.is_empty() {
+            name.to_lowercase()
+        } else {
+            format!("{}.{}", name.to_lowercase(), self.origin)
+        };
+
+        let valid = absolute.is_empty()
+            || (absolute.len() <= 253
+                && absolute
+                    .split('.')
+                    .all(|label| !label.is_empty() && label.len() <= 63));
+        if !valid {
+            return Err(syntax(line, &format!("invalid name: {}", name)));
+        }
+
+        Ok(absolute)
+    }
+}
+
+fn parse_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) || !data.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn syntax(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+fn unsupported(line: usize, rtype: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("line {}: unsupported record type: {}", line, rtype),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn it_parses_a_zone() {
+        let records = parse_zone(
+            r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster.example.com. (
+            2024010101 ; serial
+            2h         ; refresh
+            15m        ; retry
+            2w         ; expire
+            300 )      ; minimum
+    IN  NS  ns1
+        NS  ns2.example.net.
+    IN  MX  10 mail
+ns1 300 IN  A   192.0.2.1
+www IN 60   AAAA 2001:db8::1
+ftp CNAME   ns1 ; a comment
+"#,
+            "",
+        )
+        .unwrap();
+
+        assert_eq!(
+            records[0],
+            DnsRecord::SOA {
+                domain: "example.com".into(),
+                m_name: "ns1.example.com".into(),
+                r_name: "hostmaster.example.com".into(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 900,
+                expire: 1_209_600,
+                minimum: 300,
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            records[2],
+            DnsRecord::NS {
+                domain: "example.com".into(),
+                host: "ns2.example.net".into(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            records[3],
+            DnsRecord::MX {
+                domain: "example.com".into(),
+                priority: 10,
+                host: "mail.example.com".into(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            records[4],
+            DnsRecord::A {
+                domain: "ns1.example.com".into(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 300,
+            }
+        );
+        assert_eq!(
+            records[5],
+            DnsRecord::AAAA {
+                domain: "www.example.com".into(),
+                addr: "2001:db8::1".parse().unwrap(),
+                ttl: 60,
+            }
+        );
+        assert_eq!(
+            records[6],
+            DnsRecord::CNAME {
+                domain: "ftp.example.com".into(),
+                host: "ns1.example.com".into(),
+                ttl: 3600,
+            }
+        );
+    }
+
+    #[test]
+    fn it_follows_includes() {
+        let dir = env::temp_dir().join(format!("dnsafe-zone-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hosts.inc"), "www A 192.0.2.2\n$ORIGIN other.\nx A 192.0.2.3\n")
+            .unwrap();
+        fs::write(
+            dir.join("example.com.zone"),
+            "$TTL 60\n$INCLUDE hosts.inc lab.example.com.\nafter A 192.0.2.4\n",
+        )
+        .unwrap();
+
+        let records = parse_zone_file(dir.join("example.com.zone"), "example.com").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = records.iter().map(|x| x.get_domain()).collect();
+        assert_eq!(vec!["www.lab.example.com", "x.other", "after.example.com"], names);
+    }
+
+    #[test]
+    fn it_parses_the_generic_syntax() {
+        let records = parse_zone(
+            "$TTL 60\n\
+             www TYPE99 \\# 5 0102 030405\n\
+             www TYPE99 \\# 0\n\
+             www A \\# 4 C0000201\n",
+            "example.com",
+        )
+        .unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::UNKNOWN {
+                    domain: "www.example.com".into(),
+                    qtype: 99,
+                    data: vec![1, 2, 3, 4, 5],
+                    ttl: 60,
+                },
+                DnsRecord::UNKNOWN {
+                    domain: "www.example.com".into(),
+                    qtype: 99,
+                    data: Vec::new(),
+                    ttl: 60,
+                },
+                DnsRecord::A {
+                    domain: "www.example.com".into(),
+                    addr: "192.0.2.1".parse().unwrap(),
+                    ttl: 60,
+                },
+            ]
+        );
+
+        assert!(parse_zone("$TTL 60\nwww TYPE99 \\# 2 01\n", "example.com").is_err());
+        assert!(parse_zone("$TTL 60\nwww TYPE99 \\# 1 0g\n", "example.com").is_err());
+        assert!(parse_zone("$TTL 60\nwww A \\# 3 c00002\n", "example.com").is_err());
+    }
+
+    #[test]
+    fn it_skips_unsupported_types() {
+        let records = parse_zone(
+            "$TTL 60\n\
+             www TXT \"v=spf1 -all\"\n\
+             \x20   A 192.0.2.1\n\
+             _sip._tcp SRV 10 5 5060 www\n",
+            "example.com",
+        )
+        .unwrap();
+
+        assert_eq!(
+            records,
+            vec![DnsRecord::A {
+                domain: "www.example.com".into(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 60,
+            }]
+        );
+        assert!("www.example.com. 60 IN TXT hello".parse::<DnsRecord>().is_err());
+    }
+
+    #[test]
+    fn it_parses_ttls_with_units() {
+        assert_eq!(Some(3600), parse_ttl("3600"));
+        assert_eq!(Some(5400), parse_ttl("1h30m"));
+        assert_eq!(Some(86_401), parse_ttl("1D1"));
+        assert_eq!(None, parse_ttl("1x"));
+        assert_eq!(None, parse_ttl("h"));
+        assert_eq!(None, parse_ttl("99999999999"));
+    }
+
+    #[test]
+    fn it_reports_errors_with_their_line() {
+        let err = parse_zone("$TTL 60\nwww A 192.0.2\n", "example.com").unwrap_err();
+        assert_eq!("line 2: invalid IPv4 address", err.to_string());
+
+        assert!(parse_zone("www A 192.0.2.1\n", "example.com").is_err());
+        assert!(parse_zone("$TTL 60\nwww MX ( 10\n", "example.com").is_err());
+        assert!(parse_zone("$TTL 60\n  A 192.0.2.1\n", "example.com").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Serving zones authoritatively
+//!
+//! A `Zone` holds the records of a single zone in memory and answers queries
+//! for the names in it the way RFC 1034 (section 4.3.2) describes: with the
+//! matching records, a referral where part of the zone is delegated, or a
+//! negative answer carrying the zone's SOA record.
+
+mod file;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResultCode;
+
+pub use self::file::{parse_zone, parse_zone_file};
+pub(crate) use self::file::parse_record;
+
+// How many CNAME records within the zone are followed for a single answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The records of a single zone.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    origin: String,
+    records: BTreeMap<String, Vec<DnsRecord>>,
+    // Every name that exists in the zone, including names that only exist
+    // because there are records below them (empty non-terminals).
+    names: HashSet<String>,
+}
+
+impl Zone {
+    /// Builds a zone from its records, which have to include exactly one SOA
+    /// record at the origin, and nothing outside the zone.
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Zone> {
+        let origin = normalize(origin);
+
+        let mut zone = Zone {
+            origin: origin.clone(),
+            records: BTreeMap::new(),
+            names: HashSet::new(),
+        };
+        for record in records {
+            let domain = normalize(record.get_domain());
+            if !in_zone(&domain, &origin) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is outside of zone {}", domain, origin),
+                ));
+            }
+            if let DnsRecord::OPT { .. } = record {
+                continue;
+            }
+
+            // Register the name along with all of its ancestors within the zone.
+            let mut name = domain.as_str();
+            while zone.names.insert(name.to_string()) && name != origin {
+                name = parent(name);
+            }
+
+            let rrset = zone.records.entry(domain).or_default();
+            if !rrset.contains(&record) {
+                rrset.push(record);
+            }
+        }
+
+        let soa_count = zone.rrset(&origin, QueryType::SOA).count();
+        if soa_count != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("zone {} needs one SOA record, found {}", origin, soa_count),
+            ));
+        }
+
+        Ok(zone)
+    }
+
+    /// Reads a zone from a zone file.
+    pub fn from_file<P: AsRef<Path>>(path: P, origin: &str) -> Result<Zone> {
+        Zone::new(origin, parse_zone_file(path, origin)?)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        self.rrset(&self.origin, QueryType::SOA).next().unwrap()
+    }
+
+    /// Every record in the zone, ordered by owner name.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.values().flatten()
+    }
+
+    /// Answers a query for a name within the zone. The answer is
+    /// authoritative unless the name has been delegated, in which case the
+    /// response refers the client to the delegated name servers instead.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        self.answer_into(&normalize(qname), qtype, &mut packet, 0);
+        packet
+    }
+
+    fn answer_into(&self, qname: &str, qtype: QueryType, packet: &mut DnsPacket, depth: usize) {
+        // Walking down from the origin, the first name with NS records is
+        // where the zone ends and somebody else becomes authoritative.
+        if let Some(cut) = self.zone_cut(qname) {
+            let ns: Vec<DnsRecord> = self.rrset(cut, QueryType::NS).cloned().collect();
+            self.add_glue(&ns, packet);
+            packet.authorities.extend(ns);
+            return;
+        }
+
+        packet.header.authoritative_answer = true;
+
+        let node = if self.names.contains(qname) {
+            Some(qname.to_string())
+        } else {
+            self.wildcard_for(qname)
+        };
+        let node = match node {
+            Some(x) => x,
+            None => {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                packet.authorities.push(self.negative_soa());
+                return;
+            }
+        };
+
+        // Records matched through a wildcard take the name that was asked for.
+        let owned = |record: &DnsRecord| {
+            let mut record = record.clone();
+            record.set_domain(qname);
+            record
+        };
+
+        let answers: Vec<DnsRecord> = self.rrset(&node, qtype).map(owned).collect();
+        if !answers.is_empty() {
+            self.add_glue(&answers, packet);
+            packet.answers.extend(answers);
+            return;
+        }
+
+        let cname = self.rrset(&node, QueryType::CNAME).map(owned).next();
+        if let (Some(cname), true) = (cname, qtype != QueryType::CNAME) {
+            let target = match cname {
+                DnsRecord::CNAME { ref host, .. } => normalize(host),
+                _ => unreachable!(),
+            };
+            packet.answers.push(cname);
+
+            // The client would ask us for the target next anyway, so if we
+            // happen to be authoritative for it we can answer right away.
+            let seen = packet.answers.iter().any(|x| x.get_domain() == target);
+            if in_zone(&target, &self.origin) && !seen && depth < MAX_CNAME_CHAIN {
+                self.answer_into(&target, qtype, packet, depth + 1);
+            }
+            return;
+        }
+
+        // The name exists, just without records of the requested type.
+        packet.authorities.push(self.negative_soa());
+    }
+
+    // The delegation point at or above `qname`, if it lies below the origin.
+    fn zone_cut(&self, qname: &str) -> Option<&str> {
+        let relative = qname.strip_suffix(&self.origin)?;
+
+        let mut cut = None;
+        let mut name = qname;
+        let mut remaining = relative.trim_end_matches('.');
+        while !remaining.is_empty() {
+            if self.rrset(name, QueryType::NS).next().is_some() {
+                cut = self.records.get_key_value(name).map(|(k, _)| k.as_str());
+            }
+            remaining = match remaining.find('.') {
+                Some(i) => &remaining[i + 1..],
+                None => "",
+            };
+            name = parent(name);
+        }
+
+        // The loop went from the bottom up, so `cut` is the topmost one.
+        cut
+    }
+
+    // The wildcard that synthesizes `qname`, if any. Wildcards only apply
+    // below the closest existing ancestor of the name (RFC 4592).
+    fn wildcard_for(&self, qname: &str) -> Option<String> {
+        let mut encloser = qname;
+        while encloser != self.origin && !self.names.contains(encloser) {
+            encloser = parent(encloser);
+        }
+
+        let wildcard = if encloser.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+        if self.records.contains_key(&wildcard) {
+            Some(wildcard)
+        } else {
+            None
+        }
+    }
+
+    // Adds the addresses of the name servers and mail exchangers in `records`
+    // to the additional section, so clients don't have to look them up.
+    fn add_glue(&self, records: &[DnsRecord], packet: &mut DnsPacket) {
+        for record in records {
+            let host = match *record {
+                DnsRecord::NS { ref host, .. } | DnsRecord::MX { ref host, .. } => host,
+                _ => continue,
+            };
+            let glue = self
+                .rrset(host, QueryType::A)
+                .chain(self.rrset(host, QueryType::AAAA));
+            for glue in glue {
+                if !packet.resources.contains(glue) {
+                    packet.resources.push(glue.clone());
+                }
+            }
+        }
+    }
+
+    // Negative answers are cached for the lesser of the SOA's own TTL and its
+    // minimum field (RFC 2308).
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = soa {
+            soa.set_ttl(minimum.min(ttl));
+        }
+        soa
+    }
+
+    fn rrset<'a>(&'a self, name: &str, qtype: QueryType) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |x| x.get_querytype() == qtype)
+    }
+}
+
+/// The zones a server is authoritative for.
+#[derive(Clone, Debug, Default)]
+pub struct ZoneStore {
+    zones: HashMap<String, Zone>,
+}
+
+impl ZoneStore {
+    pub fn new() -> ZoneStore {
+        ZoneStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// Adds a zone, replacing any previous zone with the same origin.
+    pub fn add(&mut self, zone: Zone) {
+        self.zones.insert(zone.origin.clone(), zone);
+    }
+
+    /// The most specific zone `qname` falls under.
+    pub fn zone_for(&self, qname: &str) -> Option<&Zone> {
+        let qname = normalize(qname);
+        let mut name = qname.as_str();
+
+        loop {
+            if let Some(zone) = self.zones.get(name) {
+                return Some(zone);
+            }
+            if name.is_empty() {
+                return None;
+            }
+            name = parent(name);
+        }
+    }
+
+    /// Answers a query from the zone it falls under, or returns `None` if we
+    /// aren't authoritative for the name.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.zone_for(qname).map(|zone| zone.answer(qname, qtype))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn parent(name: &str) -> &str {
+    match name.find('.') {
+        Some(i) => &name[i + 1..],
+        None => "",
+    }
+}
+
+fn in_zone(name: &str, origin: &str) -> bool {
+    origin.is_empty()
+        || name == origin
+        || (name.ends_with(origin) && name[..name.len() - origin.len()].ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone() -> Zone {
+        let records = parse_zone(
+            r#"
+$TTL 3600
+@        SOA   ns1 hostmaster 1 7200 900 1209600 300
+@        NS    ns1
+@        MX    10 mail
+ns1      A     192.0.2.1
+mail     A     192.0.2.2
+www      CNAME web
+web      A     192.0.2.3
+ext      CNAME www.example.net.
+*.dyn    A     192.0.2.4
+a.b.deep A     192.0.2.5
+sub      NS    ns.sub
+ns.sub   A     192.0.2.6
+"#,
+            "example.com",
+        )
+        .unwrap();
+
+        Zone::new("example.com", records).unwrap()
+    }
+
+    fn a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.into(),
+            addr: addr.parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn it_answers_authoritatively() {
+        let packet = zone().answer("Web.Example.com.", QueryType::A);
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(vec![a("web.example.com", "192.0.2.3")], packet.answers);
+    }
+
+    #[test]
+    fn it_adds_addresses_of_mail_exchangers() {
+        let packet = zone().answer("example.com", QueryType::MX);
+
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(vec![a("mail.example.com", "192.0.2.2")], packet.resources);
+    }
+
+    #[test]
+    fn it_follows_cnames_within_the_zone() {
+        let packet = zone().answer("www.example.com", QueryType::A);
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(a("web.example.com", "192.0.2.3"), packet.answers[1]);
+
+        // Targets outside the zone are left for the client to resolve.
+        let packet = zone().answer("ext.example.com", QueryType::A);
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+    }
+
+    #[test]
+    fn it_refers_to_delegated_zones() {
+        let packet = zone().answer("www.sub.example.com", QueryType::A);
+
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            vec![DnsRecord::NS {
+                domain: "sub.example.com".into(),
+                host: "ns.sub.example.com".into(),
+                ttl: 3600,
+            }],
+            packet.authorities
+        );
+        assert_eq!(vec![a("ns.sub.example.com", "192.0.2.6")], packet.resources);
+    }
+
+    #[test]
+    fn it_answers_negatively_with_the_soa() {
+        let zone = zone();
+
+        let nxdomain = zone.answer("missing.example.com", QueryType::A);
+        assert_eq!(ResultCode::NXDOMAIN, nxdomain.header.rescode);
+        assert_eq!(Some(300), nxdomain.authorities[0].get_ttl());
+
+        let nodata = zone.answer("web.example.com", QueryType::AAAA);
+        assert_eq!(ResultCode::NOERROR, nodata.header.rescode);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(QueryType::SOA, nodata.authorities[0].get_querytype());
+
+        // Names with nothing but children exist too.
+        let empty = zone.answer("b.deep.example.com", QueryType::A);
+        assert_eq!(ResultCode::NOERROR, empty.header.rescode);
+    }
+
+    #[test]
+    fn it_synthesizes_wildcard_answers() {
+        let zone = zone();
+
+        let packet = zone.answer("host.dyn.example.com", QueryType::A);
+        assert_eq!(vec![a("host.dyn.example.com", "192.0.2.4")], packet.answers);
+
+        let packet = zone.answer("a.host.dyn.example.com", QueryType::A);
+        assert_eq!(1, packet.answers.len());
+
+        // The wildcard doesn't cover names that exist.
+        let packet = zone.answer("b.deep.example.com", QueryType::A);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_invalid_zones() {
+        assert!(Zone::new("example.com", vec![a("example.org", "192.0.2.1")]).is_err());
+        assert!(Zone::new("example.com", vec![a("example.com", "192.0.2.1")]).is_err());
+    }
+
+    #[test]
+    fn it_picks_the_most_specific_zone() {
+        let soa = |origin: &str| parse_zone("@ 60 SOA ns hostmaster 1 1 1 1 1\n", origin).unwrap();
+        let mut store = ZoneStore::new();
+        store.add(Zone::new("example.com", soa("example.com")).unwrap());
+        store.add(Zone::new("sub.example.com", soa("sub.example.com")).unwrap());
+
+        assert_eq!("sub.example.com", store.zone_for("a.sub.example.com").unwrap().origin());
+        assert_eq!("example.com", store.zone_for("example.com").unwrap().origin());
+        assert!(store.zone_for("example.org").is_none());
+    }
+}
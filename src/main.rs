@@ -1,25 +1,350 @@
 extern crate dnsafe;
+extern crate env_logger;
+extern crate getopts;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate socket2;
+extern crate toml;
 
-use std::net::UdpSocket;
+mod config;
+mod metrics;
 
-use dnsafe::{recursive_lookup, BytePacketBuffer, DnsPacket, PacketBuffer, ResultCode};
+use std::env;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use dnsafe::{metrics, recursive_lookup_traced, set_dnstap, set_lookup_timeout, set_root_server,
+             AccessControl, BytePacketBuffer, Cache, ClientSubnetConfig, CookieStatus, DnsHeader,
+             DnsPacket, DnsQuestion, DnsRecord, Dnstap, DnstapMessage, DnstapMessageType,
+             EdnsOption, ExtendedErrorCode, Forwarder, HostsFile, InflightQueries, Opcode,
+             PacketBuffer, Policy, PolicyAction, PolicyMatch, RateLimitAction, RateLimiter,
+             ResponseKind, ResultCode, ServerCookieSecret, Trace, TraceEvent, Transport,
+             VectorPacketBuffer, ZoneRoutes, ZoneStore};
+
+use crate::config::{Command, Config, Mode};
+
+// How often the hosts file is checked for changes. Checking is cheap, and
+// edits are expected to show up right away.
+const HOSTS_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// TCP connections are closed after being idle for this long, and no more than
+// this many are kept open at once.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_MAX_CONNECTIONS: usize = 256;
+
+// State shared by every request the server handles.
+struct ServerContext {
+    cookie_secret: ServerCookieSecret,
+    cache: Cache,
+    inflight: InflightQueries<Resolution>,
+    client_subnet: Option<ClientSubnetConfig>,
+    // Set in forwarding mode, in which case queries go to the upstreams
+    // instead of being resolved recursively.
+    forwarder: Option<Forwarder>,
+    // Zones whose queries go to particular servers regardless of the mode.
+    routes: ZoneRoutes,
+    // Zones we answer for ourselves.
+    zones: ZoneStore,
+    // Names we answer for ourselves ahead of the zones, from a hosts file.
+    hosts: Option<HostsFile>,
+    // Whether we resolve names outside of our own zones.
+    recursion: bool,
+    // Whether every query answered is logged.
+    query_log: bool,
+    // Limits the responses sent to each client network, if enabled.
+    rate_limiter: Option<RateLimiter>,
+    // Where queries and responses are logged in dnstap format, if anywhere.
+    dnstap: Option<Arc<Dnstap>>,
+    // Blocklists and response policy zones applied to the names we resolve.
+    policy: Option<Policy>,
+}
+
+// A resolved packet, and the names of the name servers that referrals led to
+// on the way.
+type Resolution = (DnsPacket, Vec<String>);
+
+// The sockets we accept queries on at one address, over UDP and TCP, and who
+// may ask what of them.
+struct Listener {
+    socket: UdpSocket,
+    tcp: TcpListener,
+    access: AccessControl,
+}
+
+// A received query waiting for a worker, along with the listener to answer on.
+struct Job {
+    req_buffer: BytePacketBuffer,
+    len: usize,
+    src: SocketAddr,
+    listener: Arc<Listener>,
+    // The connection a query came in on over TCP, which the response goes
+    // back on. Queries without one came over UDP.
+    stream: Option<Arc<Mutex<TcpStream>>>,
+    received: Instant,
+}
+
+impl Job {
+    fn transport(&self) -> Transport {
+        match self.stream {
+            Some(_) => Transport::Tcp,
+            None => Transport::Udp,
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match self.stream {
+            Some(ref stream) => stream.lock().unwrap().local_addr(),
+            None => self.listener.socket.local_addr(),
+        }
+    }
+}
+
+// Whether a query was answered from the cache, for the query log. Queries
+// answered without consulting it, such as those for our own zones, skip it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CacheStatus {
+    Hit,
+    Miss,
+    Skipped,
+}
+
+impl CacheStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Skipped => "skipped",
+        }
+    }
+}
 
 fn main() {
-    // Bind an UDP socket on port 2053
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).unwrap();
+    let args: Vec<String> = env::args().collect();
+    let config = match Config::from_args(&args[0], &args[1..]) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::Help(usage)) => {
+            print!("{}", usage);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    env_logger::Builder::from_default_env()
+        .filter_level(config.log_level.filter())
+        .init();
+
+    if let Err(e) = run(&config) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(config: &Config) -> Result<()> {
+    set_lookup_timeout(Duration::from_secs(config.timeout));
+    if let Some(server) = config.root_server {
+        set_root_server(server);
+    }
+    // Our own lookups are logged along with the queries we answer.
+    let dnstap = config.dnstap()?.map(Arc::new);
+    if let Some(ref dnstap) = dnstap {
+        set_dnstap(dnstap.clone());
+    }
+
+    let context = Arc::new(ServerContext {
+        // The secret for issuing server cookies only has to survive as long as the
+        // process, clients simply pick up a new cookie after a restart.
+        cookie_secret: ServerCookieSecret::random(),
+        cache: Cache::new(config.cache_size),
+        inflight: InflightQueries::default(),
+        client_subnet: config.client_subnet(),
+        forwarder: match config.mode {
+            Mode::Forwarding => Some(Forwarder::new(
+                config.forwarder_addrs()?,
+                config.forward_selection.upstream_selection(),
+            )),
+            _ => None,
+        },
+        routes: config.zone_routes()?,
+        zones: config.zone_store()?,
+        hosts: config.hosts()?,
+        recursion: config.mode != Mode::Authoritative,
+        query_log: config.query_log,
+        rate_limiter: config.rate_limit().map(RateLimiter::new),
+        dnstap,
+        policy: config.policy()?,
+    });
 
-    println!("Listening on 0.0.0.0:2053");
+    // Bind every socket before answering anything, so a typo in one of the
+    // addresses doesn't leave us running half-configured.
+    let mut listeners = Vec::new();
+    for (addr, access) in config.listeners()? {
+        let (socket, tcp) =
+            bind(addr).map_err(|e| Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+        info!(addr:%; "Listening");
+        listeners.push(Arc::new(Listener {
+            socket,
+            tcp,
+            access,
+        }));
+    }
 
-    // For now, queries are handled sequentially, so an infinite loop for servicing
-    // requests is initiated.
+    if let Some(addr) = config.metrics_addr()? {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+        info!(addr:%; "Serving metrics");
+
+        let context = context.clone();
+        thread::Builder::new()
+            .name("dnsafe-metrics".to_string())
+            .spawn(move || metrics::serve(listener, context))?;
+    }
+
+    if context.hosts.is_some() {
+        let context = context.clone();
+        thread::Builder::new()
+            .name("dnsafe-hosts".to_string())
+            .spawn(move || loop {
+                thread::sleep(HOSTS_CHECK_INTERVAL);
+                let result = match context.hosts {
+                    Some(ref hosts) => hosts.reload(),
+                    None => return,
+                };
+                match result {
+                    Ok(true) => info!("Reloaded hosts file"),
+                    Ok(false) => {}
+                    Err(e) => warn!(error:% = e; "Failed to reload hosts file"),
+                }
+            })?;
+    }
+
+    // Policy files are checked for changes every so often, and reloaded
+    // while queries carry on with the previous version.
+    if context.policy.is_some() && config.policy_reload_interval > 0 {
+        let interval = Duration::from_secs(config.policy_reload_interval);
+        let context = context.clone();
+        thread::Builder::new()
+            .name("dnsafe-policy".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                if let Some(ref policy) = context.policy {
+                    policy.reload();
+                }
+            })?;
+    }
+
+    for handle in serve(listeners, context, config.workers, config.queue_size)? {
+        let _ = handle.join();
+    }
+
+    Ok(())
+}
+
+// Starts answering queries on `listeners`, returning the threads receiving
+// them.
+fn serve(
+    listeners: Vec<Arc<Listener>>,
+    context: Arc<ServerContext>,
+    workers: usize,
+    queue_size: usize,
+) -> Result<Vec<JoinHandle<()>>> {
+    // Received queries are handed to a fixed pool of workers through a bounded
+    // queue, so a slow lookup only occupies a single worker, and a burst of them
+    // can't make us buffer an unbounded number of queries.
+    let (sender, receiver) = sync_channel::<Job>(queue_size);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for i in 0..workers {
+        let context = context.clone();
+        let receiver = receiver.clone();
+
+        thread::Builder::new()
+            .name(format!("dnsafe-worker-{}", i))
+            .spawn(move || loop {
+                // The lock is released at the end of the statement, so the other
+                // workers can pick up queries while this one is busy.
+                let job = receiver.lock().unwrap().recv();
+                let mut job = match job {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+
+                handle_packet(&mut job, &context);
+            })?;
+    }
+
+    // Each socket gets a thread of its own that does nothing but receive, and
+    // each TCP connection one that reads the queries sent over it.
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mut handles = Vec::new();
+    for listener in listeners {
+        let addr = listener.socket.local_addr()?;
+
+        let udp_sender = sender.clone();
+        let udp_listener = listener.clone();
+        handles.push(
+            thread::Builder::new()
+                .name(format!("dnsafe-listener-{}", addr))
+                .spawn(move || receive(udp_listener, udp_sender))?,
+        );
+
+        let tcp_sender = sender.clone();
+        let connections = connections.clone();
+        handles.push(
+            thread::Builder::new()
+                .name(format!("dnsafe-tcp-listener-{}", addr))
+                .spawn(move || accept(listener, tcp_sender, connections))?,
+        );
+    }
+
+    Ok(handles)
+}
+
+// Binds a UDP socket to `addr`, and a TCP socket to the same address and port.
+// IPv6 sockets only accept IPv6 traffic, so that `0.0.0.0` and `::` can be
+// listened on side by side.
+fn bind(addr: SocketAddr) -> Result<(UdpSocket, TcpListener)> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    let socket: UdpSocket = socket.into();
+
+    // With port 0, the TCP socket takes the port picked for the UDP one.
+    let addr = socket.local_addr()?;
+    let tcp = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        tcp.set_only_v6(true)?;
+    }
+    tcp.set_reuse_address(true)?;
+    tcp.bind(&addr.into())?;
+    tcp.listen(128)?;
+
+    Ok((socket, tcp.into()))
+}
+
+// Reads queries from the socket of `listener` and queues them for the workers.
+fn receive(listener: Arc<Listener>, sender: SyncSender<Job>) {
     loop {
         // With a socket ready, we can go ahead and read a packet. This will
         // block until one is received.
         let mut req_buffer = BytePacketBuffer::new();
-        let (_, src) = match socket.recv_from(&mut req_buffer.buf) {
+        let (len, src) = match listener.socket.recv_from(&mut req_buffer.buf) {
             Ok(x) => x,
             Err(e) => {
-                println!("Failed to read from UDP socket: {:?}", e);
+                warn!(error:% = e; "Failed to read from UDP socket");
                 continue;
             }
         };
@@ -28,86 +353,829 @@ fn main() {
         // the raw bytes are simply returned, and if not it'll abort by restarting the
         // loop and waiting for the next request. The `recv_from` function will write the
         // data into the provided buffer, and return the length of the data read as well
-        // as the source adress. We're not interested in the length, but we need to keep
-        // track of the source in order to send our reply later on.
+        // as the source adress. We need to keep track of the source in order to send our
+        // reply later on, and of the length to log the query as it was received.
 
-        // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
-        // a `DnsPacket`. It uses the same error handling idiom as the previous statement.
+        let job = Job {
+            req_buffer,
+            len,
+            src,
+            listener: listener.clone(),
+            stream: None,
+            received: Instant::now(),
+        };
+        match sender.try_send(job) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(client:% = src; "Dropping query, all workers are busy");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("All workers have stopped");
+                return;
+            }
+        }
+    }
+}
 
-        let request = match DnsPacket::from_buffer(&mut req_buffer) {
+// Accepts TCP connections on `listener`, reading each on a thread of its own.
+fn accept(listener: Arc<Listener>, sender: SyncSender<Job>, connections: Arc<AtomicUsize>) {
+    for stream in listener.tcp.incoming() {
+        let stream = match stream {
             Ok(x) => x,
             Err(e) => {
-                println!("Failed to parse UDP query packet: {:?}", e);
+                warn!(error:% = e; "Failed to accept TCP connection");
                 continue;
             }
         };
 
-        // Create and initialize the response packet
-        let mut packet = DnsPacket::new();
-        packet.header.id = request.header.id;
-        packet.header.recursion_desired = true;
-        packet.header.recursion_available = true;
-        packet.header.response = true;
-
-        // Being mindful of how unreliable input data from arbitrary senders can be, we
-        // need make sure that a question is actually present. If not, we return `FORMERR`
-        // to indicate that the sender made something wrong.
-        if request.questions.is_empty() {
-            packet.header.rescode = ResultCode::FORMERR;
+        if connections.fetch_add(1, Ordering::SeqCst) >= TCP_MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(client:? = stream.peer_addr().ok(); "Closing TCP connection, too many are open");
+            continue;
         }
-        // Usually a question will be present, though.
-        else {
-            let question = &request.questions[0];
-            println!("Received query: {:?}", question);
-            if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
-                packet.questions.push(question.clone());
-                packet.header.rescode = result.header.rescode;
-                packet.questions.push(question.clone());
-                packet.header.rescode = result.header.rescode;
-
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    packet.answers.push(rec);
-                }
-                for rec in result.authorities {
-                    println!("Authority: {:?}", rec);
-                    packet.authorities.push(rec);
-                }
-                for rec in result.resources {
-                    println!("Resource: {:?}", rec);
-                    packet.resources.push(rec);
+
+        let listener = listener.clone();
+        let sender = sender.clone();
+        let connections = connections.clone();
+        let spawned = thread::Builder::new()
+            .name("dnsafe-tcp-connection".to_string())
+            .spawn(move || {
+                if let Err(e) = read_connection(stream, listener, &sender) {
+                    debug!(error:% = e; "Closing TCP connection");
                 }
-            } else {
-                packet.header.rescode = ResultCode::SERVFAIL;
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            warn!(error:% = e; "Failed to start TCP connection thread");
+        }
+    }
+}
+
+// Reads the queries sent over a TCP connection and queues them for the
+// workers, until the client closes it or leaves it idle. Every message is
+// preceded by its length.
+fn read_connection(
+    mut stream: TcpStream,
+    listener: Arc<Listener>,
+    sender: &SyncSender<Job>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let src = stream.peer_addr()?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
+    loop {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        // Queries are small, so anything that doesn't fit into a datagram
+        // isn't one we'd answer anyway.
+        let len = u16::from_be_bytes(len) as usize;
+        let mut req_buffer = BytePacketBuffer::new();
+        if len > req_buffer.buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Query too long"));
+        }
+        stream.read_exact(&mut req_buffer.buf[0..len])?;
+
+        let job = Job {
+            req_buffer,
+            len,
+            src,
+            listener: listener.clone(),
+            stream: Some(writer.clone()),
+            received: Instant::now(),
+        };
+        // Unlike datagrams, TCP queries wait for a free worker, which keeps
+        // the client from sending more in the meantime.
+        if sender.send(job).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Parses a single query, resolves it and sends the response back to its sender.
+fn handle_packet(job: &mut Job, context: &ServerContext) {
+    let src = job.src;
+    let transport = job.transport();
+    let req_buffer = &mut job.req_buffer;
+    let mut cache_status = CacheStatus::Skipped;
+    let mut cookie_status = CookieStatus::Missing;
+
+    // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
+    // a `DnsPacket`. It uses the same error handling idiom as the previous statement.
+
+    let mut packet = match DnsPacket::from_buffer(req_buffer) {
+        Ok(request) => {
+            // A valid server cookie proves that the client has talked to us from
+            // the same address before, which an off-path attacker spoofing it
+            // can't do.
+            cookie_status = context.cookie_secret.check(request.edns_options(), src.ip());
+            let access = &job.listener.access;
+            match handle_request(&request, src, cookie_status, access, context, &mut cache_status)
+            {
+                Some(x) => x,
+                None => return,
             }
+        }
+        Err(e) => {
+            warn!(client:% = src, transport:%, error:% = e; "Failed to parse query packet");
+            match handle_malformed_request(req_buffer, src, &job.listener.access, context) {
+                Some(x) => x,
+                None => return,
+            }
+        }
+    };
 
-            // The only thing remaining is to encode our response and send it off!
+    // Responses to clients with a valid cookie can't be reflected at anybody
+    // else, so they're never limited, and neither are responses over TCP,
+    // where the client has to have completed a handshake with us.
+    if let Some(ref rate_limiter) = context.rate_limiter {
+        if transport == Transport::Udp
+            && cookie_status != CookieStatus::Valid
+            && !rate_limit(rate_limiter, src, &mut packet)
+        {
+            return;
+        }
+    }
 
-            let mut res_buffer = BytePacketBuffer::new();
-            match packet.write(&mut res_buffer) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Failed to encode UDP response packet: {:?}", e);
-                    continue;
-                }
-            };
+    // The only thing remaining is to encode our response and send it off!
 
-            let len = res_buffer.pos();
-            let data = match res_buffer.get_range(0, len) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Failed to retrieve response buffer: {:?}", e);
-                    continue;
-                }
-            };
+    let data = match encode(&mut packet, transport) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(client:% = src, transport:%, error:% = e; "Failed to encode response packet");
+            return;
+        }
+    };
+
+    let sent = match job.stream {
+        Some(ref stream) => {
+            let mut message = (data.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(&data);
+            stream.lock().unwrap().write_all(&message)
+        }
+        None => job.listener.socket.send_to(&data, src).map(|_| ()),
+    };
+    if let Err(e) = sent {
+        warn!(client:% = src, transport:%, error:% = e; "Failed to send response");
+    }
 
-            match socket.send_to(data, src) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Failed to send response buffer: {:?}", e);
-                    continue;
+    if let Some(ref dnstap) = context.dnstap {
+        // Answers from our own zones are logged as those of an authoritative
+        // server, and everything else as those of a resolver.
+        let role = if packet.header.authoritative_answer || !context.recursion {
+            DnstapMessageType::AuthQuery
+        } else {
+            DnstapMessageType::ClientQuery
+        };
+        log_dnstap(dnstap, role, job, &data);
+    }
+
+    let qtype = match packet.questions.first() {
+        Some(question) => question.qtype.to_string(),
+        None => "none".to_string(),
+    };
+    let rcode = packet.rescode().to_string();
+    metrics().record_query(&qtype, &rcode, &transport.to_string(), job.received.elapsed());
+
+    if context.query_log {
+        log_query(src, &packet, job.received.elapsed(), cache_status);
+    }
+}
+
+// Applies the rate limit to `packet`, returning whether it should be sent. A
+// response that slips through is truncated, so the client retries over TCP.
+fn rate_limit(rate_limiter: &RateLimiter, src: SocketAddr, packet: &mut DnsPacket) -> bool {
+    let kind = ResponseKind::of(packet);
+    let action = rate_limiter.check(src.ip(), kind);
+    if action == RateLimitAction::Send {
+        return true;
+    }
+    if rate_limiter.config().log_only {
+        info!(client:% = src, kind:%, action:?; "Would rate limit response");
+        return true;
+    }
+    debug!(client:% = src, kind:%, action:?; "Rate limiting response");
+
+    match action {
+        RateLimitAction::Drop => false,
+        _ => {
+            packet.header.truncated_message = true;
+            packet.answers.clear();
+            packet.authorities.clear();
+            packet
+                .resources
+                .retain(|rec| matches!(*rec, DnsRecord::OPT { .. }));
+            true
+        }
+    }
+}
+
+// Logs the query of `job`, and the response sent back, to dnstap.
+fn log_dnstap(dnstap: &Dnstap, role: DnstapMessageType, job: &Job, response: &[u8]) {
+    let local = match job.local_addr() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    let (transport, src) = (job.transport(), job.src);
+    let query_time = SystemTime::now() - job.received.elapsed();
+
+    if dnstap.wants(role) {
+        let query = &job.req_buffer.buf[0..job.len];
+        let message = DnstapMessage::query(role, transport, src, local, query_time, query);
+        dnstap.log(message);
+    }
+    if dnstap.wants(role.response()) {
+        let message = DnstapMessage::response(role, transport, src, local, query_time, response);
+        dnstap.log(message);
+    }
+}
+
+// Encodes a response. Datagrams are limited to 512 bytes, while messages over
+// TCP may be up to 64k long.
+fn encode(packet: &mut DnsPacket, transport: Transport) -> Result<Vec<u8>> {
+    match transport {
+        Transport::Udp => {
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer)?;
+            let len = buffer.pos();
+            Ok(buffer.get_range(0, len)?.to_vec())
+        }
+        Transport::Tcp => {
+            let mut buffer = VectorPacketBuffer::new();
+            packet.write(&mut buffer)?;
+            Ok(buffer.buffer)
+        }
+    }
+}
+
+// Logs a line about an answered query, with its details as structured fields.
+fn log_query(src: SocketAddr, packet: &DnsPacket, latency: Duration, cache_status: CacheStatus) {
+    let (qname, qtype, qclass) = match packet.questions.first() {
+        Some(question) => (question.name.as_str(), question.qtype.to_string(), question.qclass),
+        None => ("", String::new(), 0),
+    };
+
+    info!(
+        target: "dnsafe::query",
+        client:% = src,
+        qname,
+        qtype = qtype.as_str(),
+        qclass,
+        rcode:% = packet.rescode(),
+        latency_ms = latency.as_millis() as u64,
+        cache = cache_status.as_str();
+        "Answered query"
+    );
+}
+
+// Builds the response for a single request, if it's to be answered at all. The
+// opcode decides how the rest of the message has to be interpreted, so anything
+// other than a standard QUERY is answered with `NOTIMP` rather than being
+// mistaken for a lookup.
+fn handle_request(
+    request: &DnsPacket,
+    src: SocketAddr,
+    cookie_status: CookieStatus,
+    access: &AccessControl,
+    context: &ServerContext,
+    cache_status: &mut CacheStatus,
+) -> Option<DnsPacket> {
+    // Create and initialize the response packet
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = context.recursion && access.recursion.allows(src.ip());
+    packet.header.response = true;
+
+    debug!(client:% = src, cookie:? = cookie_status; "Received request");
+
+    match request.header.opcode {
+        _ if !access.query.allows(src.ip()) => {
+            info!(client:% = src; "Refusing query");
+            packet.questions = request.questions.clone();
+            packet.header.rescode = ResultCode::REFUSED;
+        }
+        _ if cookie_status == CookieStatus::Malformed => {
+            packet.questions = request.questions.clone();
+            packet.header.rescode = ResultCode::FORMERR;
+        }
+        Opcode::QUERY => {
+            if !handle_query(request, src, access, context, &mut packet, cache_status) {
+                return None;
+            }
+        }
+        opcode => {
+            info!(client:% = src, opcode:% = opcode; "Unsupported opcode");
+            packet.questions = request.questions.clone();
+            packet.header.rescode = ResultCode::NOTIMP;
+            packet.add_extended_error(
+                ExtendedErrorCode::NotSupported,
+                &format!("opcode {:?} is not supported", opcode),
+            );
+        }
+    }
+
+    // EDNS is negotiated hop by hop, so we only answer with an OPT record, and
+    // therefore extended errors, when the client sent one itself.
+    if request.get_opt().is_some() {
+        if let DnsRecord::OPT {
+            ref mut options, ..
+        } = *packet.get_or_add_opt()
+        {
+            options.extend(context.cookie_secret.respond(request.edns_options(), src.ip()));
+        }
+    } else {
+        packet
+            .resources
+            .retain(|rec| !matches!(*rec, DnsRecord::OPT { .. }));
+    }
+
+    Some(packet)
+}
+
+// Messages with other opcodes don't necessarily follow the QUERY layout, so they
+// may fail to parse. As long as the header is intact we can still tell the sender
+// that we don't implement the opcode, or that its query was malformed.
+fn handle_malformed_request(
+    buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    access: &AccessControl,
+    context: &ServerContext,
+) -> Option<DnsPacket> {
+    let mut header = DnsHeader::new();
+    if buffer.seek(0).is_err() || header.read(buffer).is_err() || header.response {
+        return None;
+    }
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = header.id;
+    packet.header.opcode = header.opcode;
+    packet.header.recursion_desired = header.recursion_desired;
+    packet.header.recursion_available = context.recursion && access.recursion.allows(src.ip());
+    packet.header.response = true;
+    packet.header.rescode = match header.opcode {
+        Opcode::QUERY => ResultCode::FORMERR,
+        _ => ResultCode::NOTIMP,
+    };
+
+    Some(packet)
+}
+
+// Fills in the response to a query, returning whether it should be sent.
+fn handle_query(
+    request: &DnsPacket,
+    src: SocketAddr,
+    access: &AccessControl,
+    context: &ServerContext,
+    packet: &mut DnsPacket,
+    cache_status: &mut CacheStatus,
+) -> bool {
+    // Being mindful of how unreliable input data from arbitrary senders can be, we
+    // need make sure that a question is actually present. If not, we return `FORMERR`
+    // to indicate that the sender made something wrong.
+    let question = match request.questions.first() {
+        Some(x) => x,
+        None => {
+            packet.header.rescode = ResultCode::FORMERR;
+            return true;
+        }
+    };
+
+    // Usually a question will be present, though.
+    debug!(client:% = src, qname = question.name.as_str(), qtype:% = question.qtype;
+           "Received query");
+    packet.questions.push(question.clone());
+
+    // Zone transfers (AXFR and IXFR) need TCP, which we don't serve, but
+    // clients that aren't allowed to transfer are told so all the same.
+    if matches!(question.qtype.to_num(), 251 | 252) {
+        if access.transfer.allows(src.ip()) {
+            packet.header.rescode = ResultCode::NOTIMP;
+            packet.add_extended_error(
+                ExtendedErrorCode::NotSupported,
+                "zone transfers are not supported",
+            );
+        } else {
+            info!(client:% = src, qname = question.name.as_str(); "Refusing zone transfer");
+            packet.header.rescode = ResultCode::REFUSED;
+            packet.add_extended_error(ExtendedErrorCode::Prohibited, "zone transfer refused");
+        }
+        return true;
+    }
+
+    // Our own names and zones are answered straight from memory, with the
+    // hosts file taking precedence.
+    let local = context
+        .hosts
+        .as_ref()
+        .and_then(|hosts| hosts.answer(&question.name, question.qtype))
+        .or_else(|| context.zones.answer(&question.name, question.qtype));
+    if let Some(answer) = local {
+        packet.header.authoritative_answer = answer.header.authoritative_answer;
+        packet.header.rescode = answer.header.rescode;
+        packet.answers = answer.answers;
+        packet.authorities = answer.authorities;
+        packet.resources = answer.resources;
+        return true;
+    }
+    if !context.recursion {
+        packet.header.rescode = ResultCode::REFUSED;
+        return true;
+    }
+    if !access.recursion.allows(src.ip()) {
+        info!(client:% = src, qname = question.name.as_str(); "Refusing recursion");
+        packet.header.rescode = ResultCode::REFUSED;
+        packet.add_extended_error(ExtendedErrorCode::Prohibited, "recursion refused");
+        return true;
+    }
+
+    // Policies only apply to the names we resolve, not to our own zones. A
+    // name that is passed through skips the checks of the answer as well.
+    let qname_match = context
+        .policy
+        .as_ref()
+        .and_then(|policy| policy.check_qname(&question.name));
+    if let Some(ref found) = qname_match {
+        if found.action != PolicyAction::Passthru {
+            return apply_policy(found, src, question, packet);
+        }
+    }
+
+    // With client subnets enabled, the upstream answer may depend on where the
+    // query came from, so the cache has to be consulted for the same subnet
+    // that would be sent upstream.
+    let ecs = context
+        .client_subnet
+        .and_then(|config| config.option_for(src.ip(), request.edns_options()));
+    let cache_client = match ecs {
+        Some(EdnsOption::ECS { addr, .. }) => addr,
+        _ => src.ip(),
+    };
+
+    let mut trace = Trace::new();
+    let result = resolve(question, ecs, cache_client, context, &mut trace);
+    if !trace.events().is_empty() {
+        debug!("Resolution of {:?}:\n{}", question, trace.to_string().trim_end());
+    }
+    *cache_status = match trace.events() {
+        [TraceEvent::CacheHit { .. }] => CacheStatus::Hit,
+        _ => CacheStatus::Miss,
+    };
+
+    let (result, name_servers) = match result {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(qname = question.name.as_str(), qtype:% = question.qtype, error:% = e;
+                  "Lookup failed");
+            let info_code = match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                    ExtendedErrorCode::NoReachableAuthority
                 }
+                ErrorKind::InvalidData | ErrorKind::InvalidInput => ExtendedErrorCode::InvalidData,
+                _ => ExtendedErrorCode::NetworkError,
             };
+            packet.header.rescode = ResultCode::SERVFAIL;
+            packet.add_extended_error(info_code, &e.to_string());
+            return true;
+        }
+    };
+
+    // Clients that sent a subnet of their own get told which part of it the
+    // answer applies to.
+    for option in request.edns_options() {
+        if let EdnsOption::ECS {
+            source_prefix,
+            addr,
+            ..
+        } = *option
+        {
+            let scope_prefix = result
+                .edns_options()
+                .iter()
+                .filter_map(|x| match *x {
+                    EdnsOption::ECS { scope_prefix, .. } => Some(scope_prefix.min(source_prefix)),
+                    _ => None,
+                })
+                .next()
+                .unwrap_or(0);
+
+            if let DnsRecord::OPT {
+                ref mut options, ..
+            } = *packet.get_or_add_opt()
+            {
+                options.push(EdnsOption::ECS {
+                    source_prefix,
+                    scope_prefix,
+                    addr,
+                });
+            }
+        }
+    }
+
+    for (info_code, extra_text) in result.extended_errors() {
+        info!(code:? = info_code, text = extra_text; "Upstream error");
+    }
+
+    if let (Some(policy), None) = (&context.policy, &qname_match) {
+        if let Some(ref found) = policy.check_response(&result, &name_servers) {
+            if found.action != PolicyAction::Passthru {
+                return apply_policy(found, src, question, packet);
+            }
+        }
+    }
+
+    match result.rescode() {
+        // A refusal from an authoritative server means the delegation is lame;
+        // passing it on would make it look like we refused the client.
+        ResultCode::REFUSED => {
+            packet.header.rescode = ResultCode::SERVFAIL;
+            packet.add_extended_error(
+                ExtendedErrorCode::NoReachableAuthority,
+                "authoritative server refused the query",
+            );
+            return true;
+        }
+        // Extended result codes such as BADVERS describe a problem between us
+        // and the upstream server rather than with the client's query.
+        x if x.is_extended() => {
+            packet.header.rescode = ResultCode::SERVFAIL;
+            packet.add_extended_error(
+                ExtendedErrorCode::Other,
+                &format!("upstream server returned {:?}", x),
+            );
+            return true;
+        }
+        x => packet.header.rescode = x,
+    }
+
+    for rec in result.answers {
+        debug!("Answer: {}", rec);
+        packet.answers.push(rec);
+    }
+    for rec in result.authorities {
+        debug!("Authority: {}", rec);
+        packet.authorities.push(rec);
+    }
+    for rec in result.resources {
+        // The OPT record only applies to the upstream exchange.
+        if let DnsRecord::OPT { .. } = rec {
+            continue;
+        }
+        debug!("Resource: {}", rec);
+        packet.resources.push(rec);
+    }
+
+    true
+}
+
+// Rewrites the response as a policy asks, returning whether it should be sent.
+fn apply_policy(
+    found: &PolicyMatch,
+    src: SocketAddr,
+    question: &DnsQuestion,
+    packet: &mut DnsPacket,
+) -> bool {
+    info!(
+        client:% = src,
+        qname = question.name.as_str(),
+        zone = found.zone.as_str(),
+        trigger:% = found.trigger,
+        action:% = found.action;
+        "Applying policy"
+    );
+
+    found.apply(&question.name, question.qtype, packet);
+    found.action != PolicyAction::Drop
+}
+
+// Answers a question from the cache if possible, and otherwise resolves it
+// and caches the outcome. Only successful answers and NXDOMAIN are cached.
+// Clients asking a question that is already being resolved wait for that
+// resolution instead of starting their own, and their trace stays empty.
+// Either way, the name servers that referrals led to come with the answer,
+// so that policies see them no matter who did the resolving.
+fn resolve(
+    question: &DnsQuestion,
+    ecs: Option<EdnsOption>,
+    cache_client: IpAddr,
+    context: &ServerContext,
+    trace: &mut Trace,
+) -> Result<Resolution> {
+    resolve_with(question, ecs, cache_client, context, trace, |options, trace| {
+        match (context.routes.route_for(&question.name), &context.forwarder) {
+            (Some((zone, route)), _) => {
+                debug!(qname = question.name.as_str(), zone; "Routing by zone");
+                route.resolve_traced(&question.name, question.qtype, options, trace)
+            }
+            (None, Some(forwarder)) => {
+                forwarder.forward_traced(&question.name, question.qtype, options, trace)
+            }
+            (None, None) => recursive_lookup_traced(&question.name, question.qtype, options, trace),
+        }
+    })
+}
+
+// Like `resolve`, but with `upstream` doing the actual resolution.
+fn resolve_with<F>(
+    question: &DnsQuestion,
+    ecs: Option<EdnsOption>,
+    cache_client: IpAddr,
+    context: &ServerContext,
+    trace: &mut Trace,
+    upstream: F,
+) -> Result<Resolution>
+where
+    F: FnOnce(&[EdnsOption], &mut Trace) -> Result<DnsPacket>,
+{
+    let cached = context
+        .cache
+        .lookup(&question.name, question.qtype, question.qclass, cache_client);
+    if let Some(resolution) = cached {
+        trace.push(TraceEvent::CacheHit {
+            qname: question.name.clone(),
+            qtype: question.qtype,
+        });
+        return Ok(resolution);
+    }
+
+    let options: Vec<EdnsOption> = ecs.into_iter().collect();
+    context.inflight.resolve(question, &options, || {
+        let packet = upstream(&options, trace)?;
+        let name_servers = trace.name_servers();
+
+        let rescode = packet.rescode();
+        if rescode == ResultCode::NOERROR || rescode == ResultCode::NXDOMAIN {
+            let cache = &context.cache;
+            cache.store(&question.name, question.qtype, question.qclass, &packet, &name_servers);
+        }
+
+        Ok((packet, name_servers))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use dnsafe::{PolicyFormat, PolicyTrigger, PolicyZone, QueryType, RateLimitConfig};
+
+    use super::*;
+
+    const RPZ: &str = "$TTL 60\n\
+                       @ SOA ns.rpz.local. admin.rpz.local. 1 3600 600 86400 60\n\
+                       ns.evil.example.rpz-nsdname CNAME .\n";
+
+    fn context() -> ServerContext {
+        ServerContext {
+            cookie_secret: ServerCookieSecret::random(),
+            cache: Cache::new(16),
+            inflight: InflightQueries::default(),
+            client_subnet: None,
+            forwarder: None,
+            routes: ZoneRoutes::new(),
+            zones: ZoneStore::new(),
+            hosts: None,
+            recursion: true,
+            query_log: false,
+            rate_limiter: None,
+            dnstap: None,
+            policy: None,
+        }
+    }
+
+    fn policy_context() -> ServerContext {
+        let zone = PolicyZone::parse("rpz.local", RPZ, PolicyFormat::Rpz).unwrap();
+        ServerContext {
+            policy: Some(Policy::new(vec![zone])),
+            ..context()
+        }
+    }
+
+    fn query(id: u16) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet
+            .questions
+            .push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buffer
+    }
+
+    #[test]
+    fn it_answers_slipped_queries_over_tcp() {
+        // Every response is limited, and every limited one slips.
+        let config = RateLimitConfig {
+            responses_per_second: 0,
+            slip: 1,
+            ..RateLimitConfig::default()
+        };
+        let context = ServerContext {
+            hosts: Some(HostsFile::parse("192.0.2.1 www.example.com\n")),
+            rate_limiter: Some(RateLimiter::new(config)),
+            ..context()
+        };
+
+        let (socket, tcp) = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = Arc::new(Listener {
+            socket,
+            tcp,
+            access: AccessControl::default(),
+        });
+        serve(vec![listener], Arc::new(context), 2, 16).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(&query(1), addr).unwrap();
+        let mut res_buffer = BytePacketBuffer::new();
+        client.recv_from(&mut res_buffer.buf).unwrap();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert!(response.header.truncated_message);
+        assert!(response.answers.is_empty());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for id in 2..4 {
+            let message = query(id);
+            let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&message);
+            stream.write_all(&framed).unwrap();
+
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut res_buffer = VectorPacketBuffer::new();
+            res_buffer.buffer = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut res_buffer.buffer).unwrap();
+
+            let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+            assert_eq!(id, response.header.id);
+            assert!(!response.header.truncated_message);
+            assert_eq!(1, response.answers.len());
+        }
+    }
+
+    // Resolves www.example.com through a referral to ns.evil.example, and
+    // returns the trigger of the policy that matched the answer, if any.
+    fn resolve_and_check(context: &ServerContext) -> Option<PolicyTrigger> {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let client = "192.0.2.100".parse().unwrap();
+
+        let mut trace = Trace::new();
+        let resolution = resolve_with(&question, None, client, context, &mut trace, |_, trace| {
+            trace.push(TraceEvent::Referral {
+                zone: "example.com".to_string(),
+                name_server: "ns.evil.example".to_string(),
+                glue: None,
+            });
+            thread::sleep(Duration::from_millis(100));
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 300,
+            });
+            Ok(packet)
+        });
+
+        let (packet, name_servers) = resolution.unwrap();
+        let policy = context.policy.as_ref().unwrap();
+        policy.check_response(&packet, &name_servers).map(|x| x.trigger)
+    }
+
+    #[test]
+    fn it_applies_name_server_policies_to_cached_answers() {
+        let context = policy_context();
+        assert_eq!(Some(PolicyTrigger::NsName), resolve_and_check(&context));
+        assert_eq!(Some(PolicyTrigger::NsName), resolve_and_check(&context));
+        assert_eq!((1, 1), (context.cache.hits(), context.cache.misses()));
+    }
+
+    #[test]
+    fn it_applies_name_server_policies_to_shared_answers() {
+        let context = Arc::new(policy_context());
+        let barrier = Arc::new(Barrier::new(2));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let context = context.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    resolve_and_check(&context)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(Some(PolicyTrigger::NsName), handle.join().unwrap());
         }
-    } // End of request loop
-} // End of main
+    }
+}
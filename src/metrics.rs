@@ -0,0 +1,107 @@
+//! The HTTP endpoint Prometheus scrapes metrics from
+//!
+//! Only `GET /metrics` is answered, one request per connection, which is all a
+//! scraper needs. Connections are handled one at a time on a thread of their
+//! own, so a slow scraper can't hold up any queries.
+
+use std::fmt::Write as _;
+use std::io::{Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dnsafe::metrics;
+
+use crate::ServerContext;
+
+// Requests are tiny, so anything larger isn't a scraper.
+const MAX_REQUEST_LEN: usize = 8192;
+
+// How long a client gets to send its request and read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Answers metrics requests on `listener` until it fails.
+pub fn serve(listener: TcpListener, context: Arc<ServerContext>) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle_connection(stream, &context));
+        if let Err(e) = result {
+            debug!(error:% = e; "Failed to answer metrics request");
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, context: &ServerContext) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        let len = stream.read(&mut buf)?;
+        if len == 0 || request.len() + len > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(context)),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// The metrics recorded by the library, followed by those of the server.
+fn render(context: &ServerContext) -> String {
+    let mut out = String::new();
+    metrics().render(&mut out);
+
+    let cache = &context.cache;
+    let counters = [
+        ("dnsafe_cache_hits_total", "Queries answered from the cache.", cache.hits()),
+        ("dnsafe_cache_misses_total", "Queries not found in the cache.", cache.misses()),
+        (
+            "dnsafe_cache_evictions_total",
+            "Packets removed from the cache to make room.",
+            cache.evictions(),
+        ),
+    ];
+    for &(name, help, value) in counters.iter() {
+        metric(&mut out, name, "counter", help, value);
+    }
+    let len = cache.len() as u64;
+    metric(&mut out, "dnsafe_cache_entries", "gauge", "Packets in the cache.", len);
+
+    if let Some(ref rate_limiter) = context.rate_limiter {
+        let help = "Responses truncated by the rate limit.";
+        let slipped = rate_limiter.slipped();
+        metric(&mut out, "dnsafe_rate_limit_slipped_total", "counter", help, slipped);
+        let help = "Responses dropped by the rate limit.";
+        let dropped = rate_limiter.dropped();
+        metric(&mut out, "dnsafe_rate_limit_dropped_total", "counter", help, dropped);
+    }
+    if let Some(ref dnstap) = context.dnstap {
+        let help = "Dnstap messages dropped.";
+        metric(&mut out, "dnsafe_dnstap_dropped_total", "counter", help, dnstap.dropped());
+    }
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, metric_type: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "{} {}", name, value);
+}